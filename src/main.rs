@@ -1,12 +1,21 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{self, BufReader, BufWriter};
+use std::io::{self, BufReader, BufWriter, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 enum NodeType {
-    FILE,
+    FILE { content: String },
     DIR { children: HashMap<String, usize> },
+    // target is stored as given and resolved on use, relative to the link's
+    // parent directory unless absolute
+    SYMLINK { target: String },
 }
 
 /*
@@ -23,99 +32,566 @@ struct FsNode {
     name: String,
     parent: usize,
     node_type: NodeType,
+    // permission bits, e.g. 0o755
+    mode: u32,
+    // owner; everything belongs to root (0) for now
+    uid: u32,
+    // reads don't update atime, as on a noatime mount
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    // birth time: set when the node is made and never changed after, while
+    // ctime follows every chmod, rename or content change
+    created: SystemTime,
 }
 
 impl FsNode {
-    fn new_file_node(name: &str, parent: usize) -> FsNode {
+    fn new_file_node(name: &str, parent: usize, now: SystemTime) -> FsNode {
         FsNode {
             name: name.to_string(),
             parent,
-            node_type: NodeType::FILE,
+            node_type: NodeType::FILE {
+                content: String::new(),
+            },
+            mode: 0o644,
+            uid: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            created: now,
         }
     }
-    fn new_dir_node(name: &str, parent: usize) -> FsNode {
+    fn new_dir_node(name: &str, parent: usize, now: SystemTime) -> FsNode {
         FsNode {
             name: name.to_string(),
             parent,
             node_type: NodeType::DIR {
                 children: HashMap::new(),
             },
+            mode: 0o755,
+            uid: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            created: now,
         }
     }
 
     fn is_file_node(&self) -> bool {
-        if let NodeType::FILE = self.node_type {
-            true
-        } else {
-            false
-        }
+        matches!(self.node_type, NodeType::FILE { .. })
     }
 
     fn is_dir_node(&self) -> bool {
-        !self.is_file_node()
+        matches!(self.node_type, NodeType::DIR { .. })
+    }
+
+    // the file type as stat names it
+    fn type_name(&self) -> &'static str {
+        match self.node_type {
+            NodeType::DIR { .. } => "directory",
+            NodeType::FILE { .. } => "regular file",
+            NodeType::SYMLINK { .. } => "symbolic link",
+        }
+    }
+
+    // content length in bytes, or the target length for symlinks as on Unix;
+    // directories have no size of their own
+    fn size(&self) -> usize {
+        match &self.node_type {
+            NodeType::FILE { content } => content.len(),
+            NodeType::DIR { .. } => 0,
+            NodeType::SYMLINK { target } => target.len(),
+        }
+    }
+}
+
+// number of previously visited directories remembered for `cd -N`
+const CD_HISTORY_SIZE: usize = 10;
+
+// number of changes `undo` can take back
+const UNDO_HISTORY_SIZE: usize = 100;
+
+// how to take back one change. Only plain moves are recorded: a move is
+// undone by linking the node back where it was, with no copy of anything.
+#[derive(Debug)]
+enum UndoRecord {
+    // the node, then the parent and name it had before the mv
+    Moved {
+        id: usize,
+        parent: usize,
+        name: String,
+    },
+}
+
+// symlinks followed while resolving one path before giving up on a loop
+const MAX_SYMLINK_HOPS: usize = 40;
+
+// default for `set max-depth`
+const DEFAULT_MAX_DEPTH: usize = 4096;
+
+// the first line of a backup, before the counter line; v1 backups have none.
+// Older versions are read but never written: save always writes this one,
+// so reloading a v1 backup and saving it upgrades it.
+const BACKUP_MAGIC: &str = "FSBACKUP ";
+const BACKUP_VERSION: &str = "v2";
+
+// default for `set block-size`
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+// modes mkdir and creat start from before the umask is taken off, and the
+// umask a new filesystem starts with, giving 755 and 644
+const DIR_BASE_MODE: u32 = 0o777;
+const FILE_BASE_MODE: u32 = 0o666;
+const DEFAULT_UMASK: u32 = 0o022;
+
+// default for `set path-cache`
+const PATH_CACHE_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FindType {
+    File,
+    Dir,
+    Symlink,
+}
+
+// a -size bound on a node's size in bytes; + and - are strict, so
+// -size +1K -size -2K leaves out both 1024 and 2048
+#[derive(Debug, Clone, Copy)]
+enum SizeTest {
+    Greater(usize),
+    Less(usize),
+    Exact(usize),
+}
+
+impl SizeTest {
+    fn matches(self, size: usize) -> bool {
+        match self {
+            SizeTest::Greater(bound) => size > bound,
+            SizeTest::Less(bound) => size < bound,
+            SizeTest::Exact(bound) => size == bound,
+        }
+    }
+}
+
+// the predicates of a `find` command, all of which must hold for a match
+#[derive(Debug, Default)]
+struct FindQuery {
+    name: Option<String>,
+    // -path: a pattern for the start as given followed by the path below it
+    path: Option<String>,
+    file_type: Option<FindType>,
+    empty: bool,
+    // -size, any number of times
+    sizes: Vec<SizeTest>,
+    // depth bounds relative to the start, which is at depth 0
+    min_depth: Option<usize>,
+    max_depth: Option<usize>,
+    // -delete: remove the matches instead of printing them
+    delete: bool,
+    // -chmod and -chown: change the matches themselves, symlinks included,
+    // instead of printing them
+    chmod: Option<ModeSpec>,
+    chown: Option<u32>,
+    // --limit: stop walking once this many matches are found
+    limit: Option<usize>,
+    // --relative: print the matches relative to cwd
+    relative: bool,
+}
+
+// screen height assumed by `ls --paginate` when LINES is not exported
+const DEFAULT_LINES: usize = 24;
+
+// cp's error when source and destination are one node; reported with both names
+const SAME_FILE: &str = "are the same file";
+
+// cp's error after a merge that reported failing entries as it went
+const SOME_NOT_COPIED: &str = "some entries were not copied";
+
+// nodes copied between two `cp --progress` reports
+const CP_PROGRESS_INTERVAL: usize = 1000;
+
+// what cp and mv do when the destination entry already exists
+#[derive(Debug, Default, Clone, Copy)]
+enum Overwrite {
+    #[default]
+    Refuse,
+    Skip,
+    Force,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CopyOptions {
+    overwrite: Overwrite,
+    // -r: copy directories along with everything below them (cp only)
+    recursive: bool,
+    // --progress: report the running count of copied nodes (cp only)
+    progress: bool,
+    // --parents: recreate the source's directories under the destination (cp only)
+    parents: bool,
+    // -b: rename an overwritten destination to name~ instead of dropping it
+    backup: bool,
+    // -u/--update: replace a destination only with a source modified later
+    // (cp only)
+    update: bool,
+}
+
+impl CopyOptions {
+    // -b overwrites even without -f, though -n still wins
+    fn policy(&self) -> Overwrite {
+        match self.overwrite {
+            Overwrite::Refuse if self.backup || self.update => Overwrite::Force,
+            policy => policy,
+        }
+    }
+}
+
+// the time stamped on nodes as they are created or changed. A fixed clock
+// (`set clock SECONDS`) makes stat output reproducible in scripts.
+#[derive(Debug, Clone, Copy)]
+enum Clock {
+    System,
+    Fixed(SystemTime),
+}
+
+impl Clock {
+    fn now(&self) -> SystemTime {
+        match self {
+            Clock::System => SystemTime::now(),
+            Clock::Fixed(time) => *time,
+        }
     }
 }
 
+// what reload loaded, the root included among the directories
+#[derive(Debug, Default)]
+struct NodeCounts {
+    // the format the backup was written in
+    version: &'static str,
+    directories: usize,
+    files: usize,
+    symlinks: usize,
+}
+
+// where cp/mv place the source node, and the existing entry it replaces
+struct Destination {
+    parent_id: usize,
+    name: String,
+    replaced: Option<usize>,
+}
+
+// not thread safe, and needs no lock: the shell drives a single instance from
+// one thread. The RefCell behind path_cache keeps it from being Sync, so the
+// compiler rejects sharing one between threads; a caller that wants to should
+// put it in a Mutex, which also keeps a save from seeing a half-done change.
 struct FileSystem {
     counter: usize,
     cwd: usize,
+    // node ids are handed out once from counter and never reassigned, so they
+    // serve as inode numbers: mv only rewrites the parents' children entries
     nodes: HashMap<usize, FsNode>,
+    cd_history: VecDeque<usize>,
+    // most recent last; see UndoRecord
+    undo_history: VecDeque<UndoRecord>,
+    // the path cwd was reached through by `cd -L` when it went via a symlink.
+    // It is display state only: lookups always start from the cwd id, and the
+    // path is dropped once it no longer leads to cwd (e.g. after a mv).
+    logical_cwd: Option<String>,
+    // `set verbose on`: report every node mkdir, rmdir, rm, cp and mv touch
+    verbose: bool,
+    // `set max-nodes N`: cap on the node count, root included
+    max_nodes: Option<usize>,
+    // `set max-depth N`: how deep a node may sit, the root being at depth 0;
+    // nothing deeper is created or walked
+    max_depth: usize,
+    // `set clock`: where node timestamps come from
+    clock: Clock,
+    // `set block-size N`: the allocation unit content sizes round up to
+    block_size: usize,
+    // `umask MODE`: permission bits cleared from new directories and files
+    umask: u32,
+    // mount points of `overlay`; nothing at or below them may change. The
+    // flag is not saved, so a reloaded backup has the overlays writable.
+    read_only: Vec<usize>,
+    // `set prompt TEMPLATE`: the REPL prompt, see render_prompt
+    prompt: String,
+    // lookups from the root, behind a RefCell since find takes &self
+    path_cache: RefCell<PathCache>,
+    // basename -> ids of every node but the root, for `locate`. It holds a
+    // second copy of each distinct name plus a usize per node, so it costs
+    // roughly as much again as the names themselves. Kept up to date by
+    // insert_node, remove_node and attach, and rebuilt by reload.
+    names: HashMap<String, Vec<usize>>,
+}
+
+// least recently used cache of the (id, symlink hops) that paths from the
+// root resolve to. Only successful lookups are kept, so creating nodes
+// cannot make an entry stale; anything that unlinks or moves a node clears
+// the whole cache instead of working out which entries went through it.
+struct PathCache {
+    // `set path-cache N`: entries kept, 0 disabling the cache
+    capacity: usize,
+    // path -> (id, hops, tick of the last use)
+    entries: HashMap<String, (usize, usize, u64)>,
+    tick: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl PathCache {
+    fn new(capacity: usize) -> PathCache {
+        PathCache {
+            capacity,
+            entries: HashMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, path: &str) -> Option<(usize, usize)> {
+        self.tick += 1;
+        match self.entries.get_mut(path) {
+            Some((id, hops, used)) => {
+                *used = self.tick;
+                self.hits += 1;
+                Some((*id, *hops))
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, path: String, id: usize, hops: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            // a linear scan for the oldest entry keeps the map the only index
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(path, (id, hops, self.tick));
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.clear();
+    }
 }
 
 type FsResult = Result<(), &'static str>;
 
+// depth-first iterator over the (id, depth) pairs of a subtree, visiting
+// children in name order and not descending below max_depth
+struct Walk<'a> {
+    fs: &'a FileSystem,
+    stack: Vec<(usize, usize)>,
+    max_depth: Option<usize>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let (id, depth) = self.stack.pop()?;
+        if self.max_depth.is_none_or(|max_depth| depth < max_depth) {
+            if let NodeType::DIR { children } = &self.fs.nodes.get(&id).unwrap().node_type {
+                let mut names = children.keys().collect::<Vec<&String>>();
+                names.sort_unstable_by(|a, b| b.cmp(a));
+                self.stack
+                    .extend(names.into_iter().map(|name| (children[name], depth + 1)));
+            }
+        }
+        Some((id, depth))
+    }
+}
+
 impl FileSystem {
     fn new() -> FileSystem {
         let counter = 0;
-        let root = FsNode::new_dir_node("/", counter);
+        let clock = Clock::System;
+        let root = FsNode::new_dir_node("/", counter, clock.now());
         let mut nodes = HashMap::new();
         nodes.insert(counter, root);
         FileSystem {
             counter,
             cwd: counter,
             nodes,
+            cd_history: VecDeque::new(),
+            undo_history: VecDeque::new(),
+            logical_cwd: None,
+            verbose: false,
+            max_nodes: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            clock,
+            block_size: DEFAULT_BLOCK_SIZE,
+            umask: DEFAULT_UMASK,
+            read_only: Vec::new(),
+            prompt: "$ ".to_string(),
+            path_cache: RefCell::new(PathCache::new(PATH_CACHE_SIZE)),
+            names: HashMap::new(),
+        }
+    }
+
+    // adds the node to the node map and the name index
+    fn insert_node(&mut self, id: usize, node: FsNode) {
+        self.names.entry(node.name.clone()).or_default().push(id);
+        self.nodes.insert(id, node);
+    }
+
+    // drops the node from the node map and the name index
+    fn remove_node(&mut self, id: usize) {
+        if let Some(node) = self.nodes.remove(&id) {
+            self.unindex_name(&node.name, id);
+        }
+    }
+
+    fn unindex_name(&mut self, name: &str, id: usize) {
+        if let Some(ids) = self.names.get_mut(name) {
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                self.names.remove(name);
+            }
+        }
+    }
+
+    fn reindex_names(&mut self) {
+        self.names.clear();
+        for (&id, node) in &self.nodes {
+            if id != 0 {
+                self.names.entry(node.name.clone()).or_default().push(id);
+            }
         }
     }
 
-    // finds the node represented by the path
+    // absolute paths of every node named name, sorted
+    fn locate(&self, name: &str) -> Vec<String> {
+        let mut paths = self.names.get(name).map_or_else(Vec::new, |ids| {
+            ids.iter().map(|&id| self.path_of(id)).collect()
+        });
+        paths.sort();
+        paths
+    }
+
+    // finds the node represented by the path. Symlinks along the way are
+    // followed, but a symlink in the last component is returned as is.
     fn find(&self, start_id: usize, path: &[&str]) -> Result<usize, &'static str> {
-        let mut iter = path.iter().peekable();
+        let mut hops = 0;
+        self.find_cached(start_id, path, &mut hops)
+    }
+
+    // like find, but also follows a symlink in the last component
+    fn find_follow(&self, start_id: usize, path: &[&str]) -> Result<usize, &'static str> {
+        let mut hops = 0;
+        let id = self.find_cached(start_id, path, &mut hops)?;
+        self.follow_with_hops(id, &mut hops)
+    }
+
+    // find_with_hops going through the path cache for lookups from the root
+    fn find_cached(
+        &self,
+        start_id: usize,
+        path: &[&str],
+        hops: &mut usize,
+    ) -> Result<usize, &'static str> {
+        if start_id != 0 || path.is_empty() || self.path_cache.borrow().capacity == 0 {
+            return self.find_with_hops(start_id, path, hops);
+        }
+        let key = path.join("/");
+        if let Some((id, cached_hops)) = self.path_cache.borrow_mut().get(&key) {
+            *hops += cached_hops;
+            return Ok(id);
+        }
+        let id = self.find_with_hops(start_id, path, hops)?;
+        self.path_cache.borrow_mut().insert(key, id, *hops);
+        Ok(id)
+    }
+
+    // drops every cached lookup; called whenever a node is unlinked
+    fn invalidate_paths(&self) {
+        self.path_cache.borrow_mut().entries.clear();
+    }
+
+    fn find_with_hops(
+        &self,
+        start_id: usize,
+        path: &[&str],
+        hops: &mut usize,
+    ) -> Result<usize, &'static str> {
         let mut current_id = start_id;
-        while let Some(name) = iter.next() {
+        for name in path {
+            current_id = self.follow_with_hops(current_id, hops)?;
             // find the current name among the current node siblings
             let current_node = self.nodes.get(&current_id).unwrap();
             if let NodeType::DIR { children } = &current_node.node_type {
-                current_id = *children
-                    .get(*name)
-                    .ok_or_else(|| "No such file or directory")?;
-            } else if iter.peek().is_some() {
+                current_id = match *name {
+                    "." => current_id,
+                    ".." => current_node.parent,
+                    _ => *children.get(*name).ok_or("No such file or directory")?,
+                };
+            } else {
                 return Err("Not a directory");
             }
         }
         Ok(current_id)
     }
 
-    fn mkdir(&mut self, path_name: &str) -> FsResult {
-        let path = split_path(path_name);
+    // resolves a chain of symlinks to the node at its end
+    fn follow_with_hops(&self, id: usize, hops: &mut usize) -> Result<usize, &'static str> {
+        let node = self.nodes.get(&id).unwrap();
+        if let NodeType::SYMLINK { target } = &node.node_type {
+            *hops += 1;
+            if *hops > MAX_SYMLINK_HOPS {
+                return Err("Too many levels of symbolic links");
+            }
+            let (absolute, path) = split_path(target);
+            let start_id = if absolute { 0 } else { node.parent };
+            let target_id = self.find_with_hops(start_id, &path, hops)?;
+            self.follow_with_hops(target_id, hops)
+        } else {
+            Ok(id)
+        }
+    }
+
+    // creates a directory with the given permission bits, or 0755 without
+    fn mkdir(&mut self, path_name: &str, mode: Option<u32>) -> FsResult {
+        let (absolute, path) = split_path(path_name);
         if let Some((dir_name, base_path)) = path.split_last() {
-            let start_id = if path_name.starts_with('/') {
-                0
-            } else {
-                self.cwd
-            };
-            let target_id = self.find(start_id, base_path)?;
+            let start_id = if absolute { 0 } else { self.cwd };
+            let target_id = self.find_follow(start_id, base_path)?;
+            self.check_writable(target_id)?;
+            let has_room = self
+                .check_free_nodes(1)
+                .and_then(|_| self.check_depth(target_id, self.depth_of(target_id) + 1, false));
             let target_node = self.nodes.get_mut(&target_id).unwrap();
             match &mut target_node.node_type {
-                NodeType::FILE => Err("Not a directory"),
+                NodeType::FILE { .. } | NodeType::SYMLINK { .. } => Err("Not a directory"),
                 NodeType::DIR { children } => {
+                    if !is_valid_name(dir_name) {
+                        return Err("invalid name");
+                    }
                     if children.contains_key(*dir_name) {
                         return Err("Directory already exists");
                     };
-                    let new_node = FsNode::new_dir_node(*dir_name, target_id);
+                    has_room?;
+                    let mut new_node = FsNode::new_dir_node(dir_name, target_id, self.clock.now());
+                    // an explicit -m mode is used as given
+                    new_node.mode = mode.unwrap_or(DIR_BASE_MODE & !self.umask);
                     let new_counter = self.counter + 1;
                     children.insert((*dir_name).to_string(), new_counter);
-                    self.nodes.insert(new_counter, new_node);
+                    self.insert_node(new_counter, new_node);
                     self.counter = new_counter;
+                    if self.verbose {
+                        println!("mkdir: created directory '{}'", self.path_of(new_counter));
+                    }
                     Ok(())
                 }
             }
@@ -124,55 +600,165 @@ impl FileSystem {
         }
     }
 
-    fn pwd(&self) {
-        let mut node = self.nodes.get(&self.cwd).unwrap();
-        let mut cwd_vec: Vec<&str> = Vec::new();
-        if &node.name != "/" {
-            cwd_vec.push(&node.name);
+    // creates the directory and any missing parents; existing directories along
+    // the way are fine. Only the last directory gets the mode.
+    fn mkdir_p(&mut self, path_name: &str, mode: Option<u32>) -> FsResult {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        for end in 1..=path.len() {
+            match self.find_follow(start_id, &path[..end]) {
+                Ok(id) if self.nodes.get(&id).unwrap().is_dir_node() => {}
+                Ok(_) => return Err("Not a directory"),
+                // only a missing component is created; a symlink loop fails
+                // here rather than as an existing entry in mkdir
+                Err("No such file or directory") => {
+                    let prefix = path[..end].join("/");
+                    let prefix = if start_id == 0 {
+                        format!("/{}", prefix)
+                    } else {
+                        prefix
+                    };
+                    self.mkdir(&prefix, mode.filter(|_| end == path.len()))?
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // builds the absolute path of the node by walking up to the root; the
+    // root itself is "/"
+    fn path_of(&self, id: usize) -> String {
+        let mut node = self.nodes.get(&id).unwrap();
+        let mut path_vec: Vec<&str> = Vec::new();
+        if id != 0 {
+            path_vec.push(&node.name);
         }
         while node.parent != 0 {
             node = self.nodes.get(&node.parent).unwrap();
-            cwd_vec.push(&node.name);
+            path_vec.push(&node.name);
+        }
+        path_vec.reverse();
+
+        format!("/{}", path_vec.join("/"))
+    }
+
+    // the logical path of cwd when it is still valid, else its real path
+    fn cwd_path(&self) -> String {
+        if let Some(logical) = &self.logical_cwd {
+            if self.find_follow(0, &split_path(logical).1) == Ok(self.cwd) {
+                return logical.clone();
+            }
         }
-        cwd_vec.reverse();
+        self.path_of(self.cwd)
+    }
 
-        println!("/{}", cwd_vec.join("/"));
+    fn pwd(&self) {
+        println!("{}", self.cwd_path());
     }
 
-    fn ls(&self, path: Option<String>) -> FsResult {
+    // lists the directory's entries as (name, id) pairs sorted by name
+    fn ls(&self, path: Option<String>) -> Result<Vec<(String, usize)>, &'static str> {
         let fsnode = if let Some(path) = path {
-            let start_id = if path.starts_with('/') { 0 } else { self.cwd };
-            let path = split_path(&path);
-            let target_id = self.find(start_id, &path)?;
+            let (absolute, path) = split_path(&path);
+            let start_id = if absolute { 0 } else { self.cwd };
+            let target_id = self.find_follow(start_id, &path)?;
             self.nodes.get(&target_id).unwrap()
         } else {
             self.nodes.get(&self.cwd).unwrap()
         };
         match &fsnode.node_type {
-            NodeType::DIR { children } => children
-                .keys()
-                .for_each(|child_name| println!("{}", child_name)),
-            NodeType::FILE => return Err("not a directory"),
+            NodeType::DIR { children } => {
+                let mut entries = children
+                    .iter()
+                    .map(|(name, id)| (name.clone(), *id))
+                    .collect::<Vec<(String, usize)>>();
+                entries.sort();
+                Ok(entries)
+            }
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => Err("not a directory"),
         }
-        Ok(())
     }
 
-    fn cd(&mut self, path: Option<String>) -> FsResult {
+    // fills dir (created if missing) with a balanced tree: every directory
+    // gets breadth entries, directories d0, d1, ... down to the last of the
+    // depth levels, which holds files f0, f1, ... Returns the number of nodes
+    // created, breadth + breadth^2 + ... + breadth^depth.
+    fn gentree(&mut self, dir: &str, breadth: usize, depth: usize) -> Result<usize, &'static str> {
+        self.mkdir_p(dir, None)?;
+        if depth == 0 {
+            return Ok(0);
+        }
+        let mut created = 0;
+        let mut pending = vec![(dir.trim_end_matches('/').to_string(), 1)];
+        while let Some((parent, level)) = pending.pop() {
+            for i in 0..breadth {
+                if level == depth {
+                    self.creat(&format!("{}/f{}", parent, i))?;
+                } else {
+                    let path = format!("{}/d{}", parent, i);
+                    self.mkdir(&path, None)?;
+                    pending.push((path, level + 1));
+                }
+                created += 1;
+            }
+        }
+        Ok(created)
+    }
+
+    // `mkdir -p` the path, then cd into it
+    fn cd_create(&mut self, path: &str) -> FsResult {
+        self.mkdir_p(path, None)?;
+        self.cd(Some(path.to_string()), false)
+    }
+
+    // changes directory. Logically (-L, the default) `..` is resolved against
+    // the path typed so far, so cd'ing through a symlink and back out returns
+    // to where it started. Physically (-P) symlinks are resolved first and
+    // `..` climbs the real tree.
+    fn cd(&mut self, path: Option<String>, physical: bool) -> FsResult {
         if let Some(path) = path {
-            let start_id = if path.starts_with('/') { 0 } else { self.cwd };
-            let path = split_path(&path);
-            let target_id = self.find(start_id, &path)?;
-            let node = self.nodes.get(&target_id).unwrap();
-            match &node.node_type {
-                NodeType::DIR { children: _ } => self.cwd = target_id,
-                NodeType::FILE => return Err("not a directory"),
+            let (target_id, logical) = if physical {
+                let (absolute, components) = split_path(&path);
+                let start_id = if absolute { 0 } else { self.cwd };
+                (self.find_follow(start_id, &components)?, None)
+            } else {
+                let path = normalize_path(&path, &self.cwd_path());
+                (self.find_follow(0, &split_path(&path).1)?, Some(path))
+            };
+            if !self.nodes.get(&target_id).unwrap().is_dir_node() {
+                return Err("not a directory");
             }
+            self.set_cwd(target_id);
+            self.logical_cwd = logical.filter(|path| *path != self.path_of(target_id));
         } else {
-            self.cwd = 0;
+            self.set_cwd(0);
         };
         Ok(())
     }
 
+    // jumps back to the nth most recently visited directory that still exists
+    fn cd_back(&mut self, n: usize) -> FsResult {
+        let target_id = self
+            .cd_history
+            .iter()
+            .filter(|id| self.nodes.contains_key(id))
+            .nth(n - 1)
+            .copied()
+            .ok_or("no such entry in directory history")?;
+        self.set_cwd(target_id);
+        Ok(())
+    }
+
+    fn set_cwd(&mut self, target_id: usize) {
+        if target_id != self.cwd {
+            self.cd_history.push_front(self.cwd);
+            self.cd_history.truncate(CD_HISTORY_SIZE);
+            self.cwd = target_id;
+        }
+        self.logical_cwd = None;
+    }
+
     /*
     fn get_children(&self, parent_id: usize) -> Result<&HashMap<String, usize>, &'static str> {
         let parent_node = self.nodes.get(&parent_id).unwrap();
@@ -183,52 +769,65 @@ impl FileSystem {
     }
     */
 
-    fn rmdir(&mut self, path_name: &str) -> FsResult {
-        let path = split_path(path_name);
-        let start_id = if path_name.starts_with('/') {
-            0
-        } else {
-            self.cwd
-        };
+    // removes an empty directory and returns its path
+    fn rmdir(&mut self, path_name: &str) -> Result<Vec<String>, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
         let target_id = self.find(start_id, &path)?;
+        self.check_writable(target_id)?;
         let target_node = self.nodes.get(&target_id).unwrap();
         if let NodeType::DIR { children } = &target_node.node_type {
             if !children.is_empty() {
                 return Err("Directory not empty");
             }
+            if self.is_ancestor(target_id, self.cwd) {
+                return Err("directory in use");
+            }
         } else {
             return Err("not a directory");
         }
+        let removed = self.path_of(target_id);
+        if self.verbose {
+            println!("rmdir: removing directory, '{}'", removed);
+        }
 
-        let parent_id: usize = (&target_node.parent).to_owned();
-        let target_name = (&target_node.name).clone();
+        let parent_id: usize = target_node.parent.to_owned();
+        let target_name = target_node.name.clone();
         if let NodeType::DIR { children } = &mut self.nodes.get_mut(&parent_id).unwrap().node_type {
             children.remove(&target_name);
         };
+        self.remove_node(target_id);
+        self.invalidate_paths();
 
-        Ok(())
+        Ok(vec![removed])
     }
 
     fn creat(&mut self, path_name: &str) -> FsResult {
-        let path = split_path(path_name);
+        let (absolute, path) = split_path(path_name);
         if let Some((file_name, base_path)) = path.split_last() {
-            let start_id = if path_name.starts_with('/') {
-                0
-            } else {
-                self.cwd
-            };
-            let target_id = self.find(start_id, base_path)?;
+            let start_id = if absolute { 0 } else { self.cwd };
+            let target_id = self.find_follow(start_id, base_path)?;
+            self.check_writable(target_id)?;
+            let has_room = self
+                .check_free_nodes(1)
+                .and_then(|_| self.check_depth(target_id, self.depth_of(target_id) + 1, false));
             let target_node = self.nodes.get_mut(&target_id).unwrap();
             match &mut target_node.node_type {
-                NodeType::FILE => Err("Not a directory"),
+                NodeType::FILE { .. } | NodeType::SYMLINK { .. } => Err("Not a directory"),
                 NodeType::DIR { children } => {
+                    if !is_valid_name(file_name) {
+                        return Err("invalid name");
+                    }
                     if children.contains_key(*file_name) {
                         return Err("File already exists");
                     };
-                    let new_node = FsNode::new_file_node(*file_name, target_id);
+                    has_room?;
+                    let mut new_node =
+                        FsNode::new_file_node(file_name, target_id, self.clock.now());
+                    new_node.mode = FILE_BASE_MODE & !self.umask;
                     let new_counter = self.counter + 1;
                     children.insert((*file_name).to_string(), new_counter);
-                    self.nodes.insert(new_counter, new_node);
+                    self.insert_node(new_counter, new_node);
                     self.counter = new_counter;
                     Ok(())
                 }
@@ -238,287 +837,7038 @@ impl FileSystem {
         }
     }
 
-    fn rm(&mut self, path_name: &str) -> FsResult {
-        let path = split_path(path_name);
-        let start_id = if path_name.starts_with('/') {
-            0
-        } else {
-            self.cwd
+    // creates a symlink at link_name pointing at target, which need not exist
+    fn symlink(&mut self, target: &str, link_name: &str) -> FsResult {
+        check_target(target)?;
+        self.creat(link_name)?;
+        // creat hands the new file the latest counter value
+        let node = self.nodes.get_mut(&self.counter).unwrap();
+        node.node_type = NodeType::SYMLINK {
+            target: target.to_string(),
         };
+        node.mode = 0o777;
+        Ok(())
+    }
+
+    // symlink for `ln -sf`: an existing entry at link_name is removed
+    // first; rmdir refuses a non-empty directory
+    fn symlink_force(&mut self, target: &str, link_name: &str) -> FsResult {
+        check_target(target)?;
+        let (absolute, path) = split_path(link_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        if let Ok(existing_id) = self.find(start_id, &path) {
+            if self.nodes.get(&existing_id).unwrap().is_dir_node() {
+                self.rmdir(link_name)?;
+            } else {
+                self.rm(link_name)?;
+            }
+        }
+        self.symlink(target, link_name)
+    }
+
+    fn rm(&mut self, path_name: &str) -> FsResult {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
         let target_id = self.find(start_id, &path)?;
+        self.check_writable(target_id)?;
         let target_node = self.nodes.get(&target_id).unwrap();
 
         if target_node.is_dir_node() {
             return Err("not a file");
         }
+        if self.verbose {
+            println!("removed '{}'", self.path_of(target_id));
+        }
 
-        let parent_id: usize = (&target_node.parent).to_owned();
-        let target_name = (&target_node.name).clone();
+        let parent_id: usize = target_node.parent.to_owned();
+        let target_name = target_node.name.clone();
         if let NodeType::DIR { children } = &mut self.nodes.get_mut(&parent_id).unwrap().node_type {
             children.remove(&target_name);
         };
+        self.remove_node(target_id);
+        self.invalidate_paths();
 
         Ok(())
     }
 
-    fn save(&self, maybe_filepath: Option<String>) -> FsResult {
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(maybe_filepath.unwrap_or_else(|| "backup.fs".to_string()))
-            .map_err(|_| "Error opening the backup file")?;
-        let mut writer = BufWriter::new(file);
-        writeln!(writer, "{} {}", self.counter, self.nodes.len())
-            .map_err(|_| "error writing to backup file")?;
-        // writeln!(writer, "{}", self.nodes.len()).map_err(|_| "error writing to backup file")?;
-        for (id, node) in self.nodes.iter() {
-            writeln!(writer, "{} {}", id, node.name).map_err(|_| "error writing to backup file")?;
+    // removes the node and, for a directory, everything below it. Returns
+    // the removed paths, children before the directories holding them.
+    fn rm_recursive(&mut self, path_name: &str) -> Result<Vec<String>, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find(start_id, &path)?;
+        self.check_writable(target_id)?;
+        // removing cwd or one of its ancestors would leave cwd unreachable
+        if self.is_ancestor(target_id, self.cwd) {
+            return Err("directory in use");
         }
-        for (id, node) in self.nodes.iter() {
-            match &node.node_type {
-                NodeType::DIR { children } => writeln!(
-                    writer,
-                    "D {} {} {}",
-                    id,
-                    node.parent,
-                    children
-                        .values()
-                        .map(|idx| idx.to_string())
-                        .collect::<Vec<String>>()
-                        .join(",")
-                ),
-                NodeType::FILE => writeln!(writer, "F {} {}", id, node.parent.to_string()),
+        if self
+            .read_only
+            .iter()
+            .any(|mount_id| self.is_ancestor(target_id, *mount_id))
+        {
+            return Err("Device or resource busy");
+        }
+        let mut subtree = self
+            .walk(target_id, None)
+            .map(|(id, _)| id)
+            .collect::<Vec<usize>>();
+        subtree.reverse();
+        // paths are computed while the nodes are still linked
+        let removed = subtree
+            .iter()
+            .map(|id| self.path_of(*id))
+            .collect::<Vec<String>>();
+        if self.verbose {
+            for (id, path) in subtree.iter().zip(&removed) {
+                if self.nodes.get(id).unwrap().is_dir_node() {
+                    println!("removed directory '{}'", path);
+                } else {
+                    println!("removed '{}'", path);
+                }
             }
-            .map_err(|_| "Error writing to file")?;
         }
-        Ok(())
+        self.detach(target_id);
+        for id in subtree {
+            self.remove_node(id);
+        }
+        Ok(removed)
     }
 
-    fn reload(&mut self, maybe_filepath: Option<String>) -> FsResult {
-        let file = File::open(maybe_filepath.unwrap_or_else(|| "backup.fs".to_string()))
-            .map_err(|_| "Error opening the backup file")?;
-        let mut reader = BufReader::new(&file);
-
-        let mut buffer = String::new();
-        reader
-            .read_line(&mut buffer)
-            .map_err(|_| "Error reading the backup file")?;
-        let counter;
-        let total_nodes;
-        if let [counter_str, total_nodes_str] =
-            buffer.trim().split(' ').collect::<Vec<&str>>().as_slice()
-        {
-            counter = counter_str
-                .parse::<usize>()
-                .map_err(|_| "Error parsing the backup: error reading counter")?;
-            total_nodes = total_nodes_str
-                .parse::<usize>()
-                .map_err(|_| "Error parsing the backup: error reading total_nodes")?;
-        } else {
-            return Err("Error parsing the backup: not two number on first line");
-        };
-        let mut index = HashMap::new();
-        for _ in 0..total_nodes {
-            let mut buffer = String::new();
-            reader
-                .read_line(&mut buffer)
-                .map_err(|_| "Error reading the backup file")?;
-            if let [id_str, name] = buffer.trim().split(' ').collect::<Vec<&str>>().as_slice() {
-                let id = id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                index.insert(id, name.to_string());
+    // whether ancestor_id is id itself or one of the directories above it
+    fn is_ancestor(&self, ancestor_id: usize, id: usize) -> bool {
+        let mut current_id = id;
+        while current_id != 0 {
+            if current_id == ancestor_id {
+                return true;
             }
+            current_id = self.nodes.get(&current_id).unwrap().parent;
         }
-        let mut nodes = HashMap::new();
-        for _ in 0..total_nodes {
-            let mut buffer = String::new();
-            reader
-                .read_line(&mut buffer)
-                .map_err(|_| "Error reading the backup file")?;
+        ancestor_id == 0
+    }
 
-            if let ["D", id_str, parent_id_str, children_str] =
-                buffer.trim().split(' ').collect::<Vec<&str>>().as_slice()
-            {
-                let id = id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                let name = index.get(&id).ok_or("Error rebuilding the backup")?.clone();
-                let parent = parent_id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                let children = children_str
-                    .split(',')
-                    .map(|s| s.parse::<usize>())
-                    .map(|r| r.map_err(|_| "Error parsing the backup: not two numbers for index"))
-                    .map(|r| {
-                        r.and_then(|i| {
-                            index
-                                .get(&i)
-                                .map(|v| (v.clone(), i))
-                                .ok_or_else(|| "Error parsing")
-                        })
-                    })
-                    .collect::<Result<HashMap<String, usize>, _>>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
+    // works out where cp/mv should place the source node. Ok(None) means the
+    // destination exists and the policy says to leave it alone. With merge, a
+    // directory landing on an existing directory is always handed back.
+    fn resolve_destination(
+        &self,
+        src_id: usize,
+        dest_name: &str,
+        policy: Overwrite,
+        merge: bool,
+    ) -> Result<Option<Destination>, &'static str> {
+        let (absolute, path) = split_path(dest_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let src_name = &self.nodes.get(&src_id).unwrap().name;
+        let (parent_id, name, replaced) = match self.find(start_id, &path) {
+            Ok(dest_id) => {
+                // a symlink to a directory stands for the directory; any
+                // other symlink is an entry of its own
+                let target_id = self.find_follow(start_id, &path).unwrap_or(dest_id);
+                let dest_node = self.nodes.get(&dest_id).unwrap();
+                match &self.nodes.get(&target_id).unwrap().node_type {
+                    NodeType::DIR { children } => {
+                        (target_id, src_name.clone(), children.get(src_name).copied())
+                    }
+                    // a trailing slash insists on an existing directory
+                    NodeType::FILE { .. } | NodeType::SYMLINK { .. }
+                        if dest_name.ends_with('/') =>
+                    {
+                        return Err("Not a directory")
+                    }
+                    NodeType::FILE { .. } | NodeType::SYMLINK { .. } => {
+                        (dest_node.parent, dest_node.name.clone(), Some(dest_id))
+                    }
+                }
+            }
+            Err(_) if dest_name.ends_with('/') => return Err("Not a directory"),
+            Err(_) => {
+                let (name, base_path) = path.split_last().ok_or("missing destination")?;
+                if !is_valid_name(name) {
+                    return Err("invalid name");
+                }
+                let parent_id = self.find_follow(start_id, base_path)?;
+                if !self.nodes.get(&parent_id).unwrap().is_dir_node() {
+                    return Err("Not a directory");
+                }
+                (parent_id, (*name).to_string(), None)
+            }
+        };
 
-                let node = FsNode {
-                    name,
-                    parent,
-                    node_type: NodeType::DIR { children },
-                };
-                nodes.insert(id, node);
-            } else if let ["D", id_str, parent_id_str] =
-                buffer.trim().split(' ').collect::<Vec<&str>>().as_slice()
-            {
-                let id = id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                let name = index.get(&id).ok_or("Error rebuilding the backup")?.clone();
-                let parent = parent_id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                let node = FsNode {
-                    name,
-                    parent,
-                    node_type: NodeType::DIR {
-                        children: HashMap::new(),
-                    },
-                };
-                nodes.insert(id, node);
-            } else if let ["F", id_str, parent_id_str] =
-                buffer.trim().split(' ').collect::<Vec<&str>>().as_slice()
-            {
-                let id = id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                let name = index.get(&id).ok_or("Error rebuilding the backup")?.clone();
-                let parent = parent_id_str
-                    .parse::<usize>()
-                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
-                let node = FsNode {
-                    name,
-                    parent,
-                    node_type: NodeType::FILE,
-                };
-                nodes.insert(id, node);
-            } else {
-                return Err("Error rebuilding the backup");
+        // the source itself is left for the caller to judge, whatever the policy
+        let merging = |existing_id: &usize| {
+            merge
+                && self.nodes.get(&src_id).unwrap().is_dir_node()
+                && self.nodes.get(existing_id).unwrap().is_dir_node()
+        };
+        if let Some(existing_id) =
+            replaced.filter(|existing_id| *existing_id != src_id && !merging(existing_id))
+        {
+            match policy {
+                Overwrite::Refuse => return Err("File exists"),
+                Overwrite::Skip => return Ok(None),
+                Overwrite::Force => self.check_overwrite(src_id, existing_id)?,
             }
         }
+        Ok(Some(Destination {
+            parent_id,
+            name,
+            replaced,
+        }))
+    }
 
-        self.nodes = nodes;
-        self.cwd = 0;
-        self.counter = counter;
-        Ok(())
+    // whether src_id may replace existing_id: as in coreutils, a directory
+    // and a non-directory never replace one another
+    fn check_overwrite(&self, src_id: usize, existing_id: usize) -> FsResult {
+        let src_is_dir = self.nodes.get(&src_id).unwrap().is_dir_node();
+        match (
+            src_is_dir,
+            self.nodes.get(&existing_id).unwrap().is_dir_node(),
+        ) {
+            (false, true) => Err("cannot overwrite directory with non-directory"),
+            (true, false) => Err("cannot overwrite non-directory with directory"),
+            _ => Ok(()),
+        }
     }
-}
 
-fn split_path(path_name: &str) -> Vec<&str> {
-    path_name
-        .trim_matches('/')
-        .split('/')
-        .filter(|name| *name != "")
-        .collect()
-}
+    // unlinks the node from its parent's children, leaving it in the node map
+    fn detach(&mut self, id: usize) {
+        let node = self.nodes.get(&id).unwrap();
+        let parent_id = node.parent;
+        let name = node.name.clone();
+        if let NodeType::DIR { children } = &mut self.nodes.get_mut(&parent_id).unwrap().node_type {
+            children.remove(&name);
+        };
+        self.invalidate_paths();
+    }
 
-enum Command {
-    Pwd,
-    Quit,
-    MkDir(String),
-    Creat(String),
-    RmDir(String),
-    Rm(String),
-    Ls(Option<String>),
-    Cd(Option<String>),
-    Save(Option<String>),
-    Reload(Option<String>),
-    NoOp,
-}
+    // links the node under parent_id as name
+    fn attach(&mut self, id: usize, parent_id: usize, name: &str) {
+        let node = self.nodes.get_mut(&id).unwrap();
+        node.parent = parent_id;
+        let old_name = std::mem::replace(&mut node.name, name.to_string());
+        node.ctime = self.clock.now();
+        if old_name != name {
+            self.unindex_name(&old_name, id);
+            self.names.entry(name.to_string()).or_default().push(id);
+        }
+        if let NodeType::DIR { children } = &mut self.nodes.get_mut(&parent_id).unwrap().node_type {
+            children.insert(name.to_string(), id);
+        };
+    }
 
-fn parse_command(command: &str) -> Result<Command, &'static str> {
-    let mut iter = command.trim().split(' ');
-    match iter.next() {
-        Some("pwd") => Ok(Command::Pwd),
-        Some("quit") => Ok(Command::Quit),
-        Some("mkdir") => {
-            if let Some(filename) = iter.next() {
-                Ok(Command::MkDir(filename.to_string()))
+    // removes the file that cp/mv is about to replace
+    fn replace(&mut self, replaced: Option<usize>) {
+        if let Some(existing_id) = replaced {
+            self.detach(existing_id);
+            self.remove_node(existing_id);
+        }
+    }
+
+    // replaces like replace, or with backup renames the file to its name
+    // followed by as many '~' as it takes to be unique
+    fn replace_or_back_up(&mut self, replaced: Option<usize>, backup: bool) {
+        match replaced {
+            Some(existing_id) if backup => {
+                let node = self.nodes.get(&existing_id).unwrap();
+                let parent_id = node.parent;
+                let mut name = format!("{}~", node.name);
+                while let NodeType::DIR { children } =
+                    &self.nodes.get(&parent_id).unwrap().node_type
+                {
+                    if !children.contains_key(&name) {
+                        break;
+                    }
+                    name.push('~');
+                }
+                self.detach(existing_id);
+                self.attach(existing_id, parent_id, &name);
+            }
+            _ => self.replace(replaced),
+        }
+    }
+
+    // number of directories above the node
+    fn depth_of(&self, id: usize) -> usize {
+        let mut depth = 0;
+        let mut current_id = id;
+        while current_id != 0 {
+            current_id = self.nodes.get(&current_id).unwrap().parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    // fails if the node at id, placed at the given depth, would put itself or
+    // (with subtree) anything below it deeper than max_depth
+    fn check_depth(&self, id: usize, depth: usize, subtree: bool) -> FsResult {
+        let exceeded = match self.max_depth.checked_sub(depth) {
+            None => true,
+            Some(_) if !subtree => false,
+            // the walk stops one level past the limit, so it cannot run away
+            Some(left) => self.walk(id, Some(left + 1)).any(|(_, d)| d > left),
+        };
+        if exceeded {
+            Err("maximum directory depth exceeded")
+        } else {
+            Ok(())
+        }
+    }
+
+    // fails unless count more nodes fit under max_nodes
+    fn check_free_nodes(&self, count: usize) -> FsResult {
+        match self.max_nodes {
+            Some(max) if self.nodes.len() + count > max => Err("No space left on device (inodes)"),
+            _ => Ok(()),
+        }
+    }
+
+    // copies the source and returns how many nodes were created. As with
+    // coreutils, `cp -r src dest` copies src *into* dest (as dest/src) when
+    // dest is an existing directory, and *as* dest when dest doesn't exist.
+    // When dest/src is itself an existing directory the two are merged, the
+    // policy settling clashes with files already there.
+    fn cp(
+        &mut self,
+        src_name: &str,
+        dest_name: &str,
+        options: CopyOptions,
+    ) -> Result<usize, &'static str> {
+        let (absolute, path) = split_path(src_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let src_id = self.find_follow(start_id, &path)?;
+        if !options.recursive && !self.nodes.get(&src_id).unwrap().is_file_node() {
+            return Err("-r not specified; omitting directory");
+        }
+        let dest_name = if options.parents {
+            let (dest_absolute, dest_path) = split_path(dest_name);
+            let dest_start = if dest_absolute { 0 } else { self.cwd };
+            match self.find_follow(dest_start, &dest_path) {
+                Ok(id) if self.nodes.get(&id).unwrap().is_dir_node() => {}
+                _ => return Err("with --parents, the destination must be a directory"),
+            }
+            let dest_parent = match path.split_last() {
+                Some((_, dirs)) if !dirs.is_empty() => {
+                    format!("{}/{}", dest_name.trim_end_matches('/'), dirs.join("/"))
+                }
+                _ => dest_name.to_string(),
+            };
+            self.mkdir_p(&dest_parent, None)?;
+            dest_parent
+        } else {
+            dest_name.to_string()
+        };
+        self.cp_node(src_id, &dest_name, options)
+    }
+
+    // cp once the source is resolved, so that merges can copy entries by id
+    // without following symlinks among them
+    fn cp_node(
+        &mut self,
+        src_id: usize,
+        dest_name: &str,
+        options: CopyOptions,
+    ) -> Result<usize, &'static str> {
+        match self.resolve_destination(src_id, dest_name, options.policy(), true)? {
+            Some(dest) => {
+                // -u skips a file that is at least as new as the source;
+                // directories are still merged, entry by entry
+                let up_to_date = |existing_id: &usize| {
+                    let existing = self.nodes.get(existing_id).unwrap();
+                    *existing_id != src_id
+                        && !existing.is_dir_node()
+                        && existing.mtime >= self.nodes.get(&src_id).unwrap().mtime
+                };
+                if options.update && dest.replaced.as_ref().is_some_and(up_to_date) {
+                    return Ok(0);
+                }
+                self.check_writable(dest.parent_id)?;
+                dest.replaced.map_or(Ok(()), |id| self.check_writable(id))?;
+                if self.is_ancestor(src_id, dest.parent_id) {
+                    return Err("cannot copy a directory into itself");
+                }
+                if dest.replaced == Some(src_id) {
+                    return Err(SAME_FILE);
+                }
+                if let Some(existing_id) = dest
+                    .replaced
+                    .filter(|existing_id| self.nodes.get(existing_id).unwrap().is_dir_node())
+                {
+                    return self.merge_copy(src_id, existing_id, options);
+                }
+                self.check_depth(src_id, self.depth_of(dest.parent_id) + 1, true)?;
+                let freed = dest.replaced.filter(|_| !options.backup).map_or(0, |_| 1);
+                self.check_free_nodes(self.walk(src_id, None).count() - freed)?;
+                self.replace_or_back_up(dest.replaced, options.backup);
+                Ok(self.copy_tree(None, src_id, dest.parent_id, &dest.name, options.progress))
+            }
+            None => Ok(0),
+        }
+    }
+
+    // copies the entries of directory src_id into directory dest_id for
+    // cp_node. A failing entry is reported and the rest are still copied.
+    fn merge_copy(
+        &mut self,
+        src_id: usize,
+        dest_id: usize,
+        options: CopyOptions,
+    ) -> Result<usize, &'static str> {
+        let mut entries = match &self.nodes.get(&src_id).unwrap().node_type {
+            NodeType::DIR { children } => children
+                .iter()
+                .map(|(name, id)| (name.clone(), *id))
+                .collect::<Vec<(String, usize)>>(),
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => unreachable!(),
+        };
+        entries.sort();
+        let dest_path = self.path_of(dest_id);
+        let mut copied = 0;
+        let mut failed = false;
+        for (_, id) in entries {
+            match self.cp_node(id, &dest_path, options) {
+                Ok(count) => copied += count,
+                // a nested merge has already reported its own entries
+                Err(SOME_NOT_COPIED) => failed = true,
+                Err(err) => {
+                    failed = true;
+                    println!(
+                        "cp: cannot copy '{}' to '{}': {}",
+                        self.path_of(id),
+                        dest_path,
+                        err
+                    );
+                }
+            }
+        }
+        if failed {
+            Err(SOME_NOT_COPIED)
+        } else {
+            Ok(copied)
+        }
+    }
+
+    // deep-copies the subtree rooted at src_id under parent_id as name, giving
+    // every copy a fresh id, and returns the number of nodes copied. The
+    // subtree is read from src, another instance, or from this one if None.
+    fn copy_tree(
+        &mut self,
+        src: Option<&FileSystem>,
+        src_id: usize,
+        parent_id: usize,
+        name: &str,
+        progress: bool,
+    ) -> usize {
+        let mut copied = 0;
+        let mut stack = vec![(src_id, parent_id, name.to_string())];
+        while let Some((id, parent_id, name)) = stack.pop() {
+            let new_id = self.counter + 1;
+            let src_node = src.unwrap_or(self).nodes.get(&id).unwrap();
+            let mut new_node = FsNode::new_file_node(&name, parent_id, self.clock.now());
+            new_node.mode = src_node.mode;
+            new_node.node_type = match &src_node.node_type {
+                NodeType::DIR { children } => {
+                    stack.extend(
+                        children
+                            .iter()
+                            .map(|(child_name, child_id)| (*child_id, new_id, child_name.clone())),
+                    );
+                    NodeType::DIR {
+                        children: HashMap::new(),
+                    }
+                }
+                node_type => node_type.clone(),
+            };
+            self.insert_node(new_id, new_node);
+            self.counter = new_id;
+            self.attach(new_id, parent_id, &name);
+            // paths in another instance would read as paths in this one
+            if self.verbose && src.is_none() {
+                println!("'{}' -> '{}'", self.path_of(id), self.path_of(new_id));
+            }
+            copied += 1;
+            if progress && copied % CP_PROGRESS_INTERVAL == 0 {
+                println!("cp: copied {} nodes", copied);
+            }
+        }
+        copied
+    }
+
+    // deep-copies the node at src_name into another instance, where dst_name
+    // works like a cp -r destination: an existing directory receives the
+    // copy under the source's name, and a new name becomes the copy. Returns
+    // the number of nodes copied, all of them given fresh ids in dst.
+    fn transfer(
+        &self,
+        src_name: &str,
+        dst: &mut FileSystem,
+        dst_name: &str,
+    ) -> Result<usize, &'static str> {
+        let (absolute, path) = split_path(src_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let src_id = self.find_follow(start_id, &path)?;
+        // the root has no name to copy under; reload --merge grafts it
+        if src_id == 0 {
+            return Err("cannot copy root directory");
+        }
+        let (absolute, path) = split_path(dst_name);
+        let start_id = if absolute { 0 } else { dst.cwd };
+        let (parent_id, name) = match dst.find_follow(start_id, &path) {
+            Ok(id) if dst.nodes.get(&id).unwrap().is_dir_node() => {
+                (id, self.nodes.get(&src_id).unwrap().name.clone())
+            }
+            Ok(_) => return Err("File exists"),
+            Err(_) => {
+                let (name, base_path) = path.split_last().ok_or("missing destination")?;
+                if !is_valid_name(name) {
+                    return Err("invalid name");
+                }
+                let parent_id = dst.find_follow(start_id, base_path)?;
+                if !dst.nodes.get(&parent_id).unwrap().is_dir_node() {
+                    return Err("Not a directory");
+                }
+                (parent_id, (*name).to_string())
+            }
+        };
+        if let NodeType::DIR { children } = &dst.nodes.get(&parent_id).unwrap().node_type {
+            if children.contains_key(&name) {
+                return Err("File exists");
+            }
+        }
+        dst.check_writable(parent_id)?;
+        let (count, height) = self
+            .walk(src_id, None)
+            .fold((0, 0), |(count, height), (_, depth)| {
+                (count + 1, height.max(depth))
+            });
+        if dst.depth_of(parent_id) + 1 + height > dst.max_depth {
+            return Err("maximum directory depth exceeded");
+        }
+        dst.check_free_nodes(count)?;
+        Ok(dst.copy_tree(Some(self), src_id, parent_id, &name, false))
+    }
+
+    // takes back the latest recorded change and returns the path it restored.
+    // Other commands aren't recorded, so the old spot is checked again: a
+    // record that no longer applies is dropped with an error.
+    fn undo(&mut self) -> Result<String, &'static str> {
+        match self.undo_history.pop_back().ok_or("nothing to undo")? {
+            UndoRecord::Moved { id, parent, name } => {
+                if !self.nodes.contains_key(&id) {
+                    return Err("the moved node no longer exists");
+                }
+                match self.nodes.get(&parent).map(|node| &node.node_type) {
+                    Some(NodeType::DIR { children }) if children.contains_key(&name) => {
+                        return Err("File exists")
+                    }
+                    Some(NodeType::DIR { .. }) => {}
+                    _ => return Err("the old parent directory no longer exists"),
+                }
+                if self.is_ancestor(id, parent) {
+                    return Err("cannot move a directory into itself");
+                }
+                self.check_writable(id)?;
+                self.check_writable(parent)?;
+                self.detach(id);
+                self.attach(id, parent, &name);
+                Ok(self.path_of(id))
+            }
+        }
+    }
+
+    // moves every entry of the directory up into its parent, then removes
+    // the emptied directory. A name the parent already has, the directory's
+    // own included, fails before anything moves.
+    fn flatten(&mut self, path_name: &str) -> FsResult {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let dir_id = self.find(start_id, &path)?;
+        if dir_id == 0 {
+            return Err("cannot flatten root directory");
+        }
+        self.check_writable(dir_id)?;
+        if self.is_ancestor(dir_id, self.cwd) {
+            return Err("directory in use");
+        }
+        let dir = self.nodes.get(&dir_id).unwrap();
+        let parent_id = dir.parent;
+        let mut names = match &dir.node_type {
+            NodeType::DIR { children } => children.keys().cloned().collect::<Vec<String>>(),
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => return Err("Not a directory"),
+        };
+        names.sort();
+        if let NodeType::DIR { children } = &self.nodes.get(&parent_id).unwrap().node_type {
+            if names.iter().any(|name| children.contains_key(name)) {
+                return Err("File exists");
+            }
+        }
+        let dir_path = self.path_of(dir_id);
+        let parent_path = self.path_of(parent_id);
+        for name in &names {
+            self.mv(
+                &format!("{}/{}", dir_path, name),
+                &format!("{}/{}", parent_path.trim_end_matches('/'), name),
+                CopyOptions::default(),
+            )?;
+        }
+        self.rmdir(&dir_path)?;
+        // the moves were recorded with the removed directory as their old
+        // parent, and undo can't bring a directory back
+        self.undo_history.clear();
+        Ok(())
+    }
+
+    // moves or renames the node in place, keeping its id. Every check runs
+    // before the first mutation, so a failed move leaves the tree untouched.
+    fn mv(&mut self, src_name: &str, dest_name: &str, options: CopyOptions) -> FsResult {
+        let policy = options.policy();
+        let (absolute, path) = split_path(src_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let src_id = self.find(start_id, &path)?;
+        if src_id == 0 {
+            return Err("cannot move root directory");
+        }
+        self.check_writable(src_id)?;
+        if let Some(dest) = self.resolve_destination(src_id, dest_name, policy, true)? {
+            self.check_writable(dest.parent_id)?;
+            dest.replaced.map_or(Ok(()), |id| self.check_writable(id))?;
+            if self.is_ancestor(src_id, dest.parent_id) {
+                return Err("cannot move a directory into itself");
+            }
+            self.check_depth(src_id, self.depth_of(dest.parent_id) + 1, true)?;
+            if dest.replaced == Some(src_id) {
+                return Ok(());
+            }
+            if let Some(existing_id) = dest
+                .replaced
+                .filter(|existing_id| self.nodes.get(existing_id).unwrap().is_dir_node())
+            {
+                // a merge moves or drops many nodes, which one record can't
+                // take back, and the older records may no longer apply
+                self.undo_history.clear();
+                return self.merge_dirs(src_id, existing_id, policy);
+            }
+            // validation is done; from here on nothing can fail
+            let old_path = self.path_of(src_id);
+            // a replaced file is gone for good, so such a mv can't be undone
+            let node = self.nodes.get(&src_id).unwrap();
+            if dest.replaced.is_none() {
+                self.undo_history.push_back(UndoRecord::Moved {
+                    id: src_id,
+                    parent: node.parent,
+                    name: node.name.clone(),
+                });
+                if self.undo_history.len() > UNDO_HISTORY_SIZE {
+                    self.undo_history.pop_front();
+                }
             } else {
-                Err("missing operand")
+                self.undo_history.clear();
+            }
+            self.replace_or_back_up(dest.replaced, options.backup);
+            self.detach(src_id);
+            self.attach(src_id, dest.parent_id, &dest.name);
+            if self.verbose {
+                println!("renamed '{}' -> '{}'", old_path, self.path_of(src_id));
             }
         }
-        Some("ls") => Ok(Command::Ls(iter.next().map(|name| name.to_string()))),
-        Some("cd") => Ok(Command::Cd(iter.next().map(|name| name.to_string()))),
-        Some("rmdir") => iter
-            .next()
-            .ok_or("missing operand")
-            .and_then(|path| {
-                if path == "/" {
-                    Err("cannot remove root directory")
+        Ok(())
+    }
+
+    // moves the entries of directory src_id into directory dest_id, merging
+    // subdirectories that exist on both sides. A clash involving a file is
+    // settled by the policy; refused clashes are reported and stay behind,
+    // along with the directories holding them, while emptied source
+    // directories are removed.
+    fn merge_dirs(&mut self, src_id: usize, dest_id: usize, policy: Overwrite) -> FsResult {
+        let mut conflicts = 0;
+        let mut merged = Vec::new();
+        let mut stack = vec![(src_id, dest_id)];
+        while let Some((src_dir, dest_dir)) = stack.pop() {
+            merged.push(src_dir);
+            let mut entries = match &self.nodes.get(&src_dir).unwrap().node_type {
+                NodeType::DIR { children } => children
+                    .iter()
+                    .map(|(name, id)| (name.clone(), *id))
+                    .collect::<Vec<(String, usize)>>(),
+                NodeType::FILE { .. } | NodeType::SYMLINK { .. } => unreachable!(),
+            };
+            entries.sort();
+            for (name, id) in entries {
+                let existing = match &self.nodes.get(&dest_dir).unwrap().node_type {
+                    NodeType::DIR { children } => children.get(&name).copied(),
+                    NodeType::FILE { .. } | NodeType::SYMLINK { .. } => unreachable!(),
+                };
+                if let Some(existing_id) = existing {
+                    let both_dirs = self.nodes.get(&id).unwrap().is_dir_node()
+                        && self.nodes.get(&existing_id).unwrap().is_dir_node();
+                    if both_dirs {
+                        stack.push((id, existing_id));
+                        continue;
+                    }
+                    let refused = match policy {
+                        Overwrite::Skip => continue,
+                        Overwrite::Force => self.check_overwrite(id, existing_id).err(),
+                        Overwrite::Refuse => Some("File exists"),
+                    };
+                    if let Some(err) = refused {
+                        conflicts += 1;
+                        println!(
+                            "mv: cannot move '{}' to '{}': {}",
+                            self.path_of(id),
+                            self.path_of(existing_id),
+                            err
+                        );
+                        continue;
+                    }
+                    self.replace(Some(existing_id));
+                }
+                let old_path = self.path_of(id);
+                self.detach(id);
+                self.attach(id, dest_dir, &name);
+                if self.verbose {
+                    println!("renamed '{}' -> '{}'", old_path, self.path_of(id));
+                }
+            }
+        }
+        // children were merged after their parents, so going backwards frees
+        // the deepest directories first
+        for dir_id in merged.into_iter().rev() {
+            let is_empty = match &self.nodes.get(&dir_id).unwrap().node_type {
+                NodeType::DIR { children } => children.is_empty(),
+                NodeType::FILE { .. } | NodeType::SYMLINK { .. } => false,
+            };
+            if is_empty && !self.is_ancestor(dir_id, self.cwd) {
+                self.detach(dir_id);
+                self.remove_node(dir_id);
+            }
+        }
+        if conflicts > 0 {
+            Err("some entries were left in place")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn stat(&self, path_name: &str) -> FsResult {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find(start_id, &path)?;
+        let node = self.nodes.get(&target_id).unwrap();
+        if let NodeType::SYMLINK { target } = &node.node_type {
+            println!("  File: {} -> {}", node.name, target);
+        } else {
+            println!("  File: {}", node.name);
+        }
+        println!("  Path: {}", self.path_of(target_id));
+        println!(" Inode: {}", target_id);
+        println!("  Type: {}", node.type_name());
+        println!("  Size: {}", node.size());
+        println!("Blocks: {}", self.blocks(node));
+        println!("  Mode: {:04o}", node.mode);
+        if target_id == 0 {
+            // the root is its own parent
+            println!("Parent: 0 (root directory)");
+        } else {
+            println!("Parent: {}", node.parent);
+        }
+        println!("Access: {}", format_time(node.atime));
+        println!("Modify: {}", format_time(node.mtime));
+        println!("Change: {}", format_time(node.ctime));
+        println!(" Birth: {}", format_time(node.created));
+        Ok(())
+    }
+
+    // the node's metadata as a single-line JSON object; mode is an octal
+    // string and times are seconds since the epoch
+    fn stat_json(&self, path_name: &str) -> Result<String, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find(start_id, &path)?;
+        let node = self.nodes.get(&target_id).unwrap();
+        let target = match &node.node_type {
+            NodeType::SYMLINK { target } => json_string(target),
+            NodeType::FILE { .. } | NodeType::DIR { .. } => "null".to_string(),
+        };
+        Ok(format!(
+            "{{\"id\": {}, \"name\": {}, \"path\": {}, \"type\": {}, \"target\": {}, \
+             \"parent\": {}, \"size\": {}, \"blocks\": {}, \"mode\": \"{:04o}\", \"uid\": {}, \
+             \"links\": {}, \"atime\": {}, \"mtime\": {}, \"ctime\": {}, \"btime\": {}}}",
+            target_id,
+            json_string(&node.name),
+            json_string(&self.path_of(target_id)),
+            json_string(node.type_name()),
+            target,
+            node.parent,
+            node.size(),
+            self.blocks(node),
+            node.mode,
+            node.uid,
+            self.link_count(target_id),
+            format_time(node.atime),
+            format_time(node.mtime),
+            format_time(node.ctime),
+            format_time(node.created),
+        ))
+    }
+
+    // expands the %-tokens of a `stat -c`/`stat --printf` format for the node
+    fn stat_format(&self, path_name: &str, format: &str) -> Result<String, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find(start_id, &path)?;
+        let node = self.nodes.get(&target_id).unwrap();
+        let mut output = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                output.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => output.push_str(&node.name),
+                Some('i') => output.push_str(&target_id.to_string()),
+                Some('s') => output.push_str(&node.size().to_string()),
+                Some('b') => output.push_str(&self.blocks(node).to_string()),
+                Some('F') => output.push_str(node.type_name()),
+                Some('h') => output.push_str(&self.link_count(target_id).to_string()),
+                Some('U') => output.push_str(&node.uid.to_string()),
+                Some(token @ ('Y' | 'W')) => {
+                    let time = if token == 'Y' {
+                        node.mtime
+                    } else {
+                        node.created
+                    };
+                    output.push_str(
+                        &time
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs()
+                            .to_string(),
+                    )
+                }
+                Some('%') => output.push('%'),
+                Some(_) => return Err("unknown format token"),
+                None => return Err("format ends with a lone '%'"),
+            }
+        }
+        Ok(output)
+    }
+
+    // the blocks the node's content takes, a partial block counting whole
+    fn blocks(&self, node: &FsNode) -> usize {
+        node.size().div_ceil(self.block_size)
+    }
+
+    // there are no hard links, so a file has one link and a directory one from
+    // its parent, one from its own "." and one from each subdirectory's ".."
+    fn link_count(&self, id: usize) -> usize {
+        match &self.nodes.get(&id).unwrap().node_type {
+            NodeType::DIR { children } => {
+                2 + children
+                    .values()
+                    .filter(|child_id| self.nodes.get(child_id).unwrap().is_dir_node())
+                    .count()
+            }
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => 1,
+        }
+    }
+
+    // walks the subtree rooted at start_name depth first, in name order, and
+    // returns the absolute paths of the nodes matching the query
+    fn search(&self, start_name: &str, query: &FindQuery) -> Result<Vec<String>, &'static str> {
+        Ok(self
+            .search_ids(start_name, query)?
+            .into_iter()
+            .map(|id| {
+                if query.relative {
+                    self.relpath(self.cwd, id)
                 } else {
-                    Ok(path)
+                    self.path_of(id)
                 }
             })
-            .map(|path| Command::RmDir(path.to_string())),
-        Some("creat") => iter
-            .next()
-            .ok_or("missing operand")
-            .map(|path| Command::Creat(path.to_string())),
-        Some("rm") => iter
-            .next()
-            .ok_or("missing operand")
-            .map(|path| Command::Rm(path.to_string())),
-        Some("save") => Ok(Command::Save(iter.next().map(|name| name.to_string()))),
-        Some("reload") => Ok(Command::Reload(iter.next().map(|name| name.to_string()))),
-        Some("") => Ok(Command::NoOp),
-        _ => Err("not implemented"),
+            .collect())
     }
-}
 
-fn main() {
-    let mut fs = FileSystem::new();
-    loop {
-        let mut command = String::new();
-        print!("$ ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut command).unwrap();
-        match parse_command(&command) {
-            Ok(command) => match command {
-                Command::Pwd => fs.pwd(),
-                Command::Quit => {
-                    println!("Saving...");
-                    fs.save(None)
-                        .unwrap_or_else(|err| println!("Quitting without saving: {}", err));
-                    break;
+    // the path that leads from the directory from_id to to_id: ".." up to
+    // their closest common ancestor, then the names down from it; "." when
+    // they are the same node
+    fn relpath(&self, from_id: usize, to_id: usize) -> String {
+        let ancestry = |mut id: usize| {
+            let mut ids = vec![id];
+            while id != 0 {
+                id = self.nodes[&id].parent;
+                ids.push(id);
+            }
+            ids.reverse();
+            ids
+        };
+        let from = ancestry(from_id);
+        let to = ancestry(to_id);
+        let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+        let mut parts = vec![".."; from.len() - common];
+        parts.extend(to[common..].iter().map(|id| self.nodes[id].name.as_str()));
+        if parts.is_empty() {
+            ".".to_string()
+        } else {
+            parts.join("/")
+        }
+    }
+
+    // like search, but returns the matching ids and builds no paths
+    fn search_ids(&self, start_name: &str, query: &FindQuery) -> Result<Vec<usize>, &'static str> {
+        let (absolute, path) = split_path(start_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let start_id = self.find_follow(start_id, &path)?;
+        // the depth guard is checked along the way rather than up front, so a
+        // --limit search stops walking at its last match
+        let left = self
+            .max_depth
+            .checked_sub(self.depth_of(start_id))
+            .ok_or("maximum directory depth exceeded")?;
+        let walk_depth = query.max_depth.map_or(left + 1, |max| max.min(left + 1));
+        let mut matches = Vec::new();
+        // names from below the start down to the current node, kept for -path
+        let mut components: Vec<&str> = Vec::new();
+        for (id, depth) in self.walk(start_id, Some(walk_depth)) {
+            if query.limit.is_some_and(|limit| matches.len() >= limit) {
+                break;
+            }
+            if depth > left {
+                return Err("maximum directory depth exceeded");
+            }
+            let node = self.nodes.get(&id).unwrap();
+            if depth > 0 {
+                components.truncate(depth - 1);
+                components.push(&node.name);
+            }
+            let path_matches = query.path.as_ref().is_none_or(|pattern| {
+                let path = if components.is_empty() {
+                    start_name.to_string()
+                } else {
+                    format!(
+                        "{}/{}",
+                        start_name.trim_end_matches('/'),
+                        components.join("/")
+                    )
+                };
+                glob_match(pattern, &path)
+            });
+            if query.min_depth.is_none_or(|min_depth| depth >= min_depth)
+                && path_matches
+                && self.matches_query(node, query)
+            {
+                matches.push(id);
+            }
+        }
+        Ok(matches)
+    }
+
+    // find -delete: removes every match and returns the path and error of
+    // each one that couldn't go. All matches are collected before the first
+    // removal, and going backwards removes children before their parents
+    fn find_delete(
+        &mut self,
+        start_name: &str,
+        query: &FindQuery,
+    ) -> Result<Vec<(String, &'static str)>, &'static str> {
+        let mut failed = Vec::new();
+        for id in self.search_ids(start_name, query)?.into_iter().rev() {
+            let path = self.path_of(id);
+            let removed = if self.nodes.get(&id).unwrap().is_dir_node() {
+                self.rmdir(&path).map(drop)
+            } else {
+                self.rm(&path)
+            };
+            if let Err(err) = removed {
+                failed.push((path, err));
+            }
+        }
+        Ok(failed)
+    }
+
+    // find -chmod and -chown: changes every match, symlinks included, and
+    // returns the path and error of each one that couldn't be changed. All
+    // matches are collected before the first change
+    fn find_apply(
+        &mut self,
+        start_name: &str,
+        query: &FindQuery,
+    ) -> Result<Vec<(String, &'static str)>, &'static str> {
+        let mut failed = Vec::new();
+        for id in self.search_ids(start_name, query)? {
+            let changed = query
+                .chmod
+                .as_ref()
+                .map_or(Ok(()), |mode| self.chmod_node(id, mode))
+                .and_then(|_| query.chown.map_or(Ok(()), |uid| self.chown_node(id, uid)));
+            if let Err(err) = changed {
+                failed.push((self.path_of(id), err));
+            }
+        }
+        Ok(failed)
+    }
+
+    // the entries of the partial path's directory whose names start with its
+    // last component, each written out as the full candidate path; directories
+    // (symlinks to them included) end in '/'
+    fn complete(&self, partial: &str) -> Result<Vec<String>, &'static str> {
+        let (dir_part, leaf) = match partial.rfind('/') {
+            Some(i) => partial.split_at(i + 1),
+            None => ("", partial),
+        };
+        let (absolute, path) = split_path(dir_part);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let dir_id = self.find_follow(start_id, &path)?;
+        let children = match &self.nodes.get(&dir_id).unwrap().node_type {
+            NodeType::DIR { children } => children,
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => return Err("Not a directory"),
+        };
+        let mut candidates = children
+            .iter()
+            .filter(|(name, _)| name.starts_with(leaf))
+            .map(|(name, id)| {
+                let is_dir = self
+                    .follow_with_hops(*id, &mut 0)
+                    .is_ok_and(|target| self.nodes.get(&target).unwrap().is_dir_node());
+                format!("{}{}{}", dir_part, name, if is_dir { "/" } else { "" })
+            })
+            .collect::<Vec<String>>();
+        candidates.sort();
+        Ok(candidates)
+    }
+
+    // the lines of the file at path_name containing pattern or, with
+    // recursive, of every file in the subtree rooted there, prefixed with the
+    // file's path. Symlinks are followed only at the start.
+    fn grep(
+        &self,
+        pattern: &str,
+        path_name: &str,
+        options: GrepOptions,
+    ) -> Result<Vec<String>, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let start_id = self.find_follow(start_id, &path)?;
+        let files = if options.recursive {
+            self.check_depth(start_id, self.depth_of(start_id), true)?;
+            self.walk(start_id, None)
+                .map(|(id, _)| id)
+                .filter(|id| self.nodes.get(id).unwrap().is_file_node())
+                .collect()
+        } else if self.nodes.get(&start_id).unwrap().is_dir_node() {
+            return Err("Is a directory");
+        } else {
+            vec![start_id]
+        };
+        let line_numbers = options.line_numbers.unwrap_or(options.recursive);
+        let mut matches = Vec::new();
+        for id in files {
+            if let NodeType::FILE { content } = &self.nodes.get(&id).unwrap().node_type {
+                if options.files_with_matches {
+                    // any stops at the file's first match
+                    if content.lines().any(|line| line.contains(pattern)) {
+                        matches.push(self.path_of(id));
+                    }
+                    continue;
                 }
-                Command::MkDir(filename) => fs.mkdir(&filename).unwrap_or_else(|err| {
-                    println!("mkdir: cannot create directory {}: {}", filename, err)
-                }),
-                Command::Ls(filename) => fs.ls(filename).unwrap_or_else(|err| println!("{}", err)),
-                Command::Cd(filename) => fs
-                    .cd(filename)
-                    .unwrap_or_else(|err| println!("cd: {}", err)),
+                for (lineno, line) in content.lines().enumerate() {
+                    if !line.contains(pattern) {
+                        continue;
+                    }
+                    let mut output = String::new();
+                    if options.recursive {
+                        output.push_str(&self.path_of(id));
+                        output.push(':');
+                    }
+                    if line_numbers {
+                        output.push_str(&(lineno + 1).to_string());
+                        output.push(':');
+                    }
+                    output.push_str(line);
+                    matches.push(output);
+                }
+            }
+        }
+        Ok(matches)
+    }
 
-                Command::RmDir(filename) => fs
-                    .rmdir(&filename)
-                    .unwrap_or_else(|err| println!("rmdir: {}", err)),
-                Command::Creat(filename) => fs.creat(&filename).unwrap_or_else(|err| {
-                    println!("creat: cannot create file {}: {}", filename, err)
-                }),
-                Command::Rm(filename) => fs
-                    .rm(&filename)
-                    .unwrap_or_else(|err| println!("rm: cannot remove {}: {}", filename, err)),
-                Command::Save(maybe_filename) => fs
-                    .save(maybe_filename)
-                    .unwrap_or_else(|err| println!("error saving the filesystem: {}", err)),
-                Command::Reload(maybe_filename) => fs
-                    .reload(maybe_filename)
-                    .unwrap_or_else(|err| println!("error reloading the filesystem: {}", err)),
-                Command::NoOp => continue,
-            },
-            Err(err) => println!("{}", err),
+    // the total size of each directory in the subtree rooted at start_name
+    // (or of start_name alone if it is a file), subdirectories before the
+    // directories holding them, in name order. Entries whose name matches an
+    // exclude pattern are left out along with everything below them.
+    fn du(
+        &self,
+        start_name: &str,
+        options: &DuOptions,
+    ) -> Result<Vec<(usize, String)>, &'static str> {
+        let (absolute, path) = split_path(start_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let start_id = self.find_follow(start_id, &path)?;
+        self.check_depth(start_id, self.depth_of(start_id), true)?;
+        let included = |id: &usize| {
+            let name = &self.nodes.get(id).unwrap().name;
+            !options
+                .exclude
+                .iter()
+                .any(|pattern| glob_match(pattern, name))
+        };
+        let mut totals: HashMap<usize, usize> = HashMap::new();
+        let mut report = Vec::new();
+        // a node is pushed again as done once its children are on the stack
+        let mut stack = vec![(start_id, false)];
+        while let Some((id, done)) = stack.pop() {
+            let node = self.nodes.get(&id).unwrap();
+            let children = match &node.node_type {
+                NodeType::DIR { children } => {
+                    let mut ids = children
+                        .values()
+                        .copied()
+                        .filter(included)
+                        .collect::<Vec<_>>();
+                    ids.sort_by(|a, b| self.nodes[b].name.cmp(&self.nodes[a].name));
+                    ids
+                }
+                NodeType::FILE { .. } | NodeType::SYMLINK { .. } => Vec::new(),
+            };
+            if !done {
+                stack.push((id, true));
+                stack.extend(children.into_iter().map(|child_id| (child_id, false)));
+                continue;
+            }
+            let own = if options.blocks {
+                self.blocks(node)
+            } else {
+                node.size()
+            };
+            let total = own
+                + children
+                    .iter()
+                    .map(|child_id| totals[child_id])
+                    .sum::<usize>();
+            totals.insert(id, total);
+            if node.is_dir_node() || id == start_id {
+                report.push((total, self.path_of(id)));
+            }
+        }
+        Ok(report)
+    }
+
+    // every path in the subtree rooted at start_name, the start included, in
+    // the order of their absolute paths; relative to cwd with relative
+    fn lspaths(&self, start_name: &str, relative: bool) -> Result<Vec<String>, &'static str> {
+        let (absolute, path) = split_path(start_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let start_id = self.find_follow(start_id, &path)?;
+        self.check_depth(start_id, self.depth_of(start_id), true)?;
+        let mut paths = self
+            .walk(start_id, None)
+            .map(|(id, _)| (self.path_of(id), id))
+            .collect::<Vec<(String, usize)>>();
+        paths.sort();
+        Ok(paths
+            .into_iter()
+            .map(|(path, id)| {
+                if relative {
+                    self.relpath(self.cwd, id)
+                } else {
+                    path
+                }
+            })
+            .collect())
+    }
+
+    // draws the subtree rooted at start_name like `tree`, one line per node,
+    // followed by the directory and file counts. Symlinks show their target
+    // and are not followed.
+    fn tree(&self, start_name: &str, options: TreeOptions) -> Result<Vec<String>, &'static str> {
+        let (absolute, path) = split_path(start_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let start_id = self.find_follow(start_id, &path)?;
+        self.check_depth(start_id, self.depth_of(start_id), true)?;
+        let mut nodes = self.walk(start_id, None).collect::<Vec<(usize, usize)>>();
+
+        // --du: sizes are added up over the whole walk, -L or not, going
+        // backwards so every node comes after its subtree. totals[d] holds
+        // what the nodes at depth d seen since their parent add up to.
+        let mut sizes = HashMap::new();
+        if options.du {
+            let mut totals: Vec<usize> = Vec::new();
+            for (id, depth) in nodes.iter().rev() {
+                totals.resize(depth + 2, 0);
+                let size = self.nodes[id].size() + totals[depth + 1];
+                totals.truncate(depth + 1);
+                totals[*depth] += size;
+                sizes.insert(*id, size);
+            }
+        }
+        if let Some(level) = options.level {
+            nodes.retain(|(_, depth)| *depth <= level);
+        }
+        let annotate = |id: usize, name: &str| match sizes.get(&id) {
+            Some(size) => format!("[{}] {}", size, name),
+            None => name.to_string(),
+        };
+
+        // a node is the last of its siblings when no node at its depth follows
+        // before the walk climbs back above it
+        let mut is_last = vec![false; nodes.len()];
+        let mut later_sibling = Vec::new();
+        for (i, (_, depth)) in nodes.iter().enumerate().rev() {
+            later_sibling.resize(depth + 1, false);
+            is_last[i] = !later_sibling[*depth];
+            later_sibling[*depth] = true;
         }
+
+        let mut lines = vec![annotate(start_id, start_name)];
+        let mut open_levels: Vec<bool> = Vec::new();
+        let (mut dirs, mut files) = (0, 0);
+        for (i, (id, depth)) in nodes.iter().enumerate().skip(1) {
+            let node = self.nodes.get(id).unwrap();
+            open_levels.truncate(depth - 1);
+            let mut line = open_levels
+                .iter()
+                .map(|open| if *open { "│   " } else { "    " })
+                .collect::<String>();
+            line.push_str(if is_last[i] {
+                "└── "
+            } else {
+                "├── "
+            });
+            line.push_str(&annotate(*id, &node.name));
+            match &node.node_type {
+                NodeType::DIR { .. } => dirs += 1,
+                NodeType::FILE { .. } => files += 1,
+                NodeType::SYMLINK { target } => {
+                    line.push_str(" -> ");
+                    line.push_str(target);
+                    files += 1;
+                }
+            }
+            lines.push(line);
+            open_levels.push(!is_last[i]);
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{} {}, {} {}",
+            dirs,
+            if dirs == 1 {
+                "directory"
+            } else {
+                "directories"
+            },
+            files,
+            if files == 1 { "file" } else { "files" }
+        ));
+        Ok(lines)
+    }
+
+    fn walk(&self, start_id: usize, max_depth: Option<usize>) -> Walk<'_> {
+        Walk {
+            fs: self,
+            stack: vec![(start_id, 0)],
+            max_depth,
+        }
+    }
+
+    fn matches_query(&self, node: &FsNode, query: &FindQuery) -> bool {
+        if let Some(pattern) = &query.name {
+            if !glob_match(pattern, &node.name) {
+                return false;
+            }
+        }
+        let file_type = match node.node_type {
+            NodeType::FILE { .. } => FindType::File,
+            NodeType::DIR { .. } => FindType::Dir,
+            NodeType::SYMLINK { .. } => FindType::Symlink,
+        };
+        if query.file_type.is_some_and(|wanted| wanted != file_type) {
+            return false;
+        }
+        if !query.sizes.iter().all(|test| test.matches(node.size())) {
+            return false;
+        }
+        if query.empty {
+            let empty = match &node.node_type {
+                NodeType::FILE { content } => content.is_empty(),
+                NodeType::DIR { children } => children.is_empty(),
+                NodeType::SYMLINK { .. } => false,
+            };
+            if !empty {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn cat(&self, path_name: &str) -> Result<&str, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find_follow(start_id, &path)?;
+        match &self.nodes.get(&target_id).unwrap().node_type {
+            NodeType::FILE { content } => Ok(content),
+            NodeType::DIR { .. } => Err("Is a directory"),
+            NodeType::SYMLINK { .. } => unreachable!("find_follow resolves symlinks"),
+        }
+    }
+
+    // runs the file's content through the ops in turn and returns what the
+    // last one produces; lines keep their newlines
+    fn pipe(&self, path_name: &str, ops: &[PipeOp]) -> Result<String, &'static str> {
+        let mut text = self.cat(path_name)?.to_string();
+        for op in ops {
+            text = match op {
+                PipeOp::Grep(pattern) => text
+                    .split_inclusive('\n')
+                    .filter(|line| line.contains(pattern.as_str()))
+                    .collect(),
+                PipeOp::Head(n) => text.split_inclusive('\n').take(*n).collect(),
+                PipeOp::Tail(n) => {
+                    let lines = text.split_inclusive('\n').collect::<Vec<&str>>();
+                    lines[lines.len().saturating_sub(*n)..].concat()
+                }
+                PipeOp::Wc => {
+                    let [lines, words, bytes] = word_count(&text);
+                    format!("{} {} {}\n", lines, words, bytes)
+                }
+            };
+        }
+        Ok(text)
+    }
+
+    // replaces the file's content, creating the file if it does not exist
+    fn write(&mut self, path_name: &str, text: &str) -> FsResult {
+        let target_id = self.find_or_creat(path_name)?;
+        self.set_content(target_id, text.to_string())
+    }
+
+    // creat, then gives the new file its content; an existing file is left
+    // alone like with creat
+    fn creat_with_content(&mut self, path_name: &str, text: String) -> FsResult {
+        self.creat(path_name)?;
+        // creat hands the new file the latest id
+        self.set_content(self.counter, text)
+    }
+
+    // adds text to the end of a file, creating it if missing
+    fn append(&mut self, path_name: &str, text: &str) -> FsResult {
+        let target_id = self.find_or_creat(path_name)?;
+        let content = match &self.nodes.get(&target_id).unwrap().node_type {
+            NodeType::FILE { content } => format!("{}{}", content, text),
+            NodeType::DIR { .. } | NodeType::SYMLINK { .. } => text.to_string(),
+        };
+        self.set_content(target_id, content)
+    }
+
+    // id of the node at path_name with symlinks followed, creating an empty
+    // file there if nothing exists yet
+    fn find_or_creat(&mut self, path_name: &str) -> Result<usize, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        match self.find_follow(start_id, &path) {
+            Ok(target_id) => Ok(target_id),
+            Err(_) => {
+                // creat hands the new file the latest counter value
+                self.creat(path_name)?;
+                Ok(self.counter)
+            }
+        }
+    }
+
+    // sets a file's content; id must already have its symlinks followed
+    fn set_content(&mut self, id: usize, text: String) -> FsResult {
+        self.check_writable(id)?;
+        let node = self.nodes.get_mut(&id).unwrap();
+        match &mut node.node_type {
+            NodeType::FILE { content } => {
+                *content = text;
+                let now = self.clock.now();
+                node.mtime = now;
+                node.ctime = now;
+                Ok(())
+            }
+            NodeType::DIR { .. } => Err("Is a directory"),
+            NodeType::SYMLINK { .. } => unreachable!("set_content is given followed ids"),
+        }
+    }
+
+    // overwrites an existing file's content with another file's content
+    fn cpcontent(&mut self, src_name: &str, dest_name: &str) -> FsResult {
+        let text = self.cat(src_name)?.to_string();
+        let (absolute, path) = split_path(dest_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find_follow(start_id, &path)?;
+        self.set_content(target_id, text)
+    }
+
+    // sets the mode of the node at path_name, following symlinks
+    fn chmod(&mut self, mode: &ModeSpec, path_name: &str) -> FsResult {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find_follow(start_id, &path)?;
+        self.chmod_node(target_id, mode)
+    }
+
+    fn chmod_node(&mut self, id: usize, mode: &ModeSpec) -> FsResult {
+        self.check_writable(id)?;
+        let now = self.clock.now();
+        let node = self.nodes.get_mut(&id).unwrap();
+        node.mode = mode.apply(node.mode);
+        node.ctime = now;
+        Ok(())
+    }
+
+    fn chown_node(&mut self, id: usize, uid: u32) -> FsResult {
+        self.check_writable(id)?;
+        let now = self.clock.now();
+        let node = self.nodes.get_mut(&id).unwrap();
+        node.uid = uid;
+        node.ctime = now;
+        Ok(())
+    }
+
+    // sets the access and/or modification times to now, creating an empty
+    // file when the path doesn't exist unless no_create is set
+    fn touch(&mut self, path_name: &str, options: TouchOptions) -> FsResult {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        match self.find_follow(start_id, &path) {
+            Ok(target_id) => {
+                self.check_writable(target_id)?;
+                let now = self.clock.now();
+                let node = self.nodes.get_mut(&target_id).unwrap();
+                // neither -a nor -m means both
+                let both = !options.access && !options.modify;
+                if options.access || both {
+                    node.atime = now;
+                }
+                if options.modify || both {
+                    node.mtime = now;
+                }
+                node.ctime = now;
+                Ok(())
+            }
+            Err(_) if options.no_create => Ok(()),
+            Err(_) => self.creat(path_name),
+        }
+    }
+
+    // evaluates a `test` predicate on the node at path_name, with symlinks
+    // followed; a missing node fails every predicate
+    fn test(&self, predicate: TestPredicate, path_name: &str) -> bool {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        match self.find_follow(start_id, &path) {
+            Ok(id) => {
+                let node = self.nodes.get(&id).unwrap();
+                match predicate {
+                    TestPredicate::Exists => true,
+                    TestPredicate::File => node.is_file_node(),
+                    TestPredicate::Dir => node.is_dir_node(),
+                    TestPredicate::NonEmpty => node.size() > 0,
+                }
+            }
+            Err(_) => false,
+        }
+    }
+
+    // a coarse guess at what the node holds, like file(1)
+    fn file_kind(&self, path_name: &str) -> Result<String, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let id = self.find(start_id, &path)?;
+        Ok(match &self.nodes.get(&id).unwrap().node_type {
+            NodeType::DIR { .. } => "directory".to_string(),
+            NodeType::SYMLINK { target } => format!("symbolic link to {}", target),
+            NodeType::FILE { content } if content.is_empty() => "empty".to_string(),
+            NodeType::FILE { content }
+                if content
+                    .bytes()
+                    .all(|b| b.is_ascii_graphic() || b" \t\n\r".contains(&b)) =>
+            {
+                "ASCII text".to_string()
+            }
+            NodeType::FILE { .. } => "data".to_string(),
+        })
+    }
+
+    // modification time of the node at path_name, with symlinks followed
+    fn mtime(&self, path_name: &str) -> Result<SystemTime, &'static str> {
+        let (absolute, path) = split_path(path_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find_follow(start_id, &path)?;
+        Ok(self.nodes.get(&target_id).unwrap().mtime)
+    }
+
+    // drops every node except the root, leaving the filesystem as new
+    fn purge(&mut self) {
+        let verbose = self.verbose;
+        let max_nodes = self.max_nodes;
+        let max_depth = self.max_depth;
+        let clock = self.clock;
+        let block_size = self.block_size;
+        let umask = self.umask;
+        let prompt = std::mem::take(&mut self.prompt);
+        let capacity = self.path_cache.borrow().capacity;
+        *self = FileSystem::new();
+        self.path_cache.borrow_mut().capacity = capacity;
+        self.prompt = prompt;
+        self.verbose = verbose;
+        self.max_nodes = max_nodes;
+        self.max_depth = max_depth;
+        self.clock = clock;
+        self.block_size = block_size;
+        self.umask = umask;
+        self.nodes
+            .insert(0, FsNode::new_dir_node("/", 0, clock.now()));
+    }
+
+    // backups open with a "FSBACKUP v2" line, then the "counter total" line
+    // that v1 backups start with; everything after it is the same in both.
+    // They hold the structure only: modes and timestamps are not saved, and
+    // reloaded nodes get the reload time. Saving a subtree renumbers its nodes
+    // from 0, so the backup reloads as a standalone tree. File content is only
+    // kept with_content, as a "C id length" line per non-empty file followed
+    // by that many bytes and a newline; readers that stop after the node lines
+    // still load such a backup, without the content.
+    fn save(
+        &self,
+        maybe_filepath: Option<String>,
+        maybe_root: Option<String>,
+        with_content: bool,
+    ) -> FsResult {
+        // (id, id in the backup) pairs in backup order, which all three blocks
+        // follow. Only a subtree save renumbers, so only it needs the map.
+        let (counter, order, renumbered) = match maybe_root {
+            Some(root_name) => {
+                let (absolute, path) = split_path(&root_name);
+                let start_id = if absolute { 0 } else { self.cwd };
+                let root_id = self.find_follow(start_id, &path)?;
+                if !self.nodes.get(&root_id).unwrap().is_dir_node() {
+                    return Err("Not a directory");
+                }
+                let order = self
+                    .walk(root_id, None)
+                    .enumerate()
+                    .map(|(new_id, (id, _))| (id, new_id))
+                    .collect::<Vec<(usize, usize)>>();
+                let renumbered = order.iter().copied().collect::<HashMap<usize, usize>>();
+                (order.len() - 1, order, Some(renumbered))
+            }
+            None => {
+                let mut ids = self.nodes.keys().copied().collect::<Vec<usize>>();
+                ids.sort_unstable();
+                let order = ids.into_iter().map(|id| (id, id)).collect();
+                (self.counter, order, None)
+            }
+        };
+        self.write_backup(
+            maybe_filepath,
+            counter,
+            &order,
+            renumbered.as_ref(),
+            with_content,
+        )
+    }
+
+    // writes the nodes of order, (id, id in the backup) pairs, as a backup.
+    // With renumbered, the backup holds only the nodes in it, and directory
+    // entries outside it are left out.
+    fn write_backup(
+        &self,
+        maybe_filepath: Option<String>,
+        counter: usize,
+        order: &[(usize, usize)],
+        renumbered: Option<&HashMap<usize, usize>>,
+        with_content: bool,
+    ) -> FsResult {
+        let backup_id = |id: &usize| renumbered.map_or(Some(*id), |ids| ids.get(id).copied());
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(maybe_filepath.unwrap_or_else(|| "backup.fs".to_string()))
+            .map_err(|_| "Error opening the backup file")?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}{}", BACKUP_MAGIC, BACKUP_VERSION)
+            .map_err(|_| "error writing to backup file")?;
+        writeln!(writer, "{} {}", counter, order.len())
+            .map_err(|_| "error writing to backup file")?;
+        // writeln!(writer, "{}", self.nodes.len()).map_err(|_| "error writing to backup file")?;
+        for (old_id, id) in order.iter() {
+            let name = if *id == 0 {
+                "/"
+            } else {
+                &self.nodes.get(old_id).unwrap().name
+            };
+            writeln!(writer, "{} {}", id, name).map_err(|_| "error writing to backup file")?;
+        }
+        for (old_id, id) in order.iter() {
+            let node = self.nodes.get(old_id).unwrap();
+            let parent = if *id == 0 {
+                0
+            } else {
+                backup_id(&node.parent).unwrap()
+            };
+            match &node.node_type {
+                NodeType::DIR { children } => writeln!(
+                    writer,
+                    "D {} {} {}",
+                    id,
+                    parent,
+                    children
+                        .values()
+                        .filter_map(|idx| backup_id(idx).map(|idx| idx.to_string()))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                ),
+                NodeType::FILE { .. } => writeln!(writer, "F {} {}", id, parent),
+                NodeType::SYMLINK { target } => writeln!(writer, "S {} {} {}", id, parent, target),
+            }
+            .map_err(|_| "Error writing to file")?;
+        }
+        if with_content {
+            for (old_id, id) in order.iter() {
+                match &self.nodes.get(old_id).unwrap().node_type {
+                    NodeType::FILE { content } if !content.is_empty() => {
+                        writeln!(writer, "C {} {}\n{}", id, content.len(), content)
+                            .map_err(|_| "Error writing to file")?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // saves the files below the directory start_name that match the query,
+    // with their content and the directories leading to them, as a backup
+    // rooted at start_name that reload or import can load. Returns how many
+    // files went in.
+    fn archive(
+        &self,
+        start_name: &str,
+        query: &FindQuery,
+        filepath: &str,
+    ) -> Result<usize, &'static str> {
+        let (absolute, path) = split_path(start_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let start_id = self.find_follow(start_id, &path)?;
+        if !self.nodes.get(&start_id).unwrap().is_dir_node() {
+            return Err("Not a directory");
+        }
+        let files = self
+            .search_ids(start_name, query)?
+            .into_iter()
+            .filter(|id| matches!(self.nodes[id].node_type, NodeType::FILE { .. }))
+            .collect::<Vec<usize>>();
+        let mut kept = HashSet::new();
+        kept.insert(start_id);
+        for &file_id in &files {
+            let mut id = file_id;
+            while kept.insert(id) {
+                id = self.nodes[&id].parent;
+            }
+        }
+        let order = self
+            .walk(start_id, None)
+            .map(|(id, _)| id)
+            .filter(|id| kept.contains(id))
+            .enumerate()
+            .map(|(new_id, id)| (id, new_id))
+            .collect::<Vec<(usize, usize)>>();
+        let renumbered = order.iter().copied().collect::<HashMap<usize, usize>>();
+        self.write_backup(
+            Some(filepath.to_string()),
+            order.len() - 1,
+            &order,
+            Some(&renumbered),
+            true,
+        )?;
+        Ok(files.len())
+    }
+
+    fn reload(&mut self, maybe_filepath: Option<String>) -> Result<NodeCounts, &'static str> {
+        let (version, counter, nodes) = FileSystem::read_backup(maybe_filepath, self.clock.now())?;
+        self.nodes = nodes;
+        self.reindex_names();
+        self.invalidate_paths();
+        self.read_only.clear();
+        self.cwd = 0;
+        self.counter = counter;
+        self.cd_history.clear();
+        self.undo_history.clear();
+        self.logical_cwd = None;
+        let mut counts = NodeCounts {
+            version,
+            ..NodeCounts::default()
+        };
+        for node in self.nodes.values() {
+            match node.node_type {
+                NodeType::DIR { .. } => counts.directories += 1,
+                NodeType::FILE { .. } => counts.files += 1,
+                NodeType::SYMLINK { .. } => counts.symlinks += 1,
+            }
+        }
+        Ok(counts)
+    }
+
+    // grafts the entries of a backup's root into the target directory by
+    // loading it as an instance of its own and transferring them. Name
+    // collisions fail before anything is added, or are left alone with
+    // Overwrite::Skip.
+    fn reload_merge(
+        &mut self,
+        filepath: &str,
+        target_name: Option<&str>,
+        policy: Overwrite,
+    ) -> FsResult {
+        let target_name = target_name.unwrap_or(".");
+        let (absolute, path) = split_path(target_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let target_id = self.find_follow(start_id, &path)?;
+        self.check_writable(target_id)?;
+        let existing = match &self.nodes.get(&target_id).unwrap().node_type {
+            NodeType::DIR { children } => children,
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => return Err("Not a directory"),
+        };
+        let mut backup = FileSystem::new();
+        (_, backup.counter, backup.nodes) =
+            FileSystem::read_backup(Some(filepath.to_string()), self.clock.now())?;
+        let incoming = &backup.nodes;
+        let mut entries = match &incoming[&0].node_type {
+            NodeType::DIR { children } => children
+                .iter()
+                .map(|(name, id)| (name.clone(), *id))
+                .collect::<Vec<_>>(),
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => unreachable!(),
+        };
+        if let Overwrite::Skip = policy {
+            entries.retain(|(name, _)| !existing.contains_key(name));
+        } else if entries.iter().any(|(name, _)| existing.contains_key(name)) {
+            return Err("File exists");
+        }
+
+        let target_depth = self.depth_of(target_id);
+        let mut pending = entries
+            .iter()
+            .map(|(_, id)| (*id, target_depth + 1))
+            .collect::<Vec<(usize, usize)>>();
+        let mut needed = 0;
+        while let Some((id, depth)) = pending.pop() {
+            if depth > self.max_depth {
+                return Err("maximum directory depth exceeded");
+            }
+            needed += 1;
+            if let NodeType::DIR { children } = &incoming[&id].node_type {
+                pending.extend(children.values().map(|child_id| (*child_id, depth + 1)));
+            }
+        }
+        self.check_free_nodes(needed)?;
+        for (name, id) in entries {
+            self.copy_tree(Some(&backup), id, target_id, &name, false);
+        }
+        Ok(())
+    }
+
+    // copies one path out of a backup into the tree, dest_name working as
+    // for transfer; returns the number of nodes copied
+    fn import(
+        &mut self,
+        filepath: &str,
+        src_name: &str,
+        dest_name: &str,
+    ) -> Result<usize, &'static str> {
+        let mut backup = FileSystem::new();
+        (_, backup.counter, backup.nodes) =
+            FileSystem::read_backup(Some(filepath.to_string()), self.clock.now())?;
+        backup.transfer(src_name, self, dest_name)
+    }
+
+    // grafts a backup into the empty directory mount_name, like reload
+    // --merge, and makes everything below it read-only
+    fn overlay(&mut self, filepath: &str, mount_name: &str) -> FsResult {
+        let (absolute, path) = split_path(mount_name);
+        let start_id = if absolute { 0 } else { self.cwd };
+        let mount_id = self.find_follow(start_id, &path)?;
+        match &self.nodes.get(&mount_id).unwrap().node_type {
+            NodeType::DIR { children } if children.is_empty() => {}
+            NodeType::DIR { .. } => return Err("Directory not empty"),
+            NodeType::FILE { .. } | NodeType::SYMLINK { .. } => return Err("Not a directory"),
+        }
+        self.reload_merge(filepath, Some(mount_name), Overwrite::Refuse)?;
+        self.read_only.push(mount_id);
+        Ok(())
+    }
+
+    // fails for nodes at or below a read-only overlay
+    fn check_writable(&self, id: usize) -> FsResult {
+        if self
+            .read_only
+            .iter()
+            .any(|mount_id| self.is_ancestor(*mount_id, id))
+        {
+            Err("Read-only file system")
+        } else {
+            Ok(())
+        }
+    }
+
+    // parses a backup file into its format version, its counter and the
+    // nodes reachable from its root. The file is read in one go and parsed
+    // in two passes over it, the index naming the ids and the node lines then
+    // linking them up; names are borrowed from the buffer until a node takes
+    // its own copy.
+    fn read_backup(
+        maybe_filepath: Option<String>,
+        now: SystemTime,
+    ) -> Result<(&'static str, usize, HashMap<usize, FsNode>), &'static str> {
+        let mut file = File::open(maybe_filepath.unwrap_or_else(|| "backup.fs".to_string()))
+            .map_err(|_| "Error opening the backup file")?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .map_err(|_| "Error reading the backup file")?;
+        let mut reader = BackupReader {
+            bytes: &bytes,
+            pos: 0,
+        };
+
+        let mut line = reader.next_line()?.unwrap_or("");
+        // v1 backups start right away with the counter line
+        let mut version = "v1";
+        if let Some(magic_version) = line.strip_prefix(BACKUP_MAGIC) {
+            if magic_version.trim() != BACKUP_VERSION {
+                return Err("Error parsing the backup: unsupported format version");
+            }
+            version = BACKUP_VERSION;
+            line = reader.next_line()?.unwrap_or("");
+        } else if !line.trim_start().starts_with(|c: char| c.is_ascii_digit()) {
+            return Err("Error parsing the backup: not a backup file");
+        }
+        let mut fields = line.trim().split(' ');
+        let (counter, total_nodes) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(counter_str), Some(total_nodes_str), None) => (
+                counter_str
+                    .parse::<usize>()
+                    .map_err(|_| "Error parsing the backup: error reading counter")?,
+                total_nodes_str
+                    .parse::<usize>()
+                    .map_err(|_| "Error parsing the backup: error reading total_nodes")?,
+            ),
+            _ => return Err("Error parsing the backup: not two number on first line"),
+        };
+        // every node takes at least a byte, so a bogus total cannot make us
+        // reserve more than the file could describe
+        let capacity = total_nodes.min(bytes.len());
+
+        let mut index: HashMap<usize, &str> = HashMap::with_capacity(capacity);
+        for _ in 0..total_nodes {
+            let line = reader.next_line()?.unwrap_or("");
+            // names may end in spaces, so only a CRLF ending is stripped
+            if let Some((id_str, name)) = line.trim_end_matches('\r').split_once(' ') {
+                let id = id_str
+                    .parse::<usize>()
+                    .map_err(|_| "Error parsing the backup: not two numbers for index")?;
+                if id != 0 && !is_valid_name(name) {
+                    return Err("Error parsing the backup: invalid name in index");
+                }
+                if index.insert(id, name).is_some() {
+                    return Err("Error parsing the backup: duplicate id in index");
+                }
+            } else {
+                return Err("Error parsing the backup: malformed index line");
+            }
+        }
+
+        let parse_id = |id_str: &str| {
+            id_str
+                .parse::<usize>()
+                .map_err(|_| "Error parsing the backup: not two numbers for index")
+        };
+        let mut nodes = HashMap::with_capacity(capacity);
+        for _ in 0..total_nodes {
+            let line = reader.next_line()?.unwrap_or("");
+            let mut fields = line.trim().split(' ');
+            let (id, node) = match (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) {
+                (Some("D"), Some(id_str), Some(parent_id_str), children_str, None) => {
+                    let id = parse_id(id_str)?;
+                    let name = index.get(&id).ok_or("Error rebuilding the backup")?;
+                    let parent = parse_id(parent_id_str)?;
+                    let mut node = FsNode::new_dir_node(name, parent, now);
+                    if let Some(children_str) = children_str {
+                        let children = children_str
+                            .split(',')
+                            .map(|s| {
+                                let child_id = s.parse::<usize>().ok()?;
+                                index
+                                    .get(&child_id)
+                                    .map(|name| (name.to_string(), child_id))
+                            })
+                            .collect::<Option<HashMap<String, usize>>>()
+                            .ok_or("Error parsing the backup: not two numbers for index")?;
+                        node.node_type = NodeType::DIR { children };
+                    }
+                    (id, node)
+                }
+                (Some("F"), Some(id_str), Some(parent_id_str), None, None) => {
+                    let id = parse_id(id_str)?;
+                    let name = index.get(&id).ok_or("Error rebuilding the backup")?;
+                    let parent = parse_id(parent_id_str)?;
+                    (id, FsNode::new_file_node(name, parent, now))
+                }
+                _ => match line
+                    .trim_end_matches('\r')
+                    .splitn(4, ' ')
+                    .collect::<Vec<&str>>()
+                    .as_slice()
+                {
+                    ["S", id_str, parent_id_str, target] => {
+                        let id = parse_id(id_str)?;
+                        let name = index.get(&id).ok_or("Error rebuilding the backup")?;
+                        let mut node = FsNode::new_file_node(name, parse_id(parent_id_str)?, now);
+                        node.node_type = NodeType::SYMLINK {
+                            target: target.to_string(),
+                        };
+                        node.mode = 0o777;
+                        (id, node)
+                    }
+                    _ => return Err("Error rebuilding the backup"),
+                },
+            };
+            if nodes.insert(id, node).is_some() {
+                return Err("Error rebuilding the backup: duplicate node");
+            }
+        }
+
+        while let Some(line) = reader.next_line()? {
+            let mut fields = line.trim().split(' ');
+            if let (Some("C"), Some(id_str), Some(length_str), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            {
+                let id = id_str
+                    .parse::<usize>()
+                    .map_err(|_| "Error parsing the backup: malformed content line")?;
+                let length = length_str
+                    .parse::<usize>()
+                    .map_err(|_| "Error parsing the backup: malformed content line")?;
+                let content = reader
+                    .take(length + 1)
+                    .ok_or("Error parsing the backup: truncated content")?;
+                let (newline, content) = content.split_last().unwrap();
+                if *newline != b'\n' {
+                    return Err("Error parsing the backup: truncated content");
+                }
+                let text = String::from_utf8(content.to_vec())
+                    .map_err(|_| "Error parsing the backup: content is not UTF-8")?;
+                match nodes
+                    .get_mut(&id)
+                    .map(|node: &mut FsNode| &mut node.node_type)
+                {
+                    Some(NodeType::FILE { content }) => *content = text,
+                    _ => return Err("Error rebuilding the backup: content for a non-file"),
+                }
+            } else if !line.trim().is_empty() {
+                return Err("Error rebuilding the backup");
+            }
+        }
+
+        let nodes = reachable_nodes(nodes)?;
+        let counter = nodes.keys().copied().fold(counter, usize::max);
+        Ok((version, counter, nodes))
+    }
+}
+
+// a cursor over a backup file read into memory
+struct BackupReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BackupReader<'a> {
+    // the next line without its '\n', or None at the end of the file
+    fn next_line(&mut self) -> Result<Option<&'a str>, &'static str> {
+        if self.pos == self.bytes.len() {
+            return Ok(None);
+        }
+        let rest = &self.bytes[self.pos..];
+        let end = rest.iter().position(|byte| *byte == b'\n');
+        let line = &rest[..end.unwrap_or(rest.len())];
+        self.pos += end.map_or(rest.len(), |end| end + 1);
+        std::str::from_utf8(line)
+            .map(Some)
+            .map_err(|_| "Error reading the backup file")
+    }
+
+    // the next length bytes, or None if the file ends first
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(length)?;
+        let bytes = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+}
+
+// keeps the nodes reachable from the root, checking that every directory
+// entry points at a node whose name and parent agree with it. Backups written
+// before rm dropped removed nodes still carry those orphans, which are skipped.
+fn reachable_nodes(
+    mut nodes: HashMap<usize, FsNode>,
+) -> Result<HashMap<usize, FsNode>, &'static str> {
+    match nodes.get(&0) {
+        // path_of and the walks stop at id 0, which must be its own parent
+        Some(root) if root.is_dir_node() && root.parent == 0 => {}
+        Some(root) if root.is_dir_node() => {
+            return Err("Error rebuilding the backup: root directory has a parent")
+        }
+        _ => return Err("Error rebuilding the backup: missing root directory"),
+    }
+    let mut reachable = HashMap::new();
+    let mut stack = vec![0];
+    while let Some(id) = stack.pop() {
+        let node = nodes
+            .remove(&id)
+            .ok_or("Error rebuilding the backup: node listed twice")?;
+        if let NodeType::DIR { children } = &node.node_type {
+            for (name, child_id) in children {
+                let child = nodes
+                    .get(child_id)
+                    .ok_or("Error rebuilding the backup: dangling directory entry")?;
+                if child.parent != id || &child.name != name {
+                    return Err("Error rebuilding the backup: inconsistent parent link");
+                }
+                stack.push(*child_id);
+            }
+        }
+        reachable.insert(id, node);
+    }
+    Ok(reachable)
+}
+
+// a single directory entry name: not empty, not "." or "..", and no '/'.
+// Backups keep one name per line, so line breaks are refused as well.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\n', '\r'])
+}
+
+// a symlink target must also fit on its backup line
+fn check_target(target: &str) -> FsResult {
+    if target.is_empty() {
+        Err("empty symlink target")
+    } else if target.contains(['\n', '\r']) {
+        Err("invalid symlink target")
+    } else {
+        Ok(())
+    }
+}
+
+// splits a path into its components, dropping empty ones, and tells whether
+// it was absolute
+fn split_path(path_name: &str) -> (bool, Vec<&str>) {
+    let components = path_name
+        .split('/')
+        .filter(|name| !name.is_empty())
+        .collect();
+    (path_name.starts_with('/'), components)
+}
+
+// the line, word and byte counts of text, as wc prints them
+fn word_count(text: &str) -> [usize; 3] {
+    [
+        text.lines().count(),
+        text.split_whitespace().count(),
+        text.len(),
+    ]
+}
+
+// quotes text as a JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// seconds since the epoch, with nanoseconds
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    )
+}
+
+// the lines of old and new as a whole-file unified-style diff: common lines
+// start with ' ', lines only in old with '-' and lines only in new with '+',
+// from a longest common subsequence of the lines
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old = old.lines().collect::<Vec<&str>>();
+    let new = new.lines().collect::<Vec<&str>>();
+    // lcs[i][j]: length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!(" {}", old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+// formats a byte count the way ls -h and du -h show it: plain below 1024,
+// then in K, M, G, ... rounded up, with one decimal while under 10
+fn human_size(bytes: usize) -> String {
+    const UNITS: [char; 6] = ['K', 'M', 'G', 'T', 'P', 'E'];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let bytes = bytes as u128;
+    let mut unit = 0;
+    let mut scale = 1024u128;
+    while bytes >= scale * 1024 && unit + 1 < UNITS.len() {
+        unit += 1;
+        scale *= 1024;
+    }
+    let tenths = (bytes * 10).div_ceil(scale);
+    let whole = bytes.div_ceil(scale);
+    if tenths < 100 {
+        format!("{}.{}{}", tenths / 10, tenths % 10, UNITS[unit])
+    } else if whole >= 1024 && unit + 1 < UNITS.len() {
+        // rounding up carried into the next unit
+        format!("1.0{}", UNITS[unit + 1])
+    } else {
+        format!("{}{}", whole, UNITS[unit])
+    }
+}
+
+// matches name against a shell-style pattern where `*` matches any run of
+// characters and `?` matches exactly one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` seen and of the name character it resumed at
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// lexically resolves `.`, `..` and repeated slashes in path against cwd,
+// producing an absolute path without consulting the tree
+fn normalize_path(path: &str, cwd: &str) -> String {
+    let (absolute, path) = split_path(path);
+    let mut components: Vec<&str> = if absolute {
+        Vec::new()
+    } else {
+        split_path(cwd).1
+    };
+    for name in path {
+        match name {
+            "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(name),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct LsOptions {
+    // -F: mark directories with a trailing '/' and symlinks with '@'
+    classify: bool,
+    // -i: prefix each entry with its node id
+    inode: bool,
+    // -s: prefix each entry with its allocated blocks, after any id
+    blocks: bool,
+    // -h: show -s sizes as allocated bytes in human_size form
+    human: bool,
+    // --group-directories-first: list directories before everything else
+    group_directories: bool,
+    // --paginate: on a terminal, stop after each screenful until Enter
+    paginate: bool,
+    // -t or -S, whichever comes last
+    sort: LsSort,
+    // -r: reverse the order of the sort
+    reverse: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+enum LsSort {
+    #[default]
+    Name,
+    // -t: newest modification first
+    Time,
+    // -S: largest first
+    Size,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TreeOptions {
+    // -L: levels shown below the start
+    level: Option<usize>,
+    // --du: prefix files with their size and directories with their total
+    du: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TouchOptions {
+    // -c: don't create missing files
+    no_create: bool,
+    // -a: set the access time
+    access: bool,
+    // -m: set the modification time
+    modify: bool,
+}
+
+#[derive(Debug, Default)]
+struct DuOptions {
+    // --exclude PATTERN, any number of times
+    exclude: Vec<String>,
+    // --blocks: add up allocated blocks instead of bytes
+    blocks: bool,
+    // -h: print totals in human_size form, blocks as the bytes they hold
+    human: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct GrepOptions {
+    // -r: search every file below the path
+    recursive: bool,
+    // -n forces line numbers on and -N off; by default only -r shows them
+    line_numbers: Option<bool>,
+    // -l: print the path of each matching file instead of its lines; implies -r
+    files_with_matches: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TestPredicate {
+    // -e
+    Exists,
+    // -f
+    File,
+    // -d
+    Dir,
+    // -s: size greater than zero
+    NonEmpty,
+}
+
+enum Setting {
+    Verbose(bool),
+    MaxNodes(Option<usize>),
+    MaxDepth(usize),
+    Prompt(String),
+    // 0 for off
+    PathCache(usize),
+    Clock(Clock),
+    BlockSize(usize),
+}
+
+// the mode operand of chmod
+#[derive(Debug)]
+enum ModeSpec {
+    Octal(u32),
+    // the comma-separated clauses of e.g. u+x,g-w,o=r, applied in order
+    Symbolic(Vec<ModeClause>),
+}
+
+#[derive(Debug)]
+struct ModeClause {
+    // the permission bits of the classes named, e.g. 0o700 for u
+    classes: u32,
+    op: ModeOp,
+    // r, w and x as 4, 2 and 1
+    perms: u32,
+}
+
+#[derive(Debug)]
+enum ModeOp {
+    Add,
+    Remove,
+    Set,
+}
+
+impl ModeSpec {
+    fn apply(&self, mode: u32) -> u32 {
+        match self {
+            ModeSpec::Octal(mode) => *mode,
+            ModeSpec::Symbolic(clauses) => clauses.iter().fold(mode, |mode, clause| {
+                // repeat rwx into each class, then keep the named ones
+                let bits = (clause.perms * 0o111) & clause.classes;
+                match clause.op {
+                    ModeOp::Add => mode | bits,
+                    ModeOp::Remove => mode & !bits,
+                    ModeOp::Set => (mode & !clause.classes) | bits,
+                }
+            }),
+        }
+    }
+}
+
+// one stage of a `cat path | ...` pipeline, see FileSystem::pipe
+#[derive(Debug)]
+enum PipeOp {
+    // the lines containing the pattern
+    Grep(String),
+    // the first n lines
+    Head(usize),
+    // the last n lines
+    Tail(usize),
+    // the line, word and byte counts
+    Wc,
+}
+
+// where echo sends its text instead of printing it
+enum Redirect {
+    // > path
+    Write(String),
+    // >> path
+    Append(String),
+}
+
+enum StatOutput {
+    Full,
+    // -c: format followed by a newline
+    Format(String),
+    // --printf: format with backslash escapes and no trailing newline
+    Printf(String),
+    // --json: the metadata as a JSON object
+    Json,
+}
+
+enum Command {
+    Pwd,
+    Quit,
+    // path, mode, -p
+    MkDir(String, Option<u32>, bool),
+    // path, initial content
+    Creat(String, Option<String>),
+    RmDir(String),
+    // the bool is set by -r
+    Rm(String, bool),
+    // target, link name, -f
+    Ln(String, String, bool),
+    Stat(String, StatOutput),
+    // paths, --sep
+    Cat(Vec<String>, String),
+    Tac(String),
+    Diff(String, String),
+    // cat path | op | ...
+    Pipe(String, Vec<PipeOp>),
+    Wc(Vec<String>),
+    File(String),
+    // cat > file <<delimiter: the file's content follows, up to the delimiter line
+    HereDoc(String, String),
+    Newer(String, String),
+    // test / [ ... ]
+    Test(TestPredicate, String),
+    // runs the commands in a file on the host
+    Source(String),
+    // seconds between runs, the command line to run
+    Watch(u64, String),
+    Write(String, String),
+    Echo(String, Option<Redirect>),
+    CpContent(String, String),
+    Touch(String, TouchOptions),
+    Chmod(ModeSpec, String),
+    Find(String, FindQuery),
+    // start, query, archive file
+    Archive(String, FindQuery, String),
+    Locate(String),
+    // start, --relative
+    LsPaths(String, bool),
+    Du(String, DuOptions),
+    Tree(String, TreeOptions),
+    // dir, breadth, depth
+    GenTree(String, usize, usize),
+    Complete(String),
+    // pattern, path
+    Grep(String, String, GrepOptions),
+    Cp(String, String, CopyOptions),
+    Mv(String, String, CopyOptions),
+    Flatten(String),
+    Undo,
+    Ls(Option<String>, LsOptions),
+    // the bool selects physical (-P) over logical (-L) resolution
+    Cd(Option<String>, bool),
+    CdBack(usize),
+    // cd --create / mkcd
+    CdCreate(String),
+    // file, subtree, --content
+    Save(Option<String>, Option<String>, bool),
+    Reload(Option<String>),
+    ReloadMerge(String, Option<String>, Overwrite),
+    // backup, mount point
+    Overlay(String, String),
+    // backup, path in the backup, destination
+    Import(String, String, String),
+    // -f, which skips the confirmation
+    Purge(bool),
+    Set(Setting),
+    // df -i
+    DfInodes,
+    PathCacheStats,
+    // internal state for debugging; not meant as a user command
+    Info,
+    // umask [MODE]: prints the mask without a mode
+    Umask(Option<u32>),
+    NoOp,
+}
+
+// parses an octal permission mode such as 755 or 0700
+fn parse_mode(mode: &str) -> Result<u32, &'static str> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or("invalid mode")
+}
+
+// parses an octal mode, or a symbolic one: comma-separated clauses of
+// classes from ugoa (none meaning a), one of + - =, and perms from rwx
+fn parse_mode_spec(spec: &str) -> Result<ModeSpec, &'static str> {
+    if spec.bytes().all(|byte| byte.is_ascii_digit()) {
+        return parse_mode(spec).map(ModeSpec::Octal);
+    }
+    let mut clauses = Vec::new();
+    for clause in spec.split(',') {
+        let op_at = clause.find(&['+', '-', '='][..]).ok_or("invalid mode")?;
+        let (who, rest) = clause.split_at(op_at);
+        let mut classes = 0;
+        for class in who.chars() {
+            classes |= match class {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return Err("invalid mode"),
+            };
+        }
+        if classes == 0 {
+            classes = 0o777;
+        }
+        let op = match &rest[..1] {
+            "+" => ModeOp::Add,
+            "-" => ModeOp::Remove,
+            _ => ModeOp::Set,
+        };
+        let mut perms = 0;
+        for perm in rest[1..].chars() {
+            perms |= match perm {
+                'r' => 4,
+                'w' => 2,
+                'x' => 1,
+                _ => return Err("invalid mode"),
+            };
+        }
+        clauses.push(ModeClause { classes, op, perms });
+    }
+    Ok(ModeSpec::Symbolic(clauses))
+}
+
+// parses the `[-n|-f] [-b] [-u] [-r] [--progress] [--parents] <source>
+// <destination>` operands shared by cp and mv
+fn parse_copy_args<'a>(
+    args: impl Iterator<Item = &'a str>,
+) -> Result<(String, String, CopyOptions), &'static str> {
+    let mut options = CopyOptions::default();
+    let mut operands = Vec::new();
+    for arg in args.filter(|arg| !arg.is_empty()) {
+        match arg {
+            "-n" => options.overwrite = Overwrite::Skip,
+            "-f" => options.overwrite = Overwrite::Force,
+            "-r" | "-R" => options.recursive = true,
+            "--progress" => options.progress = true,
+            "--parents" => options.parents = true,
+            "-b" => options.backup = true,
+            "-u" | "--update" => options.update = true,
+            _ if arg.starts_with('-') => return Err("invalid option"),
+            _ => operands.push(arg.to_string()),
+        }
+    }
+    if let [src, dest] = operands.as_slice() {
+        Ok((src.clone(), dest.clone(), options))
+    } else if operands.len() < 2 {
+        Err("missing operand")
+    } else {
+        Err("extra operand")
+    }
+}
+
+fn parse_depth(arg: Option<&str>) -> Result<usize, &'static str> {
+    arg.ok_or("missing depth")?
+        .parse::<usize>()
+        .map_err(|_| "invalid depth")
+}
+
+// parses a -size argument: an optional + or -, a count, and an optional
+// unit of c (bytes, the default), k or K, M or G
+fn parse_size_test(arg: &str) -> Result<SizeTest, &'static str> {
+    let (test, count): (fn(usize) -> SizeTest, &str) = match arg.strip_prefix('+') {
+        Some(count) => (SizeTest::Greater, count),
+        None => match arg.strip_prefix('-') {
+            Some(count) => (SizeTest::Less, count),
+            None => (SizeTest::Exact, arg),
+        },
+    };
+    let (digits, unit) = match count.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => (&count[..i], unit),
+        _ => (count, 'c'),
+    };
+    let unit = match unit {
+        'c' => 1,
+        'k' | 'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err("invalid -size unit"),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .map(test)
+        .ok_or("invalid argument to -size")
+}
+
+// parses `[start] [-name <pattern>] [-type f|d|l] [-empty] [-size [+-]<n>[ckMG]]
+// [-mindepth <n>] [-maxdepth <n>] [-delete] [-chmod <mode>] [-chown <uid>]
+// [--limit <n>] [--relative]`
+fn parse_find_args<'a>(
+    args: impl Iterator<Item = &'a str>,
+) -> Result<(String, FindQuery), &'static str> {
+    let mut args = args.filter(|arg| !arg.is_empty()).peekable();
+    let start = match args.peek() {
+        Some(arg) if !arg.starts_with('-') => args.next().unwrap().to_string(),
+        _ => ".".to_string(),
+    };
+    let mut query = FindQuery::default();
+    while let Some(arg) = args.next() {
+        match arg {
+            "-name" => {
+                query.name = Some(args.next().ok_or("missing argument to -name")?.to_string())
+            }
+            "-path" => {
+                query.path = Some(args.next().ok_or("missing argument to -path")?.to_string())
+            }
+            "-type" => {
+                query.file_type = match args.next() {
+                    Some("f") => Some(FindType::File),
+                    Some("d") => Some(FindType::Dir),
+                    Some("l") => Some(FindType::Symlink),
+                    Some(_) => return Err("unknown argument to -type"),
+                    None => return Err("missing argument to -type"),
+                }
+            }
+            "-empty" => query.empty = true,
+            "-size" => query.sizes.push(parse_size_test(
+                args.next().ok_or("missing argument to -size")?,
+            )?),
+            "-mindepth" => query.min_depth = Some(parse_depth(args.next())?),
+            "-maxdepth" => query.max_depth = Some(parse_depth(args.next())?),
+            "-delete" => query.delete = true,
+            "-chmod" => {
+                query.chmod = Some(parse_mode_spec(
+                    args.next().ok_or("missing argument to -chmod")?,
+                )?)
+            }
+            "-chown" => {
+                query.chown = Some(
+                    args.next()
+                        .ok_or("missing argument to -chown")?
+                        .parse::<u32>()
+                        .map_err(|_| "invalid argument to -chown")?,
+                )
+            }
+            "--relative" => query.relative = true,
+            "--limit" => {
+                query.limit = Some(
+                    args.next()
+                        .ok_or("missing argument to --limit")?
+                        .parse::<usize>()
+                        .map_err(|_| "invalid argument to --limit")?,
+                )
+            }
+            _ => return Err("unknown predicate"),
+        }
+    }
+    if query.delete && (query.chmod.is_some() || query.chown.is_some()) {
+        return Err("-delete cannot be combined with -chmod or -chown");
+    }
+    Ok((start, query))
+}
+
+// splits a command line into whitespace-separated words, shell style: quotes
+// group words containing spaces, a backslash outside quotes takes the next
+// character literally, and inside double quotes it only escapes `"` and `\`.
+fn split_args(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some('\''), _) => current.push(c),
+            (Some(_), '\\') => match chars.next() {
+                Some(next) if next == '"' || next == '\\' => current.push(next),
+                Some(next) => {
+                    current.push('\\');
+                    current.push(next);
+                }
+                None => return Err("unterminated quote"),
+            },
+            (None, '\\') => {
+                current.push(chars.next().ok_or("trailing backslash")?);
+                in_word = true;
+            }
+            (Some(_), _) => current.push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, _) if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (None, _) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote");
+    }
+    if in_word {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+// single-quotes an argument where needed so that split_args gives it back
+// unchanged
+fn quote_arg(arg: &str) -> String {
+    let plain = |c: char| !c.is_whitespace() && !matches!(c, '\'' | '"' | '\\');
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// interprets the \n, \t and \\ escapes of a --printf format
+fn unescape(text: &str) -> String {
+    let mut output = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some(other) => output.push(other),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+// parses `cat path | op | ...`, where each op is grep PATTERN, head [-n N],
+// tail [-n N] or wc
+fn parse_pipeline(args: &[String]) -> Result<Command, &'static str> {
+    let mut stages = args.split(|arg| arg == "|");
+    let path = match stages.next().unwrap() {
+        [cat, path] if cat == "cat" => path.clone(),
+        _ => return Err("pipes only start with cat FILE"),
+    };
+    let count = |n: &str| n.parse::<usize>().map_err(|_| "invalid number of lines");
+    let ops = stages
+        .map(|stage| {
+            let stage = stage.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>();
+            match stage.as_slice() {
+                ["grep", pattern] => Ok(PipeOp::Grep(pattern.to_string())),
+                ["head"] => Ok(PipeOp::Head(10)),
+                ["head", "-n", n] => count(n).map(PipeOp::Head),
+                ["tail"] => Ok(PipeOp::Tail(10)),
+                ["tail", "-n", n] => count(n).map(PipeOp::Tail),
+                ["wc"] => Ok(PipeOp::Wc),
+                [] => Err("missing command after |"),
+                _ => Err("pipes only take grep, head, tail and wc"),
+            }
+        })
+        .collect::<Result<Vec<PipeOp>, _>>()?;
+    Ok(Command::Pipe(path, ops))
+}
+
+fn parse_command(command: &str) -> Result<Command, &'static str> {
+    let args = split_args(command)?;
+    if args.iter().any(|arg| arg == "|") {
+        return parse_pipeline(&args);
+    }
+    let mut iter = args.iter().map(|arg| arg.as_str());
+    match iter.next() {
+        Some("pwd") => Ok(Command::Pwd),
+        Some("quit") => Ok(Command::Quit),
+        Some("mkdir") => {
+            let mut mode = None;
+            let mut parents = false;
+            let mut filename = None;
+            while let Some(arg) = iter.next() {
+                match arg {
+                    "-m" => mode = Some(parse_mode(iter.next().ok_or("missing mode")?)?),
+                    "-p" => parents = true,
+                    _ => filename = Some(arg),
+                }
+            }
+            if let Some(filename) = filename {
+                Ok(Command::MkDir(filename.to_string(), mode, parents))
+            } else {
+                Err("missing operand")
+            }
+        }
+        Some("ls") => {
+            let mut options = LsOptions::default();
+            let mut path = None;
+            for arg in iter.filter(|arg| !arg.is_empty()) {
+                if arg == "--group-directories-first" {
+                    options.group_directories = true;
+                } else if arg == "--paginate" {
+                    options.paginate = true;
+                } else if let Some(flags) = arg.strip_prefix('-') {
+                    for flag in flags.chars() {
+                        match flag {
+                            'F' => options.classify = true,
+                            'i' => options.inode = true,
+                            'r' => options.reverse = true,
+                            't' => options.sort = LsSort::Time,
+                            'S' => options.sort = LsSort::Size,
+                            's' => options.blocks = true,
+                            'h' => options.human = true,
+                            _ => return Err("ls: invalid option"),
+                        }
+                    }
+                } else {
+                    path = Some(arg.to_string());
+                }
+            }
+            Ok(Command::Ls(path, options))
+        }
+        Some("cd") => match iter.next() {
+            Some("-") => Ok(Command::CdBack(1)),
+            Some("-P") => Ok(Command::Cd(iter.next().map(|name| name.to_string()), true)),
+            Some("--create") => iter
+                .next()
+                .ok_or("missing operand")
+                .map(|path| Command::CdCreate(path.to_string())),
+            Some("-L") => Ok(Command::Cd(iter.next().map(|name| name.to_string()), false)),
+            Some(arg) if arg.starts_with('-') => arg[1..]
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(Command::CdBack)
+                .ok_or("cd: invalid history index"),
+            maybe_path => Ok(Command::Cd(maybe_path.map(|name| name.to_string()), false)),
+        },
+        Some("mkcd") => iter
+            .next()
+            .ok_or("missing operand")
+            .map(|path| Command::CdCreate(path.to_string())),
+        Some("rmdir") => iter
+            .next()
+            .ok_or("missing operand")
+            .and_then(|path| {
+                if path == "/" {
+                    Err("cannot remove root directory")
+                } else {
+                    Ok(path)
+                }
+            })
+            .map(|path| Command::RmDir(path.to_string())),
+        // like write, the content words are joined with spaces and end in a
+        // newline
+        Some("creat") => iter.next().ok_or("missing operand").map(|path| {
+            let words = iter.collect::<Vec<&str>>();
+            let content = if words.is_empty() {
+                None
+            } else {
+                Some(format!("{}\n", words.join(" ")))
+            };
+            Command::Creat(path.to_string(), content)
+        }),
+        Some("rm") => match (iter.next(), iter.next()) {
+            (Some("-r"), Some(path)) | (Some("-R"), Some(path)) => {
+                Ok(Command::Rm(path.to_string(), true))
+            }
+            (Some(path), None) if !path.starts_with('-') => {
+                Ok(Command::Rm(path.to_string(), false))
+            }
+            _ => Err("missing operand"),
+        },
+        Some("ln") => {
+            let mut symbolic = false;
+            let mut force = false;
+            let mut operands = Vec::new();
+            for arg in iter {
+                match arg.strip_prefix('-') {
+                    Some(flags) if !flags.is_empty() && operands.is_empty() => {
+                        for flag in flags.chars() {
+                            match flag {
+                                's' => symbolic = true,
+                                'f' => force = true,
+                                _ => return Err("ln: invalid option"),
+                            }
+                        }
+                    }
+                    _ => operands.push(arg),
+                }
+            }
+            if !symbolic {
+                return Err("ln: hard links are not supported, use ln -s");
+            }
+            match operands.as_slice() {
+                [target, link] => Ok(Command::Ln(target.to_string(), link.to_string(), force)),
+                [] | [_] => Err("missing operand"),
+                _ => Err("extra operand"),
+            }
+        }
+        Some("stat") => {
+            let output = match iter.next() {
+                Some("-c") => StatOutput::Format(iter.next().ok_or("missing format")?.to_string()),
+                Some("--printf") => {
+                    StatOutput::Printf(iter.next().ok_or("missing format")?.to_string())
+                }
+                Some("--json") => StatOutput::Json,
+                Some(path) => return Ok(Command::Stat(path.to_string(), StatOutput::Full)),
+                None => return Err("missing operand"),
+            };
+            iter.next()
+                .ok_or("missing operand")
+                .map(|path| Command::Stat(path.to_string(), output))
+        }
+        Some("cat") => match iter.collect::<Vec<&str>>().as_slice() {
+            // cat > file <<EOF, with or without a space before the delimiter
+            [">", path, "<<", delimiter] => {
+                Ok(Command::HereDoc(path.to_string(), delimiter.to_string()))
+            }
+            [">", path, heredoc] if heredoc.len() > 2 && heredoc.starts_with("<<") => {
+                Ok(Command::HereDoc(path.to_string(), heredoc[2..].to_string()))
+            }
+            [">", ..] => Err("cat: expected cat > file <<DELIMITER"),
+            args => {
+                let mut paths = Vec::new();
+                let mut separator = String::new();
+                let mut args = args.iter();
+                while let Some(arg) = args.next() {
+                    if *arg == "--sep" {
+                        separator = args.next().ok_or("cat: --sep needs a value")?.to_string();
+                    } else if let Some(value) = arg.strip_prefix("--sep=") {
+                        separator = value.to_string();
+                    } else {
+                        paths.push(arg.to_string());
+                    }
+                }
+                if paths.is_empty() {
+                    return Err("missing operand");
+                }
+                Ok(Command::Cat(paths, separator))
+            }
+        },
+        Some(name @ ("test" | "[")) => {
+            let mut args = iter.collect::<Vec<&str>>();
+            if name == "[" && args.pop() != Some("]") {
+                return Err("[: missing ]");
+            }
+            let predicate = match args.first() {
+                Some(&"-e") => TestPredicate::Exists,
+                Some(&"-f") => TestPredicate::File,
+                Some(&"-d") => TestPredicate::Dir,
+                Some(&"-s") => TestPredicate::NonEmpty,
+                Some(_) => return Err("test: unknown predicate"),
+                None => return Err("missing operand"),
+            };
+            match args.as_slice() {
+                [_, path] => Ok(Command::Test(predicate, path.to_string())),
+                [_] => Err("missing operand"),
+                _ => Err("extra operand"),
+            }
+        }
+        Some("newer") => match (iter.next(), iter.next(), iter.next()) {
+            (Some(a), Some(b), None) => Ok(Command::Newer(a.to_string(), b.to_string())),
+            (Some(_), Some(_), Some(_)) => Err("extra operand"),
+            _ => Err("missing operand"),
+        },
+        Some("file") => match (iter.next(), iter.next()) {
+            (Some(path), None) => Ok(Command::File(path.to_string())),
+            (Some(_), Some(_)) => Err("extra operand"),
+            (None, _) => Err("missing operand"),
+        },
+        Some("wc") => {
+            let paths = iter
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_string())
+                .collect::<Vec<String>>();
+            if paths.is_empty() {
+                Err("missing operand")
+            } else {
+                Ok(Command::Wc(paths))
+            }
+        }
+        Some("diff") => match (iter.next(), iter.next(), iter.next()) {
+            (Some(old), Some(new), None) => Ok(Command::Diff(old.to_string(), new.to_string())),
+            (Some(_), Some(_), Some(_)) => Err("extra operand"),
+            _ => Err("missing operand"),
+        },
+        Some("tac") => match (iter.next(), iter.next()) {
+            (Some(path), None) => Ok(Command::Tac(path.to_string())),
+            (Some(_), Some(_)) => Err("extra operand"),
+            (None, _) => Err("missing operand"),
+        },
+        Some("watch") => {
+            let seconds = iter
+                .next()
+                .ok_or("missing operand")?
+                .parse::<u64>()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .ok_or("watch: invalid interval")?;
+            let line = iter.map(quote_arg).collect::<Vec<String>>().join(" ");
+            match parse_command(&line)? {
+                Command::NoOp => Err("missing operand"),
+                Command::Watch(..) => Err("watch: cannot watch watch"),
+                Command::Quit => Err("watch: cannot watch quit"),
+                _ => Ok(Command::Watch(seconds, line)),
+            }
+        }
+        Some("source") => match (iter.next(), iter.next()) {
+            (Some(path), None) => Ok(Command::Source(path.to_string())),
+            (Some(_), Some(_)) => Err("extra operand"),
+            (None, _) => Err("missing operand"),
+        },
+        Some("write") => iter.next().ok_or("missing operand").map(|path| {
+            let text = iter.collect::<Vec<&str>>().join(" ");
+            Command::Write(path.to_string(), format!("{}\n", text))
+        }),
+        Some("echo") => {
+            let rest = iter.collect::<Vec<&str>>();
+            let split = rest.iter().position(|arg| *arg == ">" || *arg == ">>");
+            let text = format!("{}\n", rest[..split.unwrap_or(rest.len())].join(" "));
+            match split.map(|i| (rest[i], &rest[i + 1..])) {
+                None => Ok(Command::Echo(text, None)),
+                Some((">", [path])) => {
+                    Ok(Command::Echo(text, Some(Redirect::Write(path.to_string()))))
+                }
+                Some((_, [path])) => Ok(Command::Echo(
+                    text,
+                    Some(Redirect::Append(path.to_string())),
+                )),
+                Some((_, [])) => Err("missing operand"),
+                Some(_) => Err("extra operand"),
+            }
+        }
+        Some("cpcontent") => match (iter.next(), iter.next()) {
+            (Some(src), Some(dest)) => Ok(Command::CpContent(src.to_string(), dest.to_string())),
+            _ => Err("missing operand"),
+        },
+        Some("chmod") => match (iter.next(), iter.next(), iter.next()) {
+            (Some(mode), Some(path), None) => {
+                Ok(Command::Chmod(parse_mode_spec(mode)?, path.to_string()))
+            }
+            (Some(_), Some(_), Some(_)) => Err("extra operand"),
+            _ => Err("missing operand"),
+        },
+        Some("touch") => {
+            let mut options = TouchOptions::default();
+            let mut path = None;
+            for arg in iter.filter(|arg| !arg.is_empty()) {
+                match arg.strip_prefix('-') {
+                    Some(flags) if !flags.is_empty() => {
+                        for flag in flags.chars() {
+                            match flag {
+                                'c' => options.no_create = true,
+                                'a' => options.access = true,
+                                'm' => options.modify = true,
+                                _ => return Err("touch: invalid arguments"),
+                            }
+                        }
+                    }
+                    _ if path.is_some() => return Err("touch: invalid arguments"),
+                    _ => path = Some(arg.to_string()),
+                }
+            }
+            path.map(|path| Command::Touch(path, options))
+                .ok_or("missing operand")
+        }
+        Some("grep") => {
+            let mut options = GrepOptions::default();
+            let mut operands = Vec::new();
+            for arg in iter.filter(|arg| !arg.is_empty()) {
+                match arg.strip_prefix('-') {
+                    Some(flags) if !flags.is_empty() && operands.is_empty() => {
+                        for flag in flags.chars() {
+                            match flag {
+                                'r' | 'R' => options.recursive = true,
+                                'n' => options.line_numbers = Some(true),
+                                'N' => options.line_numbers = Some(false),
+                                'l' => {
+                                    options.files_with_matches = true;
+                                    options.recursive = true;
+                                }
+                                _ => return Err("grep: invalid option"),
+                            }
+                        }
+                    }
+                    _ => operands.push(arg.to_string()),
+                }
+            }
+            match operands.as_slice() {
+                [pattern, path] => Ok(Command::Grep(pattern.clone(), path.clone(), options)),
+                [pattern] if options.recursive => {
+                    Ok(Command::Grep(pattern.clone(), ".".to_string(), options))
+                }
+                [] | [_] => Err("missing operand"),
+                _ => Err("extra operand"),
+            }
+        }
+        Some("complete") => Ok(Command::Complete(iter.next().unwrap_or("").to_string())),
+        Some("gentree") => match (iter.next(), iter.next(), iter.next(), iter.next()) {
+            (Some(dir), Some(breadth), Some(depth), None) => {
+                match (breadth.parse::<usize>(), depth.parse::<usize>()) {
+                    (Ok(breadth), Ok(depth)) => {
+                        Ok(Command::GenTree(dir.to_string(), breadth, depth))
+                    }
+                    _ => Err("gentree: breadth and depth must be numbers"),
+                }
+            }
+            (Some(_), Some(_), Some(_), Some(_)) => Err("extra operand"),
+            _ => Err("missing operand"),
+        },
+        Some("tree") => {
+            let mut options = TreeOptions::default();
+            let mut path = None;
+            while let Some(arg) = iter.next() {
+                match arg {
+                    "--du" => options.du = true,
+                    "-L" => options.level = Some(parse_depth(iter.next())?),
+                    _ if arg.starts_with('-') => return Err("tree: invalid option"),
+                    _ if path.is_some() => return Err("extra operand"),
+                    _ => path = Some(arg),
+                }
+            }
+            Ok(Command::Tree(path.unwrap_or(".").to_string(), options))
+        }
+        Some("du") => {
+            let mut options = DuOptions::default();
+            let mut path = None;
+            while let Some(arg) = iter.next() {
+                if arg == "--blocks" {
+                    options.blocks = true;
+                } else if arg == "-h" {
+                    options.human = true;
+                } else if arg == "--exclude" {
+                    options.exclude.push(
+                        iter.next()
+                            .ok_or("du: --exclude takes a pattern")?
+                            .to_string(),
+                    );
+                } else if let Some(pattern) = arg.strip_prefix("--exclude=") {
+                    options.exclude.push(pattern.to_string());
+                } else if arg.starts_with('-') {
+                    return Err("du: invalid option");
+                } else if path.replace(arg).is_some() {
+                    return Err("extra operand");
+                }
+            }
+            Ok(Command::Du(path.unwrap_or(".").to_string(), options))
+        }
+        Some("lspaths") => {
+            let mut iter = iter.peekable();
+            let relative = iter.next_if_eq(&"--relative").is_some();
+            Ok(Command::LsPaths(
+                iter.next().unwrap_or(".").to_string(),
+                relative,
+            ))
+        }
+        Some("find") => parse_find_args(iter).map(|(start, query)| Command::Find(start, query)),
+        Some("archive") => {
+            // the find predicates, then the start and the archive file
+            let mut args = iter.filter(|arg| !arg.is_empty()).collect::<Vec<&str>>();
+            let (filepath, start) = match (args.pop(), args.pop()) {
+                (Some(filepath), Some(start)) => (filepath, start),
+                _ => return Err("missing operand"),
+            };
+            let (_, query) = parse_find_args(std::iter::once(start).chain(args))?;
+            if query.delete || query.chmod.is_some() || query.chown.is_some() {
+                return Err("archive: actions are not supported");
+            }
+            Ok(Command::Archive(
+                start.to_string(),
+                query,
+                filepath.to_string(),
+            ))
+        }
+        Some("locate") => match (iter.next(), iter.next()) {
+            (Some(name), None) => Ok(Command::Locate(name.to_string())),
+            (Some(_), Some(_)) => Err("extra operand"),
+            (None, _) => Err("missing operand"),
+        },
+        Some("cp") => {
+            parse_copy_args(iter).map(|(src, dest, options)| Command::Cp(src, dest, options))
+        }
+        Some("undo") => match iter.next() {
+            None => Ok(Command::Undo),
+            Some(_) => Err("extra operand"),
+        },
+        Some("flatten") => match (iter.next(), iter.next()) {
+            (Some(path), None) => Ok(Command::Flatten(path.to_string())),
+            (Some(_), Some(_)) => Err("extra operand"),
+            (None, _) => Err("missing operand"),
+        },
+        Some("mv") => match parse_copy_args(iter)? {
+            (_, _, options)
+                if options.recursive || options.progress || options.parents || options.update =>
+            {
+                Err("invalid option")
+            }
+            (src, dest, options) => Ok(Command::Mv(src, dest, options)),
+        },
+        Some("save") => {
+            let mut iter = iter.peekable();
+            let with_content = iter.next_if_eq(&"--content").is_some();
+            Ok(Command::Save(
+                iter.next().map(|name| name.to_string()),
+                iter.next().map(|path| path.to_string()),
+                with_content,
+            ))
+        }
+        Some("reload") => match iter.next() {
+            Some("--merge") => {
+                let mut policy = Overwrite::Refuse;
+                let mut operands = Vec::new();
+                for arg in iter {
+                    match arg {
+                        "-n" => policy = Overwrite::Skip,
+                        _ if arg.starts_with('-') => return Err("invalid option"),
+                        _ => operands.push(arg.to_string()),
+                    }
+                }
+                match operands.as_slice() {
+                    [file] => Ok(Command::ReloadMerge(file.clone(), None, policy)),
+                    [file, target] => Ok(Command::ReloadMerge(
+                        file.clone(),
+                        Some(target.clone()),
+                        policy,
+                    )),
+                    [] => Err("missing operand"),
+                    _ => Err("extra operand"),
+                }
+            }
+            maybe_filename => Ok(Command::Reload(maybe_filename.map(|name| name.to_string()))),
+        },
+        Some("purge") => match (iter.next(), iter.next()) {
+            (None, _) => Ok(Command::Purge(false)),
+            (Some("-f"), None) => Ok(Command::Purge(true)),
+            (Some(arg), _) if arg.starts_with('-') && arg != "-f" => Err("purge: invalid option"),
+            (Some(_), _) => Err("extra operand"),
+        },
+        Some("import") => match (iter.next(), iter.next(), iter.next(), iter.next()) {
+            (Some(file), Some(src), dest, None) => Ok(Command::Import(
+                file.to_string(),
+                src.to_string(),
+                dest.unwrap_or(".").to_string(),
+            )),
+            (Some(_), Some(_), Some(_), Some(_)) => Err("extra operand"),
+            _ => Err("missing operand"),
+        },
+        Some("overlay") => match (iter.next(), iter.next(), iter.next()) {
+            (Some(file), Some(mount), None) => {
+                Ok(Command::Overlay(file.to_string(), mount.to_string()))
+            }
+            (Some(_), Some(_), Some(_)) => Err("extra operand"),
+            _ => Err("missing operand"),
+        },
+        Some("umask") => match (iter.next(), iter.next()) {
+            (None, _) => Ok(Command::Umask(None)),
+            (Some(mode), None) => parse_mode(mode)
+                .ok()
+                .filter(|mode| *mode <= 0o777)
+                .map(|mode| Command::Umask(Some(mode)))
+                .ok_or("umask: invalid mask"),
+            (Some(_), Some(_)) => Err("extra operand"),
+        },
+        Some("info") => match iter.next() {
+            None => Ok(Command::Info),
+            Some(_) => Err("extra operand"),
+        },
+        Some("pathcache") => match iter.next() {
+            None => Ok(Command::PathCacheStats),
+            Some(_) => Err("extra operand"),
+        },
+        Some("df") => match (iter.next(), iter.next()) {
+            (Some("-i"), None) => Ok(Command::DfInodes),
+            (Some("-i"), Some(_)) => Err("extra operand"),
+            _ => Err("df: only -i is supported"),
+        },
+        Some("set") => match (iter.next(), iter.next()) {
+            (Some("verbose"), Some("on")) => Ok(Command::Set(Setting::Verbose(true))),
+            (Some("verbose"), Some("off")) => Ok(Command::Set(Setting::Verbose(false))),
+            (Some("verbose"), _) => Err("set: verbose takes on or off"),
+            (Some("max-nodes"), Some("off")) => Ok(Command::Set(Setting::MaxNodes(None))),
+            (Some("max-nodes"), Some(max)) => max
+                .parse::<usize>()
+                .ok()
+                .filter(|max| *max > 0)
+                .map(|max| Command::Set(Setting::MaxNodes(Some(max))))
+                .ok_or("set: max-nodes takes a positive number or off"),
+            (Some("max-nodes"), None) => Err("set: max-nodes takes a positive number or off"),
+            (Some("max-depth"), Some(max)) => max
+                .parse::<usize>()
+                .ok()
+                .filter(|max| *max > 0)
+                .map(|max| Command::Set(Setting::MaxDepth(max)))
+                .ok_or("set: max-depth takes a positive number"),
+            (Some("max-depth"), None) => Err("set: max-depth takes a positive number"),
+            (Some("prompt"), Some(template)) => match iter.next() {
+                None => Ok(Command::Set(Setting::Prompt(template.to_string()))),
+                Some(_) => Err("set: quote a prompt containing spaces"),
+            },
+            (Some("prompt"), None) => Err("set: prompt takes a template"),
+            (Some("path-cache"), Some("off")) => Ok(Command::Set(Setting::PathCache(0))),
+            (Some("path-cache"), Some(size)) => size
+                .parse::<usize>()
+                .map(|size| Command::Set(Setting::PathCache(size)))
+                .map_err(|_| "set: path-cache takes a number or off"),
+            (Some("path-cache"), None) => Err("set: path-cache takes a number or off"),
+            (Some("clock"), Some("system")) => Ok(Command::Set(Setting::Clock(Clock::System))),
+            (Some("clock"), Some(seconds)) => seconds
+                .parse::<u64>()
+                .map(|seconds| {
+                    let time = UNIX_EPOCH + Duration::from_secs(seconds);
+                    Command::Set(Setting::Clock(Clock::Fixed(time)))
+                })
+                .map_err(|_| "set: clock takes system or seconds since the epoch"),
+            (Some("block-size"), Some(size)) => size
+                .parse::<usize>()
+                .ok()
+                .filter(|size| *size > 0)
+                .map(|size| Command::Set(Setting::BlockSize(size)))
+                .ok_or("set: block-size takes a positive number"),
+            (Some("block-size"), None) => Err("set: block-size takes a positive number"),
+            (Some("clock"), None) => Err("set: clock takes system or seconds since the epoch"),
+            (Some(_), _) => Err("set: unknown option"),
+            (None, _) => Err("missing operand"),
+        },
+        None => Ok(Command::NoOp),
+        _ => Err("not implemented"),
+    }
+}
+
+// the lines `ls` prints for the entries
+fn ls_lines(fs: &FileSystem, entries: &[(String, usize)], options: LsOptions) -> Vec<String> {
+    let names = entries
+        .iter()
+        .map(|(name, id)| {
+            let node = fs.nodes.get(id).unwrap();
+            let indicator = match node.node_type {
+                NodeType::DIR { .. } if options.classify => "/",
+                NodeType::SYMLINK { .. } if options.classify => "@",
+                _ => "",
+            };
+            let mut line = String::new();
+            if options.inode {
+                line.push_str(&format!("{} ", id));
+            }
+            if options.blocks && options.human {
+                line.push_str(&format!("{} ", human_size(fs.blocks(node) * fs.block_size)));
+            } else if options.blocks {
+                line.push_str(&format!("{} ", fs.blocks(node)));
+            }
+            line.push_str(name);
+            line.push_str(indicator);
+            line
+        })
+        .collect::<Vec<String>>();
+    // columns only on a terminal whose width the shell exports in COLUMNS
+    let width = env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse::<usize>().ok())
+        .filter(|_| io::stdout().is_terminal());
+    match width {
+        Some(width) => columnize(&names, width),
+        None => names,
+    }
+}
+
+// lays names out top to bottom, then left to right, in as many columns as fit
+// in width, two spaces apart; a name wider than width gets a row of its own
+fn columnize(names: &[String], width: usize) -> Vec<String> {
+    const GAP: usize = 2;
+    let lengths = names
+        .iter()
+        .map(|name| name.chars().count())
+        .collect::<Vec<usize>>();
+    let mut rows = names.len().max(1);
+    let mut column_widths = vec![lengths.iter().copied().max().unwrap_or(0)];
+    for columns in (2..=names.len()).rev() {
+        let candidate_rows = names.len().div_ceil(columns);
+        let candidate_widths = lengths
+            .chunks(candidate_rows)
+            .map(|column| column.iter().copied().max().unwrap())
+            .collect::<Vec<usize>>();
+        let total = candidate_widths.iter().sum::<usize>() + GAP * (candidate_widths.len() - 1);
+        if total <= width {
+            rows = candidate_rows;
+            column_widths = candidate_widths;
+            break;
+        }
+    }
+    (0..rows)
+        .filter(|row| *row < names.len())
+        .map(|row| {
+            let mut line = String::new();
+            for (column, column_width) in column_widths.iter().enumerate() {
+                if let Some(name) = names.get(column * rows + row) {
+                    if !line.is_empty() {
+                        line.push_str(&" ".repeat(GAP));
+                    }
+                    line.push_str(name);
+                    line.push_str(&" ".repeat(column_width - lengths[column * rows + row]));
+                }
+            }
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+// writes lines page_size at a time, waiting after each page but the last for
+// a line of input at a --More-- prompt; an answer of q stops the listing
+fn paginate(lines: &[String], page_size: usize, input: &mut dyn BufRead, output: &mut dyn Write) {
+    for (n, page) in lines.chunks(page_size).enumerate() {
+        if n > 0 {
+            write!(output, "--More--").unwrap();
+            output.flush().unwrap();
+            let mut answer = String::new();
+            input.read_line(&mut answer).unwrap();
+            if answer.trim() == "q" {
+                break;
+            }
+        }
+        page.iter()
+            .for_each(|line| writeln!(output, "{}", line).unwrap());
+    }
+}
+
+// the line to run for an input line: !! stands for last_line, and any other
+// non-blank line is run as is and becomes the new last_line. Since !! is
+// never recorded, it cannot end up recalling itself.
+fn recall_line(line: String, last_line: &mut Option<String>) -> Result<String, &'static str> {
+    if line.trim() == "!!" {
+        last_line.clone().ok_or("!!: event not found")
+    } else {
+        if !line.trim().is_empty() {
+            *last_line = Some(line.clone());
+        }
+        Ok(line)
+    }
+}
+
+// expands {cwd}, {user} and {count} (the number of the command about to be
+// read) in a prompt template; anything else is printed as is
+fn render_prompt(template: &str, cwd: &str, user: &str, count: usize) -> String {
+    template
+        .replace("{cwd}", cwd)
+        .replace("{user}", user)
+        .replace("{count}", &count.to_string())
+}
+
+// set by the SIGINT handler that `watch` installs while it runs
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// std has no way to catch a signal, and the crate takes no dependencies, so
+// this binds libc's signal(2) by hand, which every unix libc provides. Its C
+// prototype is
+//     void (*signal(int sig, void (*handler)(int)))(int);
+// sighandler_t, the handler type, is a function pointer. Function pointers
+// are pointer sized on every unix target Rust supports, so usize matches it
+// in the C ABI, both as the argument and as the returned previous handler.
+#[cfg(unix)]
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+// runs in signal context, so it must only do async-signal-safe work. A store
+// to a lock-free atomic is: it neither allocates, locks nor touches anything
+// the interrupted code might be in the middle of changing.
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// while catching, Ctrl-C sets INTERRUPTED instead of killing the shell;
+// elsewhere Ctrl-C keeps its default behaviour. The shell installs no other
+// SIGINT handler, so going back to the default is restoring the previous one.
+#[cfg(unix)]
+fn catch_interrupts(catch: bool) {
+    // SIGINT is 2 and SIG_DFL is 0 on Linux, macOS and the BSDs alike
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+    let handler = if catch {
+        on_interrupt as extern "C" fn(i32) as usize
+    } else {
+        SIG_DFL
+    };
+    // SAFETY: the declaration above matches the C prototype, the handler is
+    // an extern "C" fn(i32) that lives for the whole program and only does
+    // async-signal-safe work, and SIG_DFL is a value signal(2) accepts. The
+    // returned previous handler needs no cleanup. thread::sleep resumes
+    // after EINTR, so the watch sees the flag at the end of its current
+    // 100ms slice.
+    unsafe {
+        signal(SIGINT, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_interrupts(_: bool) {}
+
+// reads and runs commands from input until quit or the end of input; returns
+// whether quit was given. Scripts run without a prompt.
+fn run(fs: &mut FileSystem, input: &mut dyn BufRead, interactive: bool) -> bool {
+    let user = env::var("USER").unwrap_or_else(|_| "root".to_string());
+    let mut count = 0;
+    // see recall_line
+    let mut last_line: Option<String> = None;
+    loop {
+        count += 1;
+        let mut command = String::new();
+        if interactive {
+            print!(
+                "{}",
+                render_prompt(&fs.prompt, &fs.cwd_path(), &user, count)
+            );
+            io::stdout().flush().unwrap();
+        }
+        if input.read_line(&mut command).unwrap() == 0 {
+            return false;
+        }
+        let recalled = command.trim() == "!!";
+        command = match recall_line(command, &mut last_line) {
+            Ok(line) => line,
+            Err(err) => {
+                println!("{}", err);
+                continue;
+            }
+        };
+        if recalled {
+            // like a shell, show the line being run
+            println!("{}", command.trim_end());
+        }
+        match parse_command(&command) {
+            Ok(command) => match command {
+                Command::Pwd => fs.pwd(),
+                Command::Quit => {
+                    println!("Saving...");
+                    fs.save(None, None, false)
+                        .unwrap_or_else(|err| println!("Quitting without saving: {}", err));
+                    return true;
+                }
+                Command::MkDir(filename, mode, parents) => if parents {
+                    fs.mkdir_p(&filename, mode)
+                } else {
+                    fs.mkdir(&filename, mode)
+                }
+                .unwrap_or_else(|err| {
+                    println!("mkdir: cannot create directory {}: {}", filename, err)
+                }),
+                Command::Ls(filename, options) => match fs.ls(filename) {
+                    Ok(mut entries) => {
+                        // entries come in name order, which the stable sorts
+                        // keep among ties
+                        match options.sort {
+                            LsSort::Name => {}
+                            LsSort::Time => entries.sort_by_key(|(_, id)| {
+                                std::cmp::Reverse(fs.nodes.get(id).unwrap().mtime)
+                            }),
+                            LsSort::Size => entries.sort_by_key(|(_, id)| {
+                                std::cmp::Reverse(fs.nodes.get(id).unwrap().size())
+                            }),
+                        }
+                        if options.reverse {
+                            entries.reverse();
+                        }
+                        if options.group_directories {
+                            // the sort is stable, so each group stays in name order
+                            entries.sort_by_key(|(_, id)| !fs.nodes.get(id).unwrap().is_dir_node());
+                        }
+                        let lines = ls_lines(fs, &entries, options);
+                        // a page is the screen minus the line the --More-- prompt takes
+                        let page_size = env::var("LINES")
+                            .ok()
+                            .and_then(|lines| lines.parse::<usize>().ok())
+                            .unwrap_or(DEFAULT_LINES)
+                            .saturating_sub(1)
+                            .max(1);
+                        let paged = options.paginate
+                            && interactive
+                            && io::stdin().is_terminal()
+                            && io::stdout().is_terminal();
+                        if !paged {
+                            lines.iter().for_each(|line| println!("{}", line));
+                        } else {
+                            paginate(&lines, page_size, input, &mut io::stdout());
+                        }
+                    }
+                    Err(err) => println!("{}", err),
+                },
+                Command::Cd(filename, physical) => fs
+                    .cd(filename, physical)
+                    .unwrap_or_else(|err| println!("cd: {}", err)),
+                Command::CdCreate(filename) => fs
+                    .cd_create(&filename)
+                    .unwrap_or_else(|err| println!("cd: {}: {}", filename, err)),
+                Command::CdBack(n) => match fs.cd_back(n) {
+                    Ok(()) => fs.pwd(),
+                    Err(err) => println!("cd: {}", err),
+                },
+
+                Command::RmDir(filename) => fs
+                    .rmdir(&filename)
+                    .map(drop)
+                    .unwrap_or_else(|err| println!("rmdir: {}", err)),
+                Command::Creat(filename, content) => match content {
+                    Some(text) => fs.creat_with_content(&filename, text),
+                    None => fs.creat(&filename),
+                }
+                .unwrap_or_else(|err| println!("creat: cannot create file {}: {}", filename, err)),
+                Command::Rm(filename, recursive) => if recursive {
+                    fs.rm_recursive(&filename).map(drop)
+                } else {
+                    fs.rm(&filename)
+                }
+                .unwrap_or_else(|err| println!("rm: cannot remove {}: {}", filename, err)),
+                Command::Ln(target, link, force) => if force {
+                    fs.symlink_force(&target, &link)
+                } else {
+                    fs.symlink(&target, &link)
+                }
+                .unwrap_or_else(|err| {
+                    println!("ln: failed to create symbolic link {}: {}", link, err)
+                }),
+                Command::Stat(filename, StatOutput::Full) => fs
+                    .stat(&filename)
+                    .unwrap_or_else(|err| println!("stat: cannot stat {}: {}", filename, err)),
+                Command::Stat(filename, StatOutput::Format(format)) => {
+                    match fs.stat_format(&filename, &format) {
+                        Ok(output) => println!("{}", output),
+                        Err(err) => println!("stat: cannot stat {}: {}", filename, err),
+                    }
+                }
+                Command::Stat(filename, StatOutput::Json) => match fs.stat_json(&filename) {
+                    Ok(output) => println!("{}", output),
+                    Err(err) => println!("stat: cannot stat {}: {}", filename, err),
+                },
+                Command::Stat(filename, StatOutput::Printf(format)) => {
+                    match fs.stat_format(&filename, &unescape(&format)) {
+                        Ok(output) => print!("{}", output),
+                        Err(err) => println!("stat: cannot stat {}: {}", filename, err),
+                    }
+                }
+                Command::Cat(filenames, separator) => {
+                    // each content goes out as it is read, so errors land
+                    // where they happen. The separator goes between contents,
+                    // never before the first or after the last
+                    let mut first = true;
+                    let mut line_ended = true;
+                    for filename in &filenames {
+                        match fs.cat(filename) {
+                            Ok(content) => {
+                                let text = if first {
+                                    content.to_string()
+                                } else {
+                                    format!("{}{}", separator, content)
+                                };
+                                print!("{}", text);
+                                if !text.is_empty() {
+                                    line_ended = text.ends_with('\n');
+                                }
+                                first = false;
+                            }
+                            Err(err) => println!("cat: {}: {}", filename, err),
+                        }
+                    }
+                    if !line_ended {
+                        println!();
+                    }
+                }
+                Command::HereDoc(filename, delimiter) => {
+                    let mut text = String::new();
+                    loop {
+                        let mut line = String::new();
+                        if input.read_line(&mut line).unwrap() == 0
+                            || line.trim_end_matches(&['\r', '\n'][..]) == delimiter
+                        {
+                            break;
+                        }
+                        text.push_str(&line);
+                    }
+                    fs.write(&filename, &text)
+                        .unwrap_or_else(|err| println!("cat: {}: {}", filename, err))
+                }
+                Command::Test(predicate, filename) => println!("{}", fs.test(predicate, &filename)),
+                Command::Newer(a, b) => match (fs.mtime(&a), fs.mtime(&b)) {
+                    (Ok(a_time), Ok(b_time)) if a_time > b_time => {
+                        println!("{} is newer than {}", a, b)
+                    }
+                    (Ok(_), Ok(_)) => println!("{} is not newer than {}", a, b),
+                    (Err(err), _) => println!("newer: cannot stat {}: {}", a, err),
+                    (_, Err(err)) => println!("newer: cannot stat {}: {}", b, err),
+                },
+                // runs the line as a one-line script, so it behaves as typed
+                Command::Watch(seconds, line) => {
+                    INTERRUPTED.store(false, Ordering::SeqCst);
+                    catch_interrupts(true);
+                    while !INTERRUPTED.load(Ordering::SeqCst) {
+                        print!("\x1b[2J\x1b[H");
+                        println!("Every {}s: {}\n", seconds, line);
+                        run(fs, &mut line.as_bytes(), false);
+                        io::stdout().flush().unwrap();
+                        // sleep in slices so Ctrl-C ends the watch promptly
+                        for _ in 0..seconds * 10 {
+                            if INTERRUPTED.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                    }
+                    catch_interrupts(false);
+                    println!();
+                }
+                Command::Source(filename) => match File::open(&filename) {
+                    Ok(file) => {
+                        if run(fs, &mut BufReader::new(file), false) {
+                            return true;
+                        }
+                    }
+                    Err(_) => println!("source: {}: No such file", filename),
+                },
+                // prints nothing when the files are the same
+                Command::Diff(old, new) => match (fs.cat(&old), fs.cat(&new)) {
+                    (Ok(old_content), Ok(new_content)) if old_content == new_content => {}
+                    (Ok(old_content), Ok(new_content)) => {
+                        println!("--- {}", old);
+                        println!("+++ {}", new);
+                        diff_lines(old_content, new_content)
+                            .iter()
+                            .for_each(|line| println!("{}", line));
+                    }
+                    (Err(err), _) => println!("diff: {}: {}", old, err),
+                    (_, Err(err)) => println!("diff: {}: {}", new, err),
+                },
+                // like cat, a last line without a newline is printed with one
+                Command::Tac(filename) => match fs.cat(&filename) {
+                    Ok(content) => content.lines().rev().for_each(|line| println!("{}", line)),
+                    Err(err) => println!("tac: {}: {}", filename, err),
+                },
+                Command::File(filename) => match fs.file_kind(&filename) {
+                    Ok(kind) => println!("{}: {}", filename, kind),
+                    Err(err) => println!("file: {}: {}", filename, err),
+                },
+                Command::Pipe(filename, ops) => match fs.pipe(&filename, &ops) {
+                    Ok(text) if text.is_empty() || text.ends_with('\n') => print!("{}", text),
+                    Ok(text) => println!("{}", text),
+                    Err(err) => println!("cat: {}: {}", filename, err),
+                },
+                // lines, words and bytes of each file, then a total when there
+                // are several; a failing operand doesn't stop the rest
+                Command::Wc(filenames) => {
+                    let counts = filenames
+                        .iter()
+                        .map(|filename| fs.cat(filename).map(word_count))
+                        .collect::<Vec<_>>();
+                    let mut total = [0; 3];
+                    for [lines, words, bytes] in counts.iter().flatten() {
+                        total[0] += lines;
+                        total[1] += words;
+                        total[2] += bytes;
+                    }
+                    // every column is as wide as the largest count
+                    let width = total[2].to_string().len();
+                    let print_counts = |counts: &[usize; 3], name: &str| {
+                        println!(
+                            "{:>w$} {:>w$} {:>w$} {}",
+                            counts[0],
+                            counts[1],
+                            counts[2],
+                            name,
+                            w = width
+                        )
+                    };
+                    for (filename, result) in filenames.iter().zip(&counts) {
+                        match result {
+                            Ok(counts) => print_counts(counts, filename),
+                            Err(err) => println!("wc: {}: {}", filename, err),
+                        }
+                    }
+                    if filenames.len() > 1 {
+                        print_counts(&total, "total");
+                    }
+                }
+                Command::Write(filename, text) => fs
+                    .write(&filename, &text)
+                    .unwrap_or_else(|err| println!("write: {}: {}", filename, err)),
+                Command::Echo(text, None) => print!("{}", text),
+                Command::Echo(text, Some(Redirect::Write(filename))) => fs
+                    .write(&filename, &text)
+                    .unwrap_or_else(|err| println!("echo: {}: {}", filename, err)),
+                Command::Echo(text, Some(Redirect::Append(filename))) => fs
+                    .append(&filename, &text)
+                    .unwrap_or_else(|err| println!("echo: {}: {}", filename, err)),
+                Command::CpContent(src, dest) => fs.cpcontent(&src, &dest).unwrap_or_else(|err| {
+                    println!("cpcontent: cannot copy {} to {}: {}", src, dest, err)
+                }),
+                Command::Chmod(mode, filename) => fs
+                    .chmod(&mode, &filename)
+                    .unwrap_or_else(|err| println!("chmod: cannot access '{}': {}", filename, err)),
+                Command::Touch(filename, options) => fs
+                    .touch(&filename, options)
+                    .unwrap_or_else(|err| println!("touch: cannot touch {}: {}", filename, err)),
+                Command::Grep(pattern, path, options) => match fs.grep(&pattern, &path, options) {
+                    Ok(lines) => lines.iter().for_each(|line| println!("{}", line)),
+                    Err(err) => println!("grep: {}: {}", path, err),
+                },
+                Command::Complete(partial) => match fs.complete(&partial) {
+                    Ok(candidates) => candidates.iter().for_each(|path| println!("{}", path)),
+                    Err(err) => println!("complete: {}: {}", partial, err),
+                },
+                Command::GenTree(dir, breadth, depth) => match fs.gentree(&dir, breadth, depth) {
+                    Ok(created) => println!("gentree: created {} nodes", created),
+                    Err(err) => println!("gentree: {}: {}", dir, err),
+                },
+                Command::Tree(start, options) => match fs.tree(&start, options) {
+                    Ok(lines) => lines.iter().for_each(|line| println!("{}", line)),
+                    Err(err) => println!("tree: {}: {}", start, err),
+                },
+                Command::Du(start, options) => match fs.du(&start, &options) {
+                    Ok(report) => report.iter().for_each(|(total, path)| {
+                        if options.human && options.blocks {
+                            println!("{}\t{}", human_size(total * fs.block_size), path)
+                        } else if options.human {
+                            println!("{}\t{}", human_size(*total), path)
+                        } else {
+                            println!("{}\t{}", total, path)
+                        }
+                    }),
+                    Err(err) => println!("du: cannot access {}: {}", start, err),
+                },
+                Command::LsPaths(start, relative) => match fs.lspaths(&start, relative) {
+                    Ok(paths) => paths.iter().for_each(|path| println!("{}", path)),
+                    Err(err) => println!("lspaths: {}: {}", start, err),
+                },
+                Command::Find(start, query) if query.delete => {
+                    match fs.find_delete(&start, &query) {
+                        Ok(failed) => failed.iter().for_each(|(path, err)| {
+                            println!("find: cannot delete {}: {}", path, err)
+                        }),
+                        Err(err) => println!("find: {}: {}", start, err),
+                    }
+                }
+                Command::Find(start, query) if query.chmod.is_some() || query.chown.is_some() => {
+                    match fs.find_apply(&start, &query) {
+                        Ok(failed) => failed.iter().for_each(|(path, err)| {
+                            println!("find: cannot change {}: {}", path, err)
+                        }),
+                        Err(err) => println!("find: {}: {}", start, err),
+                    }
+                }
+                Command::Find(start, query) => match fs.search(&start, &query) {
+                    Ok(paths) => paths.iter().for_each(|path| println!("{}", path)),
+                    Err(err) => println!("find: {}: {}", start, err),
+                },
+                Command::Archive(start, query, filepath) => {
+                    match fs.archive(&start, &query, &filepath) {
+                        Ok(files) => println!("archived {} files to {}", files, filepath),
+                        Err(err) => println!("archive: {}: {}", start, err),
+                    }
+                }
+                Command::Locate(name) => fs
+                    .locate(&name)
+                    .iter()
+                    .for_each(|path| println!("{}", path)),
+                Command::Cp(src, dest, options) => match fs.cp(&src, &dest, options) {
+                    Ok(copied) if options.progress => println!("cp: copied {} nodes", copied),
+                    Ok(_) => {}
+                    Err(SAME_FILE) => println!("cp: '{}' and '{}' are the same file", src, dest),
+                    Err(err) => println!("cp: cannot copy {} to {}: {}", src, dest, err),
+                },
+                Command::Mv(src, dest, options) => fs
+                    .mv(&src, &dest, options)
+                    .unwrap_or_else(|err| println!("mv: cannot move {} to {}: {}", src, dest, err)),
+                Command::Undo => match fs.undo() {
+                    Ok(path) if fs.verbose => println!("undo: restored '{}'", path),
+                    Ok(_) => {}
+                    Err(err) => println!("undo: {}", err),
+                },
+                Command::Flatten(dir) => fs
+                    .flatten(&dir)
+                    .unwrap_or_else(|err| println!("flatten: cannot flatten {}: {}", dir, err)),
+                Command::Save(maybe_filename, maybe_path, with_content) => fs
+                    .save(maybe_filename, maybe_path, with_content)
+                    .unwrap_or_else(|err| println!("error saving the filesystem: {}", err)),
+                Command::Reload(maybe_filename) => match fs.reload(maybe_filename) {
+                    Ok(counts) => println!(
+                        "reloaded {} nodes ({}): {} directories, {} files, {} symlinks",
+                        fs.nodes.len(),
+                        counts.version,
+                        counts.directories,
+                        counts.files,
+                        counts.symlinks
+                    ),
+                    Err(err) => println!("error reloading the filesystem: {}", err),
+                },
+                Command::ReloadMerge(filename, target, policy) => fs
+                    .reload_merge(&filename, target.as_deref(), policy)
+                    .unwrap_or_else(|err| println!("error merging the backup: {}", err)),
+                Command::Import(filename, src, dest) => match fs.import(&filename, &src, &dest) {
+                    Ok(copied) if fs.verbose => println!("import: copied {} nodes", copied),
+                    Ok(_) => {}
+                    Err(err) => println!("import: cannot import {} to {}: {}", src, dest, err),
+                },
+                Command::Overlay(filename, mount) => fs
+                    .overlay(&filename, &mount)
+                    .unwrap_or_else(|err| println!("overlay: {}: {}", mount, err)),
+                Command::Purge(true) => fs.purge(),
+                // a script has no one to answer, and the answer would be read
+                // from its next line
+                Command::Purge(false) if !interactive => {
+                    println!("purge: refusing to purge from a script without -f")
+                }
+                Command::Purge(false) => {
+                    print!("purge: remove every file and directory? [y/N] ");
+                    io::stdout().flush().unwrap();
+                    let mut answer = String::new();
+                    input.read_line(&mut answer).unwrap();
+                    if answer.trim().eq_ignore_ascii_case("y") {
+                        fs.purge();
+                    }
+                }
+                Command::Set(Setting::Verbose(verbose)) => fs.verbose = verbose,
+                Command::Set(Setting::MaxNodes(max_nodes)) => fs.max_nodes = max_nodes,
+                Command::Set(Setting::MaxDepth(max_depth)) => fs.max_depth = max_depth,
+                Command::Set(Setting::Prompt(prompt)) => fs.prompt = prompt,
+                Command::Set(Setting::Clock(clock)) => fs.clock = clock,
+                Command::Set(Setting::BlockSize(size)) => fs.block_size = size,
+                Command::Set(Setting::PathCache(size)) => {
+                    fs.path_cache.borrow_mut().set_capacity(size)
+                }
+                Command::Umask(Some(umask)) => fs.umask = umask,
+                Command::Umask(None) => println!("{:04o}", fs.umask),
+                Command::PathCacheStats => {
+                    let cache = fs.path_cache.borrow();
+                    println!(
+                        "hits: {}, misses: {}, entries: {}/{}",
+                        cache.hits,
+                        cache.misses,
+                        cache.entries.len(),
+                        cache.capacity
+                    );
+                }
+                // one "key: value" line each, in a fixed order. Ids are never
+                // recycled, so there is no free list to show.
+                Command::Info => {
+                    println!("counter: {}", fs.counter);
+                    println!("cwd: {} {}", fs.cwd, fs.path_of(fs.cwd));
+                    println!("nodes: {}", fs.nodes.len());
+                    println!(
+                        "names: {} names, {} ids",
+                        fs.names.len(),
+                        fs.names.values().map(Vec::len).sum::<usize>()
+                    );
+                    let cache = fs.path_cache.borrow();
+                    println!("path-cache: {}/{}", cache.entries.len(), cache.capacity);
+                    println!("cd-history: {}", fs.cd_history.len());
+                    println!("read-only: {}", fs.read_only.len());
+                }
+                Command::DfInodes => {
+                    let (total, free) = match fs.max_nodes {
+                        Some(max) => (
+                            max.to_string(),
+                            max.saturating_sub(fs.nodes.len()).to_string(),
+                        ),
+                        None => ("-".to_string(), "-".to_string()),
+                    };
+                    println!("{:>10} {:>10} {:>10}", "Inodes", "IUsed", "IFree");
+                    println!("{:>10} {:>10} {:>10}", total, fs.nodes.len(), free);
+                }
+                Command::NoOp => continue,
+            },
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+fn main() {
+    let mut fs = FileSystem::new();
+    run(&mut fs, &mut io::stdin().lock(), true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    #[test]
+    fn normalize_path_resolves_lexically() {
+        for (path, cwd, expected) in [
+            ("a/../../b", "/", "/b"),
+            ("a/../../b", "/x/y", "/x/b"),
+            ("../../..", "/x", "/"),
+            ("/a/b/", "/x", "/a/b"),
+            ("a/", "/x", "/x/a"),
+            ("//a//./b//", "/", "/a/b"),
+            (".", "/x/y", "/x/y"),
+            ("", "/x", "/x"),
+            ("/", "/x", "/"),
+            ("b/./c/..", "/x/", "/x/b"),
+            // names that only look like . and .. are kept
+            ("...", "/", "/..."),
+        ] {
+            assert_eq!(normalize_path(path, cwd), expected, "{} from {}", path, cwd);
+        }
+    }
+
+    #[test]
+    fn cd_back_walks_the_history_ring() {
+        let mut fs = FileSystem::new();
+        let cd = |fs: &mut FileSystem, path: &str| fs.cd(Some(path.to_string()), false).unwrap();
+        for name in ["a", "b", "c"] {
+            fs.mkdir(name, None).unwrap();
+        }
+        cd(&mut fs, "a");
+        cd(&mut fs, "/b");
+        cd(&mut fs, "/c");
+        assert_eq!(fs.path_of(fs.cwd), "/c");
+        fs.cd_back(2).unwrap();
+        assert_eq!(fs.path_of(fs.cwd), "/a");
+        // the jump itself is recorded, so cd - goes back to where it started
+        fs.cd_back(1).unwrap();
+        assert_eq!(fs.path_of(fs.cwd), "/c");
+        fs.cd_back(3).unwrap();
+        assert_eq!(fs.path_of(fs.cwd), "/b");
+        assert_eq!(fs.cd_back(99), Err("no such entry in directory history"));
+        assert!(matches!(parse_command("cd -"), Ok(Command::CdBack(1))));
+        assert!(matches!(parse_command("cd -3"), Ok(Command::CdBack(3))));
+        assert!(matches!(
+            parse_command("cd -0"),
+            Err("cd: invalid history index")
+        ));
+    }
+
+    #[test]
+    fn cd_back_skips_removed_directories_and_keeps_the_latest() {
+        let mut fs = FileSystem::new();
+        let cd = |fs: &mut FileSystem, path: &str| fs.cd(Some(path.to_string()), false).unwrap();
+        fs.mkdir("a", None).unwrap();
+        fs.mkdir("b", None).unwrap();
+        cd(&mut fs, "a");
+        cd(&mut fs, "/b");
+        cd(&mut fs, "/");
+        fs.rmdir("b").unwrap();
+        fs.cd_back(1).unwrap();
+        assert_eq!(fs.path_of(fs.cwd), "/a");
+        for _ in 0..2 * CD_HISTORY_SIZE {
+            cd(&mut fs, "/");
+            cd(&mut fs, "/a");
+        }
+        assert_eq!(fs.cd_history.len(), CD_HISTORY_SIZE);
+    }
+
+    #[test]
+    fn purge_leaves_only_the_root() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("a", None).unwrap();
+        fs.mkdir("a/b", None).unwrap();
+        fs.creat("a/f").unwrap();
+        fs.cd(Some("a/b".to_string()), false).unwrap();
+        fs.purge();
+        assert_eq!(fs.nodes.len(), 1);
+        assert!(
+            matches!(&fs.nodes[&0].node_type, NodeType::DIR { children } if children.is_empty())
+        );
+        assert_eq!(fs.cwd, 0);
+        assert!(fs.cd_history.is_empty());
+        // the tree is usable again afterwards
+        fs.mkdir("c", None).unwrap();
+        assert_eq!(fs.path_of(fs.find(0, &["c"]).unwrap()), "/c");
+    }
+
+    #[test]
+    fn cp_and_mv_overwrite_policies() {
+        let mut fs = FileSystem::new();
+        for name in ["a", "b", "c"] {
+            fs.creat(name).unwrap();
+        }
+        fs.mkdir("d", None).unwrap();
+        let copy = |overwrite| CopyOptions {
+            overwrite,
+            ..CopyOptions::default()
+        };
+        let id = |fs: &FileSystem, path: &str| fs.find(0, &split_path(path).1).ok();
+        let (a, b, c) = (id(&fs, "a"), id(&fs, "b"), id(&fs, "c"));
+
+        // the default refuses, -n leaves the destination alone, -f replaces it
+        assert_eq!(fs.cp("a", "b", copy(Overwrite::Refuse)), Err("File exists"));
+        assert_eq!(fs.cp("a", "b", copy(Overwrite::Skip)), Ok(0));
+        assert_eq!(id(&fs, "b"), b);
+        fs.cp("a", "b", copy(Overwrite::Force)).unwrap();
+        assert!(id(&fs, "b") != b && id(&fs, "b") != a);
+        assert_eq!(fs.mv("a", "c", copy(Overwrite::Refuse)), Err("File exists"));
+        fs.mv("a", "c", copy(Overwrite::Skip)).unwrap();
+        assert_eq!((id(&fs, "a"), id(&fs, "c")), (a, c));
+        fs.mv("a", "c", copy(Overwrite::Force)).unwrap();
+        assert_eq!((id(&fs, "a"), id(&fs, "c")), (None, a));
+        assert!(!fs.nodes.contains_key(&c.unwrap()));
+
+        // a directory destination receives the source under its own name
+        let b = id(&fs, "b");
+        fs.cp("b", "d", copy(Overwrite::Refuse)).unwrap();
+        fs.mv("c", "d/", copy(Overwrite::Refuse)).unwrap();
+        assert!(id(&fs, "d/b").is_some() && id(&fs, "d/b") != b);
+        assert_eq!(id(&fs, "d/c"), a);
+        assert_eq!(fs.cp("b", "d", copy(Overwrite::Refuse)), Err("File exists"));
+        fs.cp("b", "d", copy(Overwrite::Force)).unwrap();
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            ["/", "/b", "/d", "/d/b", "/d/c"]
+        );
+    }
+
+    // a file in the system temp directory that is removed again on drop
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path =
+                std::env::temp_dir().join(format!("fs-test-{}-{}", std::process::id(), name));
+            TempFile(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn stat_ids_survive_renames_and_moves() {
+        let mut fs = FileSystem::new();
+        for dir in ["a", "b", "a/d"] {
+            fs.mkdir(dir, None).unwrap();
+        }
+        fs.creat("a/d/f").unwrap();
+        let id = |fs: &FileSystem, path: &str| fs.find(0, &split_path(path).1).unwrap();
+        let (dir_id, file_id) = (id(&fs, "a/d"), id(&fs, "a/d/f"));
+        fs.mv("a/d", "a/e", CopyOptions::default()).unwrap();
+        fs.mv("a/e", "b", CopyOptions::default()).unwrap();
+        fs.mv("b/e/f", "b/e/g", CopyOptions::default()).unwrap();
+        fs.mv("b", "/c", CopyOptions::default()).unwrap();
+        assert_eq!(id(&fs, "/c/e"), dir_id);
+        assert_eq!(id(&fs, "/c/e/g"), file_id);
+        // a save and reload keeps them too
+        let backup = TempFile::new("ids.fs");
+        fs.save(Some(backup.0.clone()), None, false).unwrap();
+        fs.reload(Some(backup.0.clone())).unwrap();
+        assert_eq!(id(&fs, "/c/e/g"), file_id);
+    }
+
+    #[test]
+    fn ls_sorts_entries_by_name() {
+        let mut fs = FileSystem::new();
+        fs.creat("b").unwrap();
+        fs.mkdir("c", None).unwrap();
+        fs.creat("a").unwrap();
+        let names: Vec<String> = fs
+            .ls(None)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert!(fs.ls(Some("a".to_string())).is_err());
+        match parse_command("ls -F c") {
+            Ok(Command::Ls(Some(path), options)) => {
+                assert_eq!(path, "c");
+                assert!(options.classify);
+            }
+            _ => panic!("ls -F c should parse"),
+        }
+        assert!(parse_command("ls -x").is_err());
+    }
+
+    // the paths a find command line prints
+    fn find(fs: &FileSystem, line: &str) -> Vec<String> {
+        match parse_command(line) {
+            Ok(Command::Find(start, query)) => fs.search(&start, &query).unwrap(),
+            _ => panic!("not a find command: {}", line),
+        }
+    }
+
+    #[test]
+    fn find_empty_matches_empty_files_and_directories() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("e", None).unwrap();
+        fs.mkdir("full", None).unwrap();
+        fs.creat("full/blank").unwrap();
+        assert_eq!(find(&fs, "find / -empty"), ["/e", "/full/blank"]);
+        assert_eq!(find(&fs, "find / -empty -type d"), ["/e"]);
+        assert_eq!(find(&fs, "find / -empty -type f"), ["/full/blank"]);
+        assert_eq!(find(&fs, "find / -empty -name full"), Vec::<String>::new());
+    }
+
+    // a small xorshift generator, so the random trees repeat from run to run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+
+        fn text(&mut self, alphabet: &[&str], max_len: usize) -> String {
+            (0..1 + self.below(max_len))
+                .map(|_| alphabet[self.below(alphabet.len())])
+                .collect()
+        }
+    }
+
+    // every node below start as its path relative to start and what a backup
+    // keeps of it: the kind, and the content or target; in path order
+    fn snapshot(fs: &FileSystem, start: &str) -> Vec<(String, String)> {
+        let start_id = fs.find(0, &split_path(start).1).unwrap();
+        let mut entries = Vec::new();
+        let mut stack = vec![(start_id, ".".to_string())];
+        while let Some((id, path)) = stack.pop() {
+            let kept = match &fs.nodes[&id].node_type {
+                NodeType::DIR { children } => {
+                    for (name, child_id) in children {
+                        stack.push((*child_id, format!("{}/{}", path, name)));
+                    }
+                    "dir".to_string()
+                }
+                NodeType::FILE { content } => format!("file {:?}", content),
+                NodeType::SYMLINK { target } => format!("link {:?}", target),
+            };
+            entries.push((path, kept));
+        }
+        entries.sort();
+        entries
+    }
+
+    fn tree_eq(a: &FileSystem, a_start: &str, b: &FileSystem, b_start: &str) -> bool {
+        snapshot(a, a_start) == snapshot(b, b_start)
+    }
+
+    // builds a random tree of about size nodes through the public operations
+    fn random_tree(rng: &mut Rng, size: usize) -> FileSystem {
+        const NAME: &[&str] = &[
+            "a", "b", "z", " ", ".", "-", "~", "\\", "\t", "é", "日", "%", "\n", "\r",
+        ];
+        const TEXT: &[&str] = &["x", " ", "\n", "\r\n", "\t", "C 1 2", "é", "\u{0}"];
+        let mut fs = FileSystem::new();
+        let mut dirs = vec!["/".to_string()];
+        for _ in 0..size {
+            let dir = &dirs[rng.below(dirs.len())];
+            let path = format!("{}/{}", dir.trim_end_matches('/'), rng.text(NAME, 4));
+            // names repeat, some come out as . or .. and some names and targets hold a
+            // line break, so some of these fail
+            let _ = match rng.below(4) {
+                0 => fs.mkdir(&path, None).map(|_| dirs.push(path.clone())),
+                1 => fs.creat(&path),
+                2 => fs
+                    .creat(&path)
+                    .and_then(|_| fs.write(&path, &rng.text(TEXT, 20))),
+                _ => fs.symlink(&rng.text(&["a", "/", "..", " ", ".", "\n"], 6), &path),
+            };
+        }
+        fs
+    }
+
+    #[test]
+    fn save_reload_round_trips_random_trees() {
+        let backup = TempFile::new("round-trip.fs");
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for size in [0, 1, 5, 20, 100, 400] {
+            let fs = random_tree(&mut rng, size);
+            fs.save(Some(backup.0.clone()), None, true).unwrap();
+            let mut reloaded = FileSystem::new();
+            reloaded.reload(Some(backup.0.clone())).unwrap();
+            assert!(tree_eq(&fs, "/", &reloaded, "/"), "tree of {} nodes", size);
+            assert_eq!(fs.counter, reloaded.counter);
+        }
+    }
+
+    #[test]
+    fn save_reload_keeps_spaces_around_names() {
+        let backup = TempFile::new("spaces.fs");
+        let mut fs = FileSystem::new();
+        for name in ["x ", " x", "  ", "a b", "x\t"] {
+            fs.mkdir(&format!("/{}", name), None).unwrap();
+        }
+        fs.save(Some(backup.0.clone()), None, false).unwrap();
+        let mut reloaded = FileSystem::new();
+        reloaded.reload(Some(backup.0.clone())).unwrap();
+        assert!(tree_eq(&fs, "/", &reloaded, "/"));
+        assert!(reloaded.find(0, &["x "]).is_ok());
+        // a line break would split the name's or the target's backup line
+        assert_eq!(fs.mkdir("/a\nb", None), Err("invalid name"));
+        assert_eq!(fs.symlink("a\rb", "/l"), Err("invalid symlink target"));
+    }
+
+    // a backup file holding text
+    fn backup_with(name: &str, text: &str) -> TempFile {
+        let backup = TempFile::new(name);
+        std::fs::write(&backup.0, text).unwrap();
+        backup
+    }
+
+    #[test]
+    fn reload_rejects_hand_broken_backups() {
+        for (text, err) in [
+            (
+                "FSBACKUP v2\n1 2\n0 /\n1 q\nD 0 7 1\nD 1 0\n",
+                "Error rebuilding the backup: root directory has a parent",
+            ),
+            (
+                "1 1\n1 q\nD 1 0\n",
+                "Error rebuilding the backup: missing root directory",
+            ),
+            (
+                "0 1\n0 /\nF 0 0\n",
+                "Error rebuilding the backup: missing root directory",
+            ),
+            (
+                "1 2\n0 /\n1 q\nD 0 0 1\nD 1 1\n",
+                "Error rebuilding the backup: inconsistent parent link",
+            ),
+            (
+                "1 3\n0 /\n1 q\n2 r\nD 0 0 1\nD 1 0\nD 1 0\n",
+                "Error rebuilding the backup: duplicate node",
+            ),
+            ("1 2\n0 /\n1 q\nD 0 0 1\n", "Error rebuilding the backup"),
+            (
+                "1 2\n0 /\n0 q\n",
+                "Error parsing the backup: duplicate id in index",
+            ),
+            (
+                "1 2\n0 /\n1 a/b\n",
+                "Error parsing the backup: invalid name in index",
+            ),
+            (
+                "0 1\n0 /\nD 0 0\nC 0 1\nx\n",
+                "Error rebuilding the backup: content for a non-file",
+            ),
+            (
+                "1 2\n0 /\n1 f\nD 0 0 1\nF 1 0\nC 1 5\nx\n",
+                "Error parsing the backup: truncated content",
+            ),
+            (
+                "1\n",
+                "Error parsing the backup: not two number on first line",
+            ),
+            ("hello\n", "Error parsing the backup: not a backup file"),
+        ] {
+            let backup = backup_with("broken.fs", text);
+            let mut fs = FileSystem::new();
+            script(&mut fs, "mkdir kept\ncd kept\n");
+            assert_eq!(
+                fs.reload(Some(backup.0.clone())).map(drop),
+                Err(err),
+                "{:?}",
+                text
+            );
+            // a failed reload leaves the tree it would have replaced
+            assert_eq!(fs.cwd_path(), "/kept");
+        }
+    }
+
+    #[test]
+    fn ls_inode_ids_match_stat() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("d", None).unwrap();
+        fs.mkdir("d/sub", None).unwrap();
+        fs.creat("d/f").unwrap();
+        fs.mv("d/f", "d/g", CopyOptions::default()).unwrap();
+        let entries = fs.ls(Some("d".to_string())).unwrap();
+        assert_eq!(entries.len(), 2);
+        // stat reports the id that find resolves the path to
+        for (name, id) in entries {
+            assert_eq!(fs.find(0, &["d", &name]), Ok(id));
+        }
+        match parse_command("ls -iF d") {
+            Ok(Command::Ls(_, options)) => assert!(options.inode && options.classify),
+            _ => panic!("ls -iF d should parse"),
+        }
+    }
+
+    #[test]
+    fn cat_files_and_directories() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("d", None).unwrap();
+        fs.write("f", "text\n").unwrap();
+        assert_eq!(fs.cat("f"), Ok("text\n"));
+        fs.write("f", "other\n").unwrap();
+        assert_eq!(fs.cat("f"), Ok("other\n"));
+        assert_eq!(fs.cat("d"), Err("Is a directory"));
+        assert_eq!(fs.cat("gone"), Err("No such file or directory"));
+        // write ends the text with a newline
+        match parse_command("write f two words") {
+            Ok(Command::Write(path, text)) => assert_eq!((&*path, &*text), ("f", "two words\n")),
+            _ => panic!("write f two words should parse"),
+        }
+    }
+
+    #[test]
+    fn find_empty_skips_files_with_content() {
+        let mut fs = FileSystem::new();
+        fs.creat("blank").unwrap();
+        fs.write("text", "x").unwrap();
+        assert_eq!(find(&fs, "find / -empty -type f"), ["/blank"]);
+    }
+
+    #[test]
+    fn lspaths_lists_a_known_tree() {
+        let mut fs = FileSystem::new();
+        for dir in ["b", "a", "a/c"] {
+            fs.mkdir(dir, None).unwrap();
+        }
+        fs.creat("a/c/f").unwrap();
+        fs.creat("z").unwrap();
+        fs.creat("b/l").unwrap();
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            ["/", "/a", "/a/c", "/a/c/f", "/b", "/b/l", "/z"]
+        );
+        assert_eq!(fs.lspaths("a", false).unwrap(), ["/a", "/a/c", "/a/c/f"]);
+        assert_eq!(fs.lspaths("/z", false).unwrap(), ["/z"]);
+        assert!(fs.lspaths("missing", false).is_err());
+    }
+
+    #[test]
+    fn failed_mv_leaves_the_tree_intact() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("d", None).unwrap();
+        fs.write("d/existing", "text\n").unwrap();
+        fs.mkdir("e", None).unwrap();
+        let before = snapshot(&fs, "/");
+        let counter = fs.counter;
+        for dest in [
+            "/nonexistent/dir/x",
+            "/d/existing/x",
+            "/d/existing/",
+            "/e/../nonexistent/x",
+        ] {
+            assert!(
+                fs.mv("/d/existing", dest, CopyOptions::default()).is_err(),
+                "{}",
+                dest
+            );
+            assert_eq!(snapshot(&fs, "/"), before, "{}", dest);
+        }
+        assert!(fs.mv("/d", "/d/x", CopyOptions::default()).is_err());
+        assert_eq!(snapshot(&fs, "/"), before);
+        assert_eq!(fs.cat("/d/existing"), Ok("text\n"));
+        assert_eq!(fs.counter, counter);
+        // nothing was recorded for undo either
+        assert!(fs.undo().is_err());
+    }
+
+    #[test]
+    fn root_in_each_surface() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("a", None).unwrap();
+        assert_eq!(fs.path_of(0), "/");
+        assert_eq!(
+            fs.ls(Some("/".to_string())).unwrap(),
+            [("a".to_string(), 1)]
+        );
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/a"]);
+        // the root stays put
+        assert_eq!(
+            fs.mv("/", "/a", CopyOptions::default()),
+            Err("cannot move root directory")
+        );
+        fs.cd(Some("/a".to_string()), false).unwrap();
+        fs.cd(Some("..".to_string()), false).unwrap();
+        fs.cd(Some("..".to_string()), false).unwrap();
+        assert_eq!(fs.path_of(fs.cwd), "/");
+    }
+
+    #[test]
+    fn cpcontent_overwrites_content_only() {
+        let mut fs = FileSystem::new();
+        set_clock(&mut fs, 100);
+        fs.write("src", "new\n").unwrap();
+        fs.write("dst", "old text\n").unwrap();
+        fs.mkdir("d", None).unwrap();
+        let dst_id = fs.find(0, &["dst"]).unwrap();
+        let counter = fs.counter;
+        set_clock(&mut fs, 200);
+        fs.cpcontent("src", "dst").unwrap();
+        assert_eq!(fs.cat("dst"), Ok("new\n"));
+        assert_eq!(fs.cat("src"), Ok("new\n"));
+        // the destination keeps its node, with a new mtime, and no node is
+        // created
+        assert_eq!(fs.find(0, &["dst"]), Ok(dst_id));
+        assert_eq!(fs.stat_format("dst", "%Y").unwrap(), "200");
+        assert_eq!(fs.counter, counter);
+        assert_eq!(fs.cpcontent("src", "d"), Err("Is a directory"));
+        assert_eq!(fs.cpcontent("d", "dst"), Err("Is a directory"));
+        assert!(fs.cpcontent("src", "missing").is_err());
+    }
+
+    #[test]
+    fn touch_no_create_skips_missing_files() {
+        let mut fs = FileSystem::new();
+        let no_create = TouchOptions {
+            no_create: true,
+            ..TouchOptions::default()
+        };
+        assert_eq!(fs.touch("nonexistent", no_create), Ok(()));
+        assert!(fs.find(0, &["nonexistent"]).is_err());
+        assert_eq!(fs.nodes.len(), 1);
+        // an existing file is still touched
+        fs.touch("f", TouchOptions::default()).unwrap();
+        let id = fs.find(0, &["f"]).unwrap();
+        fs.nodes.get_mut(&id).unwrap().mtime = UNIX_EPOCH;
+        fs.touch("f", no_create).unwrap();
+        assert!(fs.nodes[&id].mtime > UNIX_EPOCH);
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/f"]);
+    }
+
+    #[test]
+    fn content_changes_update_mtime() {
+        let mut fs = FileSystem::new();
+        fs.write("src", "new\n").unwrap();
+        fs.write("dst", "old\n").unwrap();
+        let id = fs.find(0, &["dst"]).unwrap();
+        for change in [
+            FileSystem::cpcontent,
+            |fs: &mut FileSystem, _: &str, dst: &str| fs.write(dst, "x\n"),
+        ] {
+            let node = fs.nodes.get_mut(&id).unwrap();
+            node.mtime = UNIX_EPOCH;
+            node.ctime = UNIX_EPOCH;
+            change(&mut fs, "src", "dst").unwrap();
+            assert!(fs.nodes[&id].mtime > UNIX_EPOCH);
+            assert!(fs.nodes[&id].ctime > UNIX_EPOCH);
+        }
+    }
+
+    #[test]
+    fn mkdir_mode_sets_the_new_directory_mode() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -m 700 secret\nmkdir plain\nmkdir -m 17777 bad\nmkdir -m 9 bad\n",
+        );
+        assert_eq!(fs.nodes[&fs.find(0, &["secret"]).unwrap()].mode, 0o700);
+        assert_eq!(fs.nodes[&fs.find(0, &["plain"]).unwrap()].mode, 0o755);
+        assert!(fs.find(0, &["bad"]).is_err());
+        assert!(parse_command("mkdir -m 8 x").is_err());
+        assert!(parse_command("mkdir -m").is_err());
+    }
+
+    #[test]
+    fn find_depth_bounds() {
+        let mut fs = FileSystem::new();
+        for dir in ["a", "a/b", "a/b/c"] {
+            fs.mkdir(dir, None).unwrap();
+        }
+        fs.creat("a/f").unwrap();
+        fs.creat("a/b/g").unwrap();
+        assert_eq!(find(&fs, "find a -maxdepth 0"), ["/a"]);
+        assert_eq!(find(&fs, "find a -maxdepth 1"), ["/a", "/a/b", "/a/f"]);
+        assert_eq!(find(&fs, "find a -mindepth 2"), ["/a/b/c", "/a/b/g"]);
+        assert_eq!(
+            find(&fs, "find a -mindepth 1 -maxdepth 1"),
+            ["/a/b", "/a/f"]
+        );
+        assert_eq!(
+            find(&fs, "find a -mindepth 2 -maxdepth 1"),
+            Vec::<String>::new()
+        );
+        assert_eq!(find(&fs, "find / -mindepth 3 -type f"), ["/a/b/g"]);
+        assert!(parse_command("find a -maxdepth x").is_err());
+    }
+
+    #[test]
+    fn cd_logical_and_physical_through_a_directory_symlink() {
+        let mut fs = FileSystem::new();
+        for dir in ["real", "real/sub", "other"] {
+            fs.mkdir(dir, None).unwrap();
+        }
+        fs.symlink("/real/sub", "other/link").unwrap();
+        let cd = |fs: &mut FileSystem, path: &str, physical| {
+            fs.cd(Some(path.to_string()), physical).unwrap();
+            fs.cwd_path()
+        };
+        // -L keeps the path as typed, and .. goes back along it
+        assert_eq!(cd(&mut fs, "other/link", false), "/other/link");
+        assert_eq!(cd(&mut fs, "..", false), "/other");
+        // -P resolves the link, and .. is the target's parent
+        assert_eq!(cd(&mut fs, "link", true), "/real/sub");
+        assert_eq!(cd(&mut fs, "..", true), "/real");
+        // a logical path that stops leading to cwd falls back to the real one
+        cd(&mut fs, "/other/link", false);
+        fs.mv("/other/link", "/other/moved", CopyOptions::default())
+            .unwrap();
+        assert_eq!(fs.cwd_path(), "/real/sub");
+        assert_eq!(
+            fs.cd(Some("/nowhere".to_string()), false),
+            Err("No such file or directory")
+        );
+    }
+
+    #[test]
+    fn cat_follows_symlinks() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("d", None).unwrap();
+        fs.write("f", "text\n").unwrap();
+        for (target, link) in [("f", "to-file"), ("d", "to-dir"), ("gone", "broken")] {
+            fs.symlink(target, link).unwrap();
+        }
+        assert_eq!(fs.cat("to-file"), Ok("text\n"));
+        assert_eq!(fs.cat("to-dir"), Err("Is a directory"));
+        assert_eq!(fs.cat("broken"), Err("No such file or directory"));
+    }
+
+    #[test]
+    fn rm_refuses_the_working_directory_and_its_ancestors() {
+        let mut fs = FileSystem::new();
+        for dir in ["a", "a/b", "a/b/c", "a/side"] {
+            fs.mkdir(dir, None).unwrap();
+        }
+        fs.cd(Some("a/b".to_string()), false).unwrap();
+        assert_eq!(fs.rm_recursive("/a/b"), Err("directory in use"));
+        assert_eq!(fs.rm_recursive("."), Err("directory in use"));
+        assert_eq!(fs.rm_recursive("/a"), Err("directory in use"));
+        assert_eq!(fs.rm_recursive(".."), Err("directory in use"));
+        // rmdir of an emptied cwd is refused too
+        fs.rmdir("c").unwrap();
+        assert_eq!(fs.rmdir("/a/b"), Err("directory in use"));
+        // anything off the path to cwd can still go
+        assert_eq!(fs.rm_recursive("/a/side"), Ok(vec!["/a/side".to_string()]));
+        assert_eq!(fs.cwd_path(), "/a/b");
+    }
+
+    #[test]
+    fn cp_recursive_copies_every_node_of_a_large_tree() {
+        let mut fs = FileSystem::new();
+        // a full tree of depth 4 and fan-out 6, 1555 nodes in all
+        let mut level = vec!["src".to_string()];
+        fs.mkdir("src", None).unwrap();
+        for depth in 0..4 {
+            let mut next = Vec::new();
+            for dir in &level {
+                for i in 0..6 {
+                    let path = format!("{}/{}", dir, i);
+                    if depth < 3 {
+                        fs.mkdir(&path, None).unwrap();
+                    } else {
+                        fs.creat(&path).unwrap();
+                    }
+                    next.push(path);
+                }
+            }
+            level = next;
+        }
+        let source_nodes = fs.walk(fs.find(0, &["src"]).unwrap(), None).count();
+        assert_eq!(source_nodes, 1555);
+        let options = CopyOptions {
+            recursive: true,
+            progress: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(fs.cp("src", "dst", options), Ok(source_nodes));
+        assert!(tree_eq(&fs, "/src", &fs, "/dst"));
+        assert_eq!(
+            fs.cp("src", "plain", CopyOptions::default()),
+            Err("-r not specified; omitting directory")
+        );
+    }
+
+    #[test]
+    fn is_valid_name_accepts_single_entry_names() {
+        for name in ["a", "...", ".a", "a.", " ", "a b", "é", "-"] {
+            assert!(is_valid_name(name), "{:?}", name);
+        }
+        for name in ["", ".", "..", "/", "a/b", "a/", "a\nb", "a\r"] {
+            assert!(!is_valid_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn every_command_rejects_an_invalid_name() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("d", None).unwrap();
+        fs.creat("f").unwrap();
+        let before = snapshot(&fs, "/");
+        let bad = "/d/a\nb";
+        assert_eq!(fs.mkdir(bad, None), Err("invalid name"));
+        assert_eq!(fs.mkdir_p(bad, None), Err("invalid name"));
+        assert_eq!(fs.creat(bad), Err("invalid name"));
+        assert_eq!(fs.write(bad, "x"), Err("invalid name"));
+        assert_eq!(fs.symlink("f", bad), Err("invalid name"));
+        assert_eq!(
+            fs.mv("/f", bad, CopyOptions::default()),
+            Err("invalid name")
+        );
+        assert_eq!(
+            fs.cp("/f", bad, CopyOptions::default()),
+            Err("invalid name")
+        );
+        assert_eq!(fs.cd_create(bad), Err("invalid name"));
+        assert_eq!(fs.mkdir("/d/..", None), Err("invalid name"));
+        assert_eq!(fs.creat("/d/."), Err("invalid name"));
+        assert_eq!(snapshot(&fs, "/"), before);
+    }
+
+    #[test]
+    fn reload_merge_grafts_a_backup_into_a_populated_tree() {
+        let backup = TempFile::new("merge.fs");
+        let mut small = FileSystem::new();
+        small.mkdir("p", None).unwrap();
+        small.mkdir("p/q", None).unwrap();
+        small.write("p/q/f", "backed up\n").unwrap();
+        small.symlink("p/q", "l").unwrap();
+        small.save(Some(backup.0.clone()), None, true).unwrap();
+
+        let mut fs = FileSystem::new();
+        fs.mkdir("live", None).unwrap();
+        fs.write("live/g", "kept\n").unwrap();
+        fs.mkdir("target", None).unwrap();
+        let counter = fs.counter;
+        fs.reload_merge(&backup.0, Some("target"), Overwrite::Refuse)
+            .unwrap();
+        assert_eq!(fs.cat("live/g"), Ok("kept\n"));
+        assert!(tree_eq(&small, "/", &fs, "/target"));
+        // the incoming nodes get fresh ids above the live ones
+        let p_id = fs.find(0, &["target", "p"]).unwrap();
+        assert!(p_id > counter);
+        assert_eq!(fs.nodes[&p_id].parent, fs.find(0, &["target"]).unwrap());
+
+        // a second merge collides, unless the clashing entries are skipped
+        let before = snapshot(&fs, "/");
+        assert_eq!(
+            fs.reload_merge(&backup.0, Some("target"), Overwrite::Refuse),
+            Err("File exists")
+        );
+        assert_eq!(snapshot(&fs, "/"), before);
+        fs.reload_merge(&backup.0, Some("target"), Overwrite::Skip)
+            .unwrap();
+        assert_eq!(snapshot(&fs, "/"), before);
+    }
+
+    #[test]
+    fn subtree_save_reloads_as_a_standalone_tree() {
+        let backup = TempFile::new("subtree.fs");
+        let mut fs = FileSystem::new();
+        for dir in ["other", "work", "work/project", "work/project/src"] {
+            fs.mkdir(dir, None).unwrap();
+        }
+        fs.write("work/project/src/main", "hi\n").unwrap();
+        fs.symlink("src/main", "work/project/entry").unwrap();
+        fs.creat("work/outside").unwrap();
+        fs.save(
+            Some(backup.0.clone()),
+            Some("work/project".to_string()),
+            true,
+        )
+        .unwrap();
+
+        let mut reloaded = FileSystem::new();
+        reloaded.reload(Some(backup.0.clone())).unwrap();
+        assert!(tree_eq(&fs, "/work/project", &reloaded, "/"));
+        assert_eq!(
+            reloaded.lspaths("/", false).unwrap(),
+            ["/", "/entry", "/src", "/src/main"]
+        );
+        // renumbered from 0 in walk order, so the ids are dense
+        assert_eq!(reloaded.counter, 3);
+        assert_eq!(reloaded.cat("entry"), Ok("hi\n"));
+    }
+
+    // runs lines as a sourced script would, without a prompt
+    fn script(fs: &mut FileSystem, lines: &str) {
+        run(fs, &mut lines.as_bytes(), false);
+    }
+
+    #[test]
+    fn purge_needs_f_in_a_script() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir a\nmkdir a/b\ncd a/b\npurge -f\n");
+        assert_eq!(fs.nodes.len(), 1);
+        assert_eq!(fs.cwd_path(), "/");
+        // without -f a script cannot confirm, so nothing is removed and the
+        // next line still runs
+        script(&mut fs, "mkdir c\npurge\nmkdir d\n");
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/c", "/d"]);
+    }
+
+    #[test]
+    fn source_reads_here_docs() {
+        let sourced = TempFile::new("heredoc.sh");
+        let lines = [
+            "cat > notes <<EOF",
+            "first line",
+            "  indented",
+            "",
+            "EOF not the end",
+            "EOF",
+            "mkdir after",
+            "cat > empty <<END",
+            "END",
+        ];
+        std::fs::write(&sourced.0, lines.join("\n") + "\n").unwrap();
+        let mut fs = FileSystem::new();
+        script(&mut fs, &format!("source {}\n", sourced.0));
+        assert_eq!(
+            fs.cat("notes"),
+            Ok("first line\n  indented\n\nEOF not the end\n")
+        );
+        assert_eq!(fs.cat("empty"), Ok(""));
+        // the lines after the delimiter run as commands again
+        assert!(fs.find(0, &["after"]).is_ok());
+    }
+
+    #[test]
+    fn mtime_follows_symlinks() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "touch a\nln -s a l\n");
+        let a = fs.find(0, &["a"]).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(100);
+        fs.nodes.get_mut(&a).unwrap().mtime = time;
+        assert_eq!(fs.mtime("a"), Ok(time));
+        assert_eq!(fs.mtime("l"), Ok(time));
+        assert_eq!(fs.mtime("missing"), Err("No such file or directory"));
+    }
+
+    #[test]
+    fn node_limit_is_enforced_by_every_creator() {
+        const FULL: &str = "No space left on device (inodes)";
+        let mut fs = FileSystem::new();
+        script(&mut fs, "set max-nodes 4\nmkdir a\ntouch a/f\n");
+        assert_eq!(fs.nodes.len(), 3);
+        fs.mkdir("b", None).unwrap();
+        assert_eq!(fs.mkdir("c", None), Err(FULL));
+        assert_eq!(fs.creat("c"), Err(FULL));
+        assert_eq!(fs.symlink("a", "c"), Err(FULL));
+        assert_eq!(fs.cp("a/f", "g", CopyOptions::default()), Err(FULL));
+        assert_eq!(fs.mkdir_p("x/y", None), Err(FULL));
+        assert_eq!(fs.nodes.len(), 4);
+        // freeing a node makes room again
+        fs.rm("a/f").unwrap();
+        fs.creat("c").unwrap();
+    }
+
+    #[test]
+    fn mv_destination_trailing_slash() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "touch a\ntouch f\nmkdir d\n");
+        // a trailing slash must name an existing directory
+        assert_eq!(
+            fs.mv("a", "missing/", CopyOptions::default()),
+            Err("Not a directory")
+        );
+        assert_eq!(
+            fs.mv("a", "f/", CopyOptions::default()),
+            Err("Not a directory")
+        );
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/a", "/d", "/f"]);
+        fs.mv("a", "d/", CopyOptions::default()).unwrap();
+        assert_eq!(fs.lspaths("/d", false).unwrap(), ["/d", "/d/a"]);
+        // without one, a missing name is a rename and a directory receives the source
+        fs.mv("d/a", "renamed", CopyOptions::default()).unwrap();
+        fs.mv("renamed", "d", CopyOptions::default()).unwrap();
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            ["/", "/d", "/d/renamed", "/f"]
+        );
+    }
+
+    #[test]
+    fn columnize_fits_width() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let five = names(&["a", "bb", "ccc", "dddd", "e"]);
+        // top to bottom, then left to right, padded to each column's width
+        assert_eq!(columnize(&five, 12), ["a   ccc   e", "bb  dddd"]);
+        assert_eq!(columnize(&five, 19), ["a  bb  ccc  dddd  e"]);
+        assert_eq!(columnize(&five, 4), ["a", "bb", "ccc", "dddd", "e"]);
+        assert!(columnize(&[], 80).is_empty());
+        // a name wider than the terminal puts everything in one column
+        assert_eq!(
+            columnize(&names(&["a", "toolongname"]), 5),
+            ["a", "toolongname"]
+        );
+        assert_eq!(columnize(&names(&["toolongname"]), 5), ["toolongname"]);
+    }
+
+    #[test]
+    fn find_delete_removes_only_matches() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir a\nmkdir a/tmp\nmkdir a/tmp/deep\n");
+        script(
+            &mut fs,
+            "touch a/x.tmp\ntouch a/keep\ntouch a/tmp/deep/y.tmp\n",
+        );
+        script(&mut fs, "touch a/tmp/z\nfind a -name *.tmp -delete\n");
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            ["/", "/a", "/a/keep", "/a/tmp", "/a/tmp/deep", "/a/tmp/z"]
+        );
+        // a match that can't go is handed back, and the others still go
+        script(&mut fs, "touch a/tmp/t1\n");
+        let (start, query) = find_query("find a -name t* -delete");
+        assert_eq!(
+            fs.find_delete(&start, &query),
+            Ok(vec![("/a/tmp".to_string(), "Directory not empty")])
+        );
+        assert!(fs.find(0, &["a", "tmp", "t1"]).is_err());
+        // a matching directory goes once it has been emptied, deepest first
+        script(&mut fs, "find a -name deep -delete\nfind a/tmp -delete\n");
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/a", "/a/keep"]);
+    }
+
+    #[test]
+    fn mkdir_p_and_cp_parents_create_intermediate_directories() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p a/b/c\nmkdir -p a/b/c\nmkdir -p a/./b/../x\n",
+        );
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            ["/", "/a", "/a/b", "/a/b/c", "/a/x"]
+        );
+        script(
+            &mut fs,
+            "touch a/b/c/f\nmkdir dest\ncp --parents a/b/c/f dest\n",
+        );
+        assert_eq!(
+            fs.lspaths("/dest", false).unwrap(),
+            [
+                "/dest",
+                "/dest/a",
+                "/dest/a/b",
+                "/dest/a/b/c",
+                "/dest/a/b/c/f"
+            ]
+        );
+        // the destination itself must already be a directory
+        let parents = CopyOptions {
+            parents: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(
+            fs.cp("a/b/c/f", "missing", parents),
+            Err("with --parents, the destination must be a directory")
+        );
+        assert_eq!(fs.mkdir_p("a/b/c/f/g", None), Err("Not a directory"));
+    }
+
+    #[test]
+    fn tree_shows_symlink_targets_inline() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p d/sub\ntouch d/f\nln -s f d/link\nln -s /nowhere d/sub/dangling\n",
+        );
+        assert_eq!(
+            fs.tree("d", TreeOptions::default()).unwrap(),
+            [
+                "d",
+                "├── f",
+                "├── link -> f",
+                "└── sub",
+                "    └── dangling -> /nowhere",
+                "",
+                "1 directory, 3 files",
+            ]
+        );
+    }
+
+    #[test]
+    fn stat_format_tokens() {
+        let mut fs = FileSystem::new();
+        set_clock(&mut fs, 1_000);
+        script(
+            &mut fs,
+            "mkdir -p d/a\nmkdir d/b\nwrite f hello\nln -s f l\n",
+        );
+        set_clock(&mut fs, 2_000);
+        script(&mut fs, "touch f\n");
+        let format = "%n|%F|%h|%U|%Y|%W|%s|%b|%%";
+        assert_eq!(
+            fs.stat_format("f", format).unwrap(),
+            "f|regular file|1|0|2000|1000|6|1|%"
+        );
+        assert_eq!(
+            fs.stat_format("d", format).unwrap(),
+            "d|directory|4|0|1000|1000|0|0|%"
+        );
+        assert_eq!(fs.stat_format("l", "%F %s").unwrap(), "symbolic link 1");
+        assert_eq!(fs.stat_format("f", "%q"), Err("unknown format token"));
+        assert_eq!(
+            fs.stat_format("f", "50%"),
+            Err("format ends with a lone '%'")
+        );
+    }
+
+    #[test]
+    fn very_deep_chains_fail_gracefully() {
+        const DEPTH: &str = "maximum directory depth exceeded";
+        let mut fs = FileSystem::new();
+        let chain = fs.counter + 1;
+        for _ in 0..DEFAULT_MAX_DEPTH {
+            fs.mkdir("d", None).unwrap();
+            fs.cd(Some("d".to_string()), true).unwrap();
+        }
+        assert_eq!(fs.mkdir("d", None), Err(DEPTH));
+        assert_eq!(fs.creat("f"), Err(DEPTH));
+        fs.cd(None, true).unwrap();
+        assert_eq!(fs.walk(0, None).count(), DEFAULT_MAX_DEPTH + 1);
+
+        // with a lower limit the existing chain is too deep to walk
+        fs.max_depth = 100;
+        let find_all = FindQuery::default();
+        let recursive = CopyOptions {
+            recursive: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(fs.tree("/", TreeOptions::default()), Err(DEPTH));
+        assert_eq!(fs.du("/", &DuOptions::default()), Err(DEPTH));
+        assert_eq!(fs.search_ids("/", &find_all), Err(DEPTH));
+        assert_eq!(fs.cp("/d", "/copy", recursive), Err(DEPTH));
+        assert!(fs.find(0, &["copy"]).is_err());
+        // removing it needs no depth, so it still works
+        assert_eq!(
+            fs.rm_recursive("/d").map(|removed| removed.len()),
+            Ok(DEFAULT_MAX_DEPTH)
+        );
+        assert!(!fs.nodes.contains_key(&chain));
+    }
+
+    #[test]
+    fn touch_a_and_m_set_only_their_time() {
+        // (atime, mtime) of the node at path, in seconds
+        fn times(fs: &FileSystem, path: &str) -> (u64, u64) {
+            let node = &fs.nodes[&fs.find(0, &[path]).unwrap()];
+            let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            (secs(node.atime), secs(node.mtime))
+        }
+        let mut fs = FileSystem::new();
+        set_clock(&mut fs, 100);
+        script(&mut fs, "touch f\n");
+        set_clock(&mut fs, 200);
+        script(&mut fs, "touch -a f\n");
+        assert_eq!(times(&fs, "f"), (200, 100));
+        set_clock(&mut fs, 300);
+        script(&mut fs, "touch -m f\n");
+        assert_eq!(times(&fs, "f"), (200, 300));
+        // neither flag, or both, sets both
+        set_clock(&mut fs, 400);
+        script(&mut fs, "touch f\n");
+        assert_eq!(times(&fs, "f"), (400, 400));
+        set_clock(&mut fs, 500);
+        script(&mut fs, "touch -a -m f\n");
+        assert_eq!(times(&fs, "f"), (500, 500));
+    }
+
+    #[test]
+    fn cp_onto_itself_is_refused() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir d\nwrite d/a hello\n");
+        let before = snapshot(&fs, "/");
+        for dest in ["d/a", "/d/a", "d/./a", "d/../d/a"] {
+            assert_eq!(fs.cp("d/a", dest, CopyOptions::default()), Err(SAME_FILE));
+        }
+        let recursive = CopyOptions {
+            recursive: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(
+            fs.cp("d", "/d", recursive),
+            Err("cannot copy a directory into itself")
+        );
+        assert_eq!(snapshot(&fs, "/"), before);
+        assert_eq!(fs.cat("d/a").unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn find_limit_stops_at_the_last_match() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir a\nmkdir b\ntouch a/x1\ntouch a/x2\ntouch b/x3\n",
+        );
+        assert_eq!(find(&fs, "find / -name x* --limit 2").len(), 2);
+        assert_eq!(find(&fs, "find / -name x* --limit 5").len(), 3);
+        assert_eq!(find(&fs, "find / -name x* --limit 0"), Vec::<String>::new());
+        // a walk that went on past the matches would reach b's too-deep chain
+        script(&mut fs, "mkdir -p b/c/d/e/f\n");
+        fs.max_depth = 3;
+        assert_eq!(find(&fs, "find / -name x* --limit 2"), ["/a/x1", "/a/x2"]);
+        let query = parse_command("find / -name x*").unwrap();
+        let Command::Find(start, query) = query else {
+            unreachable!()
+        };
+        assert_eq!(
+            fs.search(&start, &query),
+            Err("maximum directory depth exceeded")
+        );
+    }
+
+    // the fields of a flat JSON object, with strings unescaped and other
+    // values kept as written
+    fn json_fields(json: &str) -> Vec<(String, String)> {
+        let mut chars = json.trim().strip_prefix('{').unwrap().chars().peekable();
+        let string = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            assert_eq!(chars.next(), Some('"'));
+            let mut text = String::new();
+            loop {
+                match chars.next().unwrap() {
+                    '"' => return text,
+                    '\\' => match chars.next().unwrap() {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            text.push(
+                                char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap(),
+                            );
+                        }
+                        c => text.push(c),
+                    },
+                    c => text.push(c),
+                }
+            }
+        };
+        let mut fields = Vec::new();
+        loop {
+            let key = string(&mut chars);
+            assert_eq!(chars.next(), Some(':'));
+            while chars.next_if_eq(&' ').is_some() {}
+            let value = if chars.peek() == Some(&'"') {
+                string(&mut chars)
+            } else {
+                let mut raw = String::new();
+                while let Some(c) = chars.next_if(|c| *c != ',' && *c != '}') {
+                    raw.push(c);
+                }
+                raw
+            };
+            fields.push((key, value));
+            match chars.next() {
+                Some(',') => while chars.next_if_eq(&' ').is_some() {},
+                Some('}') => break,
+                other => panic!("unexpected {:?} in {}", other, json),
+            }
+        }
+        assert_eq!(chars.next(), None);
+        fields
+    }
+
+    #[test]
+    fn stat_json_carries_the_node_metadata() {
+        let mut fs = FileSystem::new();
+        set_clock(&mut fs, 1_000);
+        script(&mut fs, "mkdir d\nwrite \"d/say \\\"hi\\\"\\\\\" hello\n");
+        script(&mut fs, "ln -s \"/d/say \\\"hi\\\"\\\\\" d/link\n");
+        let id = fs.find(0, &["d", "say \"hi\"\\"]).unwrap();
+        let dir = fs.find(0, &["d"]).unwrap();
+        fs.nodes.get_mut(&id).unwrap().mode = 0o640;
+        let json = fs.stat_json("d/say \"hi\"\\").unwrap();
+        let fields = json_fields(&json);
+        let field = |name: &str| {
+            let found = fields.iter().find(|(key, _)| key == name);
+            found.map(|(_, value)| value.as_str()).unwrap()
+        };
+        assert_eq!(field("id"), id.to_string());
+        assert_eq!(field("name"), "say \"hi\"\\");
+        assert_eq!(field("path"), "/d/say \"hi\"\\");
+        assert_eq!(field("type"), "regular file");
+        assert_eq!(field("target"), "null");
+        assert_eq!(field("parent"), dir.to_string());
+        assert_eq!(field("size"), "6");
+        assert_eq!(field("mode"), "0640");
+        assert_eq!(field("links"), "1");
+        for time in ["atime", "mtime", "ctime"] {
+            assert_eq!(field(time), "1000.000000000");
+        }
+        let link = json_fields(&fs.stat_json("d/link").unwrap());
+        assert!(link.contains(&("type".to_string(), "symbolic link".to_string())));
+        assert!(link.contains(&("target".to_string(), "/d/say \"hi\"\\".to_string())));
+    }
+
+    // what a grep command line prints
+    fn grep(fs: &FileSystem, line: &str) -> Vec<String> {
+        match parse_command(line) {
+            Ok(Command::Grep(pattern, path, options)) => fs.grep(&pattern, &path, options).unwrap(),
+            _ => panic!("not a grep command: {}", line),
+        }
+    }
+
+    #[test]
+    fn grep_numbers_lines_in_recursive_searches() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir src\ncat > src/a <<END\nfn main\nlet x\nmain()\nEND\n\
+             cat > src/b <<END\nnothing\nmain\nEND\n",
+        );
+        assert_eq!(
+            grep(&fs, "grep -r main /src"),
+            ["/src/a:1:fn main", "/src/a:3:main()", "/src/b:2:main"]
+        );
+        assert_eq!(grep(&fs, "grep -rN main /src/b"), ["/src/b:main"]);
+        // a single file gets no path and, unless asked, no numbers
+        assert_eq!(grep(&fs, "grep main src/a"), ["fn main", "main()"]);
+        assert_eq!(grep(&fs, "grep -n main src/a"), ["1:fn main", "3:main()"]);
+        // -r with no path searches the working directory
+        fs.cd(Some("src".to_string()), false).unwrap();
+        assert_eq!(grep(&fs, "grep -r let"), ["/src/a:2:let x"]);
+    }
+
+    #[test]
+    fn cd_create_makes_the_path_and_enters_it() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "cd --create a/b/c\n");
+        assert_eq!(fs.cwd_path(), "/a/b/c");
+        assert_eq!(fs.cwd, fs.find(0, &["a", "b", "c"]).unwrap());
+        // existing directories are reused, relative paths start at cwd
+        script(&mut fs, "cd /\nmkcd a/b/d\n");
+        assert_eq!(fs.cwd_path(), "/a/b/d");
+        assert_eq!(
+            fs.lspaths("/a/b", false).unwrap(),
+            ["/a/b", "/a/b/c", "/a/b/d"]
+        );
+        // a file in the way is an error and leaves cwd alone
+        script(&mut fs, "touch f\n");
+        let nodes = fs.nodes.len();
+        assert_eq!(fs.cd_create("f/g"), Err("Not a directory"));
+        assert_eq!(fs.cd_create("f"), Err("Not a directory"));
+        assert_eq!(fs.cwd_path(), "/a/b/d");
+        assert_eq!(fs.nodes.len(), nodes);
+    }
+
+    #[test]
+    fn test_predicates_match_the_node_type_and_size() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir d\ntouch empty\nwrite full x\nln -s /full link\nln -s /nowhere dangling\n",
+        );
+        // which of -e, -f, -d, -s hold for each path
+        let cases = [
+            ("d", [true, false, true, false]),
+            ("empty", [true, true, false, false]),
+            ("full", [true, true, false, true]),
+            ("link", [true, true, false, true]),
+            ("dangling", [false, false, false, false]),
+            ("missing", [false, false, false, false]),
+            ("full/x", [false, false, false, false]),
+            ("/", [true, false, true, false]),
+        ];
+        for (path, expected) in cases {
+            for (flag, expected) in ["-e", "-f", "-d", "-s"].iter().zip(expected) {
+                for line in [
+                    format!("test {} {}", flag, path),
+                    format!("[ {} {} ]", flag, path),
+                ] {
+                    let Ok(Command::Test(predicate, path)) = parse_command(&line) else {
+                        panic!("not a test command: {}", line)
+                    };
+                    assert_eq!(fs.test(predicate, &path), expected, "{}", line);
+                }
+            }
+        }
+        assert!(matches!(parse_command("[ -e d"), Err("[: missing ]")));
+        assert!(matches!(
+            parse_command("test -x d"),
+            Err("test: unknown predicate")
+        ));
+        assert!(matches!(parse_command("test -e"), Err("missing operand")));
+    }
+
+    #[test]
+    fn save_content_flag_decides_whether_content_survives() {
+        let backup = TempFile::new("content.fs");
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir d\ntouch d/empty\nln -s d/text link\n");
+        // content that looks like backup lines, or has no final newline
+        let text = "C 1 5\n0 dir / 0\n\n\r\ttabs and no newline";
+        fs.creat("/d/text").unwrap();
+        fs.write("/d/text", text).unwrap();
+        fs.creat("/d/one").unwrap();
+        fs.write("/d/one", "x").unwrap();
+
+        let mut reloaded = FileSystem::new();
+        script(&mut fs, &format!("save --content {}\n", backup.0));
+        reloaded.reload(Some(backup.0.clone())).unwrap();
+        assert!(tree_eq(&fs, "/", &reloaded, "/"));
+        assert_eq!(reloaded.cat("/d/text").unwrap(), text);
+
+        // without the flag the structure comes back with every file empty
+        script(&mut fs, &format!("save {}\n", backup.0));
+        reloaded.reload(Some(backup.0.clone())).unwrap();
+        let emptied = snapshot(&fs, "/")
+            .into_iter()
+            .map(|(path, kind)| {
+                let kind = if kind.starts_with("file") {
+                    format!("file {:?}", "")
+                } else {
+                    kind
+                };
+                (path, kind)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(snapshot(&reloaded, "/"), emptied);
+        assert_eq!(reloaded.cat("/d/one").unwrap(), "");
+    }
+
+    #[test]
+    fn mv_merges_into_an_existing_directory() {
+        let setup = "mkdir -p s/sub/deep\nmkdir -p dest/s/sub\nmkdir dest/s/kept\n\
+                     write s/new n\nwrite s/clash src\nwrite s/sub/deep/f f\n\
+                     write dest/s/clash dest\nwrite dest/s/sub/old o\n";
+        let mut fs = FileSystem::new();
+        script(&mut fs, setup);
+        let moved = fs.find(0, &["s", "sub", "deep", "f"]).unwrap();
+        // the file-vs-file clash is refused and stays behind with its directory
+        assert_eq!(
+            fs.mv("s", "dest", CopyOptions::default()),
+            Err("some entries were left in place")
+        );
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            [
+                "/",
+                "/dest",
+                "/dest/s",
+                "/dest/s/clash",
+                "/dest/s/kept",
+                "/dest/s/new",
+                "/dest/s/sub",
+                "/dest/s/sub/deep",
+                "/dest/s/sub/deep/f",
+                "/dest/s/sub/old",
+                "/s",
+                "/s/clash",
+            ]
+        );
+        assert_eq!(fs.find(0, &["dest", "s", "sub", "deep", "f"]), Ok(moved));
+        assert_eq!(fs.cat("/dest/s/clash").unwrap(), "dest\n");
+        assert_eq!(fs.cat("/s/clash").unwrap(), "src\n");
+
+        // with -f the clash is overwritten and the emptied source goes away
+        let mut fs = FileSystem::new();
+        script(&mut fs, &format!("{}mv -f s dest\n", setup));
+        assert!(fs.find(0, &["s"]).is_err());
+        assert_eq!(fs.cat("/dest/s/clash").unwrap(), "src\n");
+        assert_eq!(fs.cat("/dest/s/sub/old").unwrap(), "o\n");
+    }
+
+    #[test]
+    fn mv_keeps_the_node_and_its_link_count() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir a\nmkdir b\nmkdir a/sub\nwrite a/f shared\n");
+        let file = fs.find(0, &["a", "f"]).unwrap();
+        let (a, b) = (fs.find(0, &["a"]).unwrap(), fs.find(0, &["b"]).unwrap());
+        assert_eq!(fs.link_count(file), 1);
+        script(&mut fs, "mv a/f b/g\n");
+        // the entry moved; the node, its content and its one link did not change
+        assert_eq!(fs.find(0, &["b", "g"]), Ok(file));
+        assert_eq!(fs.cat("b/g").unwrap(), "shared\n");
+        assert_eq!(fs.link_count(file), 1);
+        assert_eq!(
+            fs.stat_format("b/g", "%h %i").unwrap(),
+            format!("1 {}", file)
+        );
+        // a moved directory takes its ".." link from one parent to the other
+        assert_eq!((fs.link_count(a), fs.link_count(b)), (3, 2));
+        script(&mut fs, "mv a/sub b\n");
+        assert_eq!((fs.link_count(a), fs.link_count(b)), (2, 3));
+    }
+
+    #[test]
+    fn render_prompt_expands_its_tokens() {
+        assert_eq!(render_prompt("$ ", "/a", "sim", 3), "$ ");
+        assert_eq!(render_prompt("{cwd} $ ", "/a/b", "sim", 3), "/a/b $ ");
+        assert_eq!(
+            render_prompt("[{count}] {user}:{cwd}> ", "/", "sim", 12),
+            "[12] sim:/> "
+        );
+        // tokens may repeat, and unknown or unclosed ones are printed as is
+        assert_eq!(
+            render_prompt("{cwd}{cwd} {host} {cwd", "/x", "u", 1),
+            "/x/x {host} {cwd"
+        );
+    }
+
+    #[test]
+    fn complete_lists_matching_entries_of_the_partial_parent() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p src/bin\nmkdir scripts\ntouch src/main.rs\ntouch src/mod.rs\n\
+             touch setup\nln -s /src srclink\nln -s /setup setlink\n",
+        );
+        let complete = |partial: &str| fs.complete(partial).unwrap();
+        assert_eq!(
+            complete("s"),
+            ["scripts/", "setlink", "setup", "src/", "srclink/"]
+        );
+        assert_eq!(complete("sr"), ["src/", "srclink/"]);
+        assert_eq!(complete("src/m"), ["src/main.rs", "src/mod.rs"]);
+        assert_eq!(
+            complete("/src/"),
+            ["/src/bin/", "/src/main.rs", "/src/mod.rs"]
+        );
+        // the parent is resolved through symlinks
+        assert_eq!(complete("srclink/b"), ["srclink/bin/"]);
+        assert_eq!(complete("src/x"), Vec::<String>::new());
+        assert_eq!(fs.complete("setup/x"), Err("Not a directory"));
+        assert_eq!(fs.complete("nope/x"), Err("No such file or directory"));
+    }
+
+    #[test]
+    fn file_kind_tells_text_from_data() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir d\ntouch empty\nln -s empty link\n");
+        for (name, content) in [
+            ("text", "plain words\n\tand tabs\r\n"),
+            ("nul", "a\0b"),
+            ("escape", "\x1b[0m"),
+            ("utf8", "café\n"),
+            ("space", " "),
+        ] {
+            fs.creat(name).unwrap();
+            fs.write(name, content).unwrap();
+        }
+        let kind = |path: &str| fs.file_kind(path).unwrap();
+        assert_eq!(kind("d"), "directory");
+        assert_eq!(kind("/"), "directory");
+        assert_eq!(kind("empty"), "empty");
+        assert_eq!(kind("text"), "ASCII text");
+        assert_eq!(kind("space"), "ASCII text");
+        assert_eq!(kind("nul"), "data");
+        assert_eq!(kind("escape"), "data");
+        assert_eq!(kind("utf8"), "data");
+        // a symlink is reported, not followed
+        assert_eq!(kind("link"), "symbolic link to empty");
+        assert_eq!(fs.file_kind("missing"), Err("No such file or directory"));
+    }
+
+    #[test]
+    fn split_path_classifies_absolute_and_relative_paths() {
+        let cases: [(&str, bool, &[&str]); 10] = [
+            ("/", true, &[]),
+            ("//", true, &[]),
+            ("", false, &[]),
+            ("a", false, &["a"]),
+            ("a/b/", false, &["a", "b"]),
+            ("./a", false, &[".", "a"]),
+            ("/a/b", true, &["a", "b"]),
+            ("//double//slash", true, &["double", "slash"]),
+            ("a//b", false, &["a", "b"]),
+            (" /a", false, &[" ", "a"]),
+        ];
+        for (path, absolute, components) in cases {
+            assert_eq!(
+                split_path(path),
+                (absolute, components.to_vec()),
+                "{:?}",
+                path
+            );
+        }
+        // the flag picks the start of the lookup
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir -p a/a\ncd a\n");
+        let inner = fs.find(0, &["a", "a"]).unwrap();
+        assert_eq!(fs.stat_format("//a", "%i").unwrap(), fs.cwd.to_string());
+        assert_eq!(fs.stat_format("a", "%i").unwrap(), inner.to_string());
+    }
+
+    #[test]
+    fn backup_flag_keeps_the_overwritten_file() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "write a new\nwrite b old\ncp -b a b\n");
+        assert_eq!(fs.cat("b").unwrap(), "new\n");
+        assert_eq!(fs.cat("b~").unwrap(), "old\n");
+        // a taken backup name gets another ~
+        script(&mut fs, "write a newer\ncp -b a b\n");
+        assert_eq!(fs.cat("b").unwrap(), "newer\n");
+        assert_eq!(fs.cat("b~").unwrap(), "old\n");
+        assert_eq!(fs.cat("b~~").unwrap(), "new\n");
+        // mv keeps the old node, id and all, under the backup name
+        let old = fs.find(0, &["b"]).unwrap();
+        script(&mut fs, "write c moved\nmv -b c b\n");
+        assert_eq!(fs.cat("b").unwrap(), "moved\n");
+        assert_eq!(fs.find(0, &["b~~~"]), Ok(old));
+        assert!(fs.find(0, &["c"]).is_err());
+        // nothing to overwrite, nothing to back up
+        script(&mut fs, "cp -b a fresh\n");
+        assert!(fs.find(0, &["fresh~"]).is_err());
+        // without -b the old content is gone
+        script(&mut fs, "cp -f a b\n");
+        assert_eq!(fs.lspaths("/", false).unwrap().len(), 7);
+    }
+
+    #[test]
+    fn gentree_creates_a_balanced_tree() {
+        for (breadth, depth) in [(1usize, 1usize), (2, 3), (3, 2), (4, 3), (5, 0), (0, 4)] {
+            let mut fs = FileSystem::new();
+            let expected = (1..=depth as u32)
+                .map(|level| breadth.pow(level))
+                .sum::<usize>();
+            assert_eq!(
+                fs.gentree("/g", breadth, depth),
+                Ok(expected),
+                "{}x{}",
+                breadth,
+                depth
+            );
+            // the created nodes plus the root and /g itself
+            assert_eq!(fs.nodes.len(), expected + 2);
+            let files = fs
+                .walk(0, None)
+                .filter(|(id, _)| fs.nodes[id].is_file_node());
+            let leaves = if depth == 0 {
+                0
+            } else {
+                breadth.pow(depth as u32)
+            };
+            assert_eq!(files.count(), leaves);
+            let deepest = fs.walk(0, None).map(|(_, level)| level).max().unwrap();
+            assert_eq!(deepest, if leaves == 0 { 1 } else { depth + 1 });
+        }
+        let mut fs = FileSystem::new();
+        fs.gentree("/g", 2, 2).unwrap();
+        assert_eq!(
+            fs.lspaths("/g", false).unwrap(),
+            ["/g", "/g/d0", "/g/d0/f0", "/g/d0/f1", "/g/d1", "/g/d1/f0", "/g/d1/f1"]
+        );
+        // a second run into the same directory collides with the first
+        assert_eq!(fs.gentree("/g", 2, 2), Err("Directory already exists"));
+    }
+
+    // the query of a find command line
+    fn find_query(line: &str) -> (String, FindQuery) {
+        match parse_command(line) {
+            Ok(Command::Find(start, query)) => (start, query),
+            _ => panic!("not a find command: {}", line),
+        }
+    }
+
+    #[test]
+    fn search_ids_finds_the_nodes_search_prints() {
+        let mut fs = FileSystem::new();
+        fs.gentree("/g", 3, 3).unwrap();
+        script(&mut fs, "write /g/d1/d2/f0 x\n");
+        for line in [
+            "find /",
+            "find /g -name f1",
+            "find /g -type d",
+            "find /g/d1 -mindepth 1 -maxdepth 1",
+            "find / -name f* --limit 4",
+            "find /g -empty -name d*",
+        ] {
+            let (start, query) = find_query(line);
+            let ids = fs.search_ids(&start, &query).unwrap();
+            let paths = ids
+                .iter()
+                .map(|id| fs.path_of(*id))
+                .collect::<Vec<String>>();
+            assert_eq!(paths, fs.search(&start, &query).unwrap(), "{}", line);
+        }
+        // every file, and nothing else, for an unrestricted -type f
+        let (start, query) = find_query("find / -type f");
+        let mut ids = fs.search_ids(&start, &query).unwrap();
+        ids.sort_unstable();
+        let files = fs.nodes.iter().filter(|(_, node)| node.is_file_node());
+        let mut expected = files.map(|(id, _)| *id).collect::<Vec<usize>>();
+        expected.sort_unstable();
+        assert_eq!(ids, expected);
+        assert_eq!(ids.len(), 27);
+    }
+
+    // runs f the given number of times and prints the mean time per run
+    fn bench<T>(label: &str, runs: u32, mut f: impl FnMut() -> T) -> Duration {
+        let start = Instant::now();
+        for _ in 0..runs {
+            std::hint::black_box(f());
+        }
+        let mean = start.elapsed() / runs;
+        println!("{}: {:?} per run", label, mean);
+        mean
+    }
+
+    // cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_search_ids_against_search() {
+        let mut fs = FileSystem::new();
+        fs.gentree("/g", 8, 5).unwrap();
+        let (start, query) = find_query("find / -name f*");
+        assert_eq!(fs.search_ids(&start, &query).unwrap().len(), 8usize.pow(5));
+        let ids = bench("search_ids", 20, || fs.search_ids(&start, &query).unwrap());
+        let paths = bench("search", 20, || fs.search(&start, &query).unwrap());
+        assert!(ids < paths, "ids {:?}, paths {:?}", ids, paths);
+    }
+
+    #[test]
+    fn path_cache_never_serves_removed_nodes() {
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir -p /a/b\ntouch /a/b/f\nln -s /a/b /link\n");
+        let lookup = |fs: &FileSystem, path: &str| fs.find_follow(0, &split_path(path).1);
+        let old = lookup(&fs, "/a/b/f").unwrap();
+        let counters = |fs: &FileSystem| {
+            let cache = fs.path_cache.borrow();
+            (cache.hits, cache.misses)
+        };
+        let (hits, misses) = counters(&fs);
+        assert_eq!(lookup(&fs, "/a/b/f"), Ok(old));
+        assert_eq!(counters(&fs), (hits + 1, misses));
+
+        // removed, then recreated under the same path as a new node
+        script(&mut fs, "rm /a/b/f\n");
+        assert!(lookup(&fs, "/a/b/f").is_err());
+        script(&mut fs, "touch /a/b/f\n");
+        let new = lookup(&fs, "/a/b/f").unwrap();
+        assert_ne!(new, old);
+        assert_eq!(lookup(&fs, "/link/f"), Ok(new));
+
+        // a removed directory takes the paths through it along
+        script(&mut fs, "rm -r /a\nmkdir -p /a/b\ntouch /a/b/f\n");
+        let newer = lookup(&fs, "/a/b/f").unwrap();
+        assert_ne!(newer, new);
+        assert_eq!(lookup(&fs, "/link/f"), Ok(newer));
+        script(&mut fs, "rmdir /a/b\n");
+        assert!(lookup(&fs, "/a/b").is_ok());
+        script(&mut fs, "rm /a/b/f\nrmdir /a/b\n");
+        assert!(lookup(&fs, "/a/b").is_err());
+        assert!(lookup(&fs, "/link/f").is_err());
+
+        // and so does a moved one
+        script(&mut fs, "mkdir /a/b\ntouch /a/b/f\n");
+        let moved = lookup(&fs, "/a/b/f").unwrap();
+        script(&mut fs, "mv /a /c\n");
+        assert!(lookup(&fs, "/a/b/f").is_err());
+        assert_eq!(lookup(&fs, "/c/b/f"), Ok(moved));
+    }
+
+    #[test]
+    fn path_cache_agrees_with_an_uncached_filesystem() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut cached = FileSystem::new();
+        let mut uncached = FileSystem::new();
+        uncached.path_cache.borrow_mut().set_capacity(0);
+        cached.path_cache.borrow_mut().set_capacity(8);
+        let names = ["a", "b", "c"];
+        for _ in 0..2000 {
+            let path = (0..1 + rng.below(3))
+                .map(|_| names[rng.below(names.len())])
+                .collect::<Vec<&str>>()
+                .join("/");
+            let line = match rng.below(6) {
+                0 => format!("mkdir /{}", path),
+                1 => format!("touch /{}", path),
+                2 => format!("rm -r /{}", path),
+                3 => format!("mv /{} /{}", path, names[rng.below(names.len())]),
+                4 => format!("ln -s /{} /{}", path, names[rng.below(names.len())]),
+                _ => "cd /".to_string(),
+            };
+            for fs in [&mut cached, &mut uncached] {
+                let _ = run(fs, &mut format!("{}\n", line).as_bytes(), false);
+            }
+            let lookup = |fs: &FileSystem| fs.find_follow(0, &split_path(&path).1);
+            assert_eq!(lookup(&cached), lookup(&uncached), "after {}", line);
+        }
+        assert!(cached.path_cache.borrow().hits > 0);
+    }
+
+    // the lines of a backup file, with each directory's children, which are
+    // written in no particular order, sorted
+    fn backup_lines(backup: &TempFile) -> Vec<String> {
+        let text = std::fs::read_to_string(&backup.0).unwrap();
+        let lines = text.lines().map(|line| match line.rsplit_once(' ') {
+            Some((head, children)) if line.starts_with("D ") => {
+                let mut children = children.split(',').collect::<Vec<&str>>();
+                children.sort_unstable();
+                format!("{} {}", head, children.join(","))
+            }
+            _ => line.to_string(),
+        });
+        lines.collect()
+    }
+
+    #[test]
+    fn reload_round_trips_a_generated_tree() {
+        let backup = TempFile::new("gentree.fs");
+        let resaved = TempFile::new("gentree-resaved.fs");
+        let mut fs = FileSystem::new();
+        fs.gentree("/g", 6, 4).unwrap();
+        script(
+            &mut fs,
+            "write /g/d0/d1/d2/f3 text\nln -s /g/d5 /g/d0/up\nrm -r /g/d3\n",
+        );
+        fs.save(Some(backup.0.clone()), None, true).unwrap();
+        let mut reloaded = FileSystem::new();
+        let counts = reloaded.reload(Some(backup.0.clone())).unwrap();
+        let total = counts.directories + counts.files + counts.symlinks;
+        assert_eq!(total, fs.nodes.len());
+        assert!(tree_eq(&fs, "/", &reloaded, "/"));
+        assert_eq!(reloaded.counter, fs.counter);
+        // ids survive, so the backup written back out has the same lines, up
+        // to the order a directory's children are listed in
+        reloaded.save(Some(resaved.0.clone()), None, true).unwrap();
+        assert_eq!(backup_lines(&resaved), backup_lines(&backup));
+        // and new nodes carry on numbering after the reloaded ones
+        script(&mut reloaded, "touch /new\n");
+        assert_eq!(reloaded.find(0, &["new"]), Ok(fs.counter + 1));
+    }
+
+    // cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_reload_a_large_backup() {
+        let backup = TempFile::new("bench-reload.fs");
+        let mut fs = FileSystem::new();
+        assert_eq!(fs.gentree("/g", 10, 5), Ok(111_110));
+        fs.save(Some(backup.0.clone()), None, false).unwrap();
+        let mut reloaded = FileSystem::new();
+        bench("reload of 111,112 nodes", 5, || {
+            reloaded.reload(Some(backup.0.clone())).unwrap()
+        });
+        assert_eq!(reloaded.nodes.len(), 111_112);
+    }
+
+    #[test]
+    fn save_format_is_unchanged() {
+        let backup = TempFile::new("format.fs");
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir d\nwrite d/f hi\ntouch gone\ntouch e\nln -s /d l\nmkdir d/s\nrm gone\n",
+        );
+        fs.save(Some(backup.0.clone()), None, false).unwrap();
+        let structure = [
+            "FSBACKUP v2",
+            "6 6",
+            "0 /",
+            "1 d",
+            "2 f",
+            "4 e",
+            "5 l",
+            "6 s",
+            "D 0 0 1,4,5",
+            "D 1 0 2,6",
+            "F 2 1",
+            "F 4 0",
+            "S 5 0 /d",
+            "D 6 1 ",
+        ];
+        assert_eq!(backup_lines(&backup), structure);
+        // content follows the node lines, one block per non-empty file
+        fs.save(Some(backup.0.clone()), None, true).unwrap();
+        let content = ["C 2 3", "hi", ""];
+        assert_eq!(
+            backup_lines(&backup),
+            [&structure[..], &content[..]].concat()
+        );
+        // a subtree is renumbered from 0 in walk order
+        fs.save(Some(backup.0.clone()), Some("/d".to_string()), true)
+            .unwrap();
+        assert_eq!(
+            backup_lines(&backup),
+            [
+                "FSBACKUP v2",
+                "2 3",
+                "0 /",
+                "1 f",
+                "2 s",
+                "D 0 0 1,2",
+                "F 1 0",
+                "D 2 0 ",
+                "C 1 3",
+                "hi",
+                "",
+            ]
+        );
+    }
+
+    // cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_save_a_large_tree() {
+        let backup = TempFile::new("bench-save.fs");
+        let mut fs = FileSystem::new();
+        assert_eq!(fs.gentree("/g", 10, 5), Ok(111_110));
+        bench("save of 111,112 nodes", 5, || {
+            fs.save(Some(backup.0.clone()), None, false).unwrap()
+        });
+        bench("subtree save of 111,111 nodes", 5, || {
+            fs.save(Some(backup.0.clone()), Some("/g".to_string()), false)
+                .unwrap()
+        });
+    }
+
+    #[test]
+    fn paginate_chunks_by_page_size() {
+        let lines = (1..=7).map(|n| n.to_string()).collect::<Vec<_>>();
+        let page = |answers: &str| {
+            let mut output = Vec::new();
+            paginate(&lines, 3, &mut answers.as_bytes(), &mut output);
+            String::from_utf8(output).unwrap()
+        };
+        // a prompt before the second and third pages, none after the last
+        assert_eq!(page("\n\n"), "1\n2\n3\n--More--4\n5\n6\n--More--7\n");
+        assert_eq!(page("\nq\n"), "1\n2\n3\n--More--4\n5\n6\n--More--");
+        assert_eq!(page("q\n"), "1\n2\n3\n--More--");
+        // a listing that fits one page, or none, never prompts
+        let mut output = Vec::new();
+        paginate(&lines[..3], 3, &mut "".as_bytes(), &mut output);
+        assert_eq!(output, b"1\n2\n3\n");
+        let mut output = Vec::new();
+        paginate(&[], 3, &mut "".as_bytes(), &mut output);
+        assert!(output.is_empty());
+    }
+
+    // fixes the clock at secs after the epoch
+    fn set_clock(fs: &mut FileSystem, secs: u64) {
+        fs.clock = Clock::Fixed(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    #[test]
+    fn fixed_clock_gives_exact_timestamps() {
+        let mut fs = FileSystem::new();
+        let times = |fs: &FileSystem, path: &str| {
+            json_fields(&fs.stat_json(path).unwrap())
+                .into_iter()
+                .filter(|(key, _)| key.ends_with("time"))
+                .map(|(_, value)| value)
+                .collect::<Vec<String>>()
+        };
+        script(
+            &mut fs,
+            "set clock 1000000000\nmkdir d\nwrite d/old hi\ntouch d/new\n\
+             set clock 1000000060\nwrite d/old more\n",
+        );
+        assert_eq!(
+            times(&fs, "d/old"),
+            [
+                "1000000000.000000000",
+                "1000000060.000000000",
+                "1000000060.000000000",
+                "1000000000.000000000"
+            ]
+        );
+        // reads leave atime alone; a rename changes only ctime
+        script(
+            &mut fs,
+            "set clock 1000000120\ncat d/old\nmv d/old d/renamed\n",
+        );
+        assert_eq!(
+            times(&fs, "d/renamed"),
+            [
+                "1000000000.000000000",
+                "1000000060.000000000",
+                "1000000120.000000000",
+                "1000000000.000000000"
+            ]
+        );
+        assert_eq!(fs.stat_format("d/new", "%Y").unwrap(), "1000000000");
+        // with the system clock back, new nodes get the real time
+        script(&mut fs, "set clock system\ntouch d/now\n");
+        let now = fs
+            .stat_format("d/now", "%Y")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        let real = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now.abs_diff(real) < 60);
+    }
+
+    #[test]
+    fn rm_recursive_returns_the_removed_subtree() {
+        let mut fs = FileSystem::new();
+        fs.gentree("/t/g", 3, 2).unwrap();
+        script(
+            &mut fs,
+            "mkdir /keep\ntouch /keep/f\nln -s /keep /t/g/link\n",
+        );
+        let mut subtree = fs.lspaths("/t/g", false).unwrap();
+        let removed = fs.rm_recursive("t/g").unwrap();
+        // everything below /t/g, the symlink but not its target, deepest first
+        let mut sorted = removed.clone();
+        sorted.sort();
+        subtree.sort();
+        assert_eq!(sorted, subtree);
+        assert_eq!(removed.len(), 3 + 9 + 2);
+        assert_eq!(removed.last().unwrap(), "/t/g");
+        let position = |path: &str| removed.iter().position(|p| p == path).unwrap();
+        assert!(position("/t/g/d1/f2") < position("/t/g/d1"));
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            ["/", "/keep", "/keep/f", "/t"]
+        );
+        // a single file or an empty directory is its own list
+        assert_eq!(fs.rm_recursive("/keep/f"), Ok(vec!["/keep/f".to_string()]));
+        assert_eq!(fs.rmdir("/keep"), Ok(vec!["/keep".to_string()]));
+        assert_eq!(
+            fs.rm_recursive("/missing"),
+            Err("No such file or directory")
+        );
+    }
+
+    #[test]
+    fn cp_r_copies_into_an_existing_directory_or_as_a_new_name() {
+        let recursive = CopyOptions {
+            recursive: true,
+            ..CopyOptions::default()
+        };
+        let setup = "mkdir -p src/sub\nwrite src/sub/f hi\nmkdir dest\n";
+        let mut fs = FileSystem::new();
+        script(&mut fs, setup);
+        // dest exists: src goes inside it
+        assert_eq!(fs.cp("src", "dest", recursive), Ok(3));
+        // new doesn't: src is copied as new
+        assert_eq!(fs.cp("src", "new", recursive), Ok(3));
+        assert_eq!(
+            fs.lspaths("/", false).unwrap(),
+            [
+                "/",
+                "/dest",
+                "/dest/src",
+                "/dest/src/sub",
+                "/dest/src/sub/f",
+                "/new",
+                "/new/sub",
+                "/new/sub/f",
+                "/src",
+                "/src/sub",
+                "/src/sub/f",
+            ]
+        );
+        assert_eq!(fs.cat("dest/src/sub/f").unwrap(), "hi\n");
+        // the copies are new nodes
+        assert_ne!(
+            fs.find(0, &["new", "sub", "f"]),
+            fs.find(0, &["src", "sub", "f"])
+        );
+        // a second copy into dest merges with dest/src, refusing the clash
+        // with the file already there but copying the rest
+        script(&mut fs, "write src/g more\nwrite src/sub/f changed\n");
+        assert_eq!(fs.cp("src", "dest", recursive), Err(SOME_NOT_COPIED));
+        assert_eq!(fs.cat("dest/src/g").unwrap(), "more\n");
+        assert_eq!(fs.cat("dest/src/sub/f").unwrap(), "hi\n");
+        // without -r a directory isn't copied
+        assert!(fs.cp("src", "plain", CopyOptions::default()).is_err());
+        assert!(fs.find(0, &["plain"]).is_err());
+    }
+
+    #[test]
+    fn overlay_is_readable_but_not_writable() {
+        const READ_ONLY: &str = "Read-only file system";
+        let backup = TempFile::new("overlay.fs");
+        let mut snapshot_fs = FileSystem::new();
+        script(
+            &mut snapshot_fs,
+            "mkdir -p d/sub\nwrite d/f hi\nln -s f d/link\n",
+        );
+        snapshot_fs
+            .save(Some(backup.0.clone()), None, true)
+            .unwrap();
+
+        let mut fs = FileSystem::new();
+        script(&mut fs, "mkdir mnt\nmkdir out\n");
+        assert_eq!(fs.overlay(&backup.0, "/mnt"), Ok(()));
+        // reads work
+        assert!(tree_eq(&snapshot_fs, "/", &fs, "/mnt"));
+        assert_eq!(fs.cat("/mnt/d/link").unwrap(), "hi\n");
+        let names = fs.ls(Some("/mnt/d".to_string())).unwrap();
+        let names = names.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names, ["f", "link", "sub"]);
+        assert_eq!(find(&fs, "find /mnt -name f"), ["/mnt/d/f"]);
+        assert_eq!(fs.stat_format("/mnt/d/f", "%s").unwrap(), "3");
+        // and so does copying out of the overlay
+        let recursive = CopyOptions {
+            recursive: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(fs.cp("/mnt/d", "/out", recursive), Ok(4));
+        script(&mut fs, "write /out/d/f changed\n");
+        assert_eq!(fs.cat("/out/d/f").unwrap(), "changed\n");
+
+        // every mutation at or below the mount point is refused
+        let before = snapshot(&fs, "/mnt");
+        assert_eq!(fs.mkdir("/mnt/d/new", None), Err(READ_ONLY));
+        assert_eq!(fs.creat("/mnt/new"), Err(READ_ONLY));
+        assert_eq!(fs.write("/mnt/d/f", "x"), Err(READ_ONLY));
+        assert_eq!(fs.append("/mnt/d/f", "x"), Err(READ_ONLY));
+        assert_eq!(fs.rm("/mnt/d/f"), Err(READ_ONLY));
+        assert_eq!(fs.rm_recursive("/mnt/d"), Err(READ_ONLY));
+        assert_eq!(fs.rmdir("/mnt/d/sub"), Err(READ_ONLY));
+        assert_eq!(fs.symlink("/out", "/mnt/l"), Err(READ_ONLY));
+        let mode = parse_mode_spec("777").unwrap();
+        assert_eq!(fs.chmod(&mode, "/mnt/d/f"), Err(READ_ONLY));
+        assert_eq!(
+            fs.mv("/mnt/d/f", "/out/f", CopyOptions::default()),
+            Err(READ_ONLY)
+        );
+        assert_eq!(
+            fs.mv("/out/d", "/mnt/d2", CopyOptions::default()),
+            Err(READ_ONLY)
+        );
+        assert_eq!(
+            fs.cp("/out/d/f", "/mnt/d/f2", CopyOptions::default()),
+            Err(READ_ONLY)
+        );
+        assert_eq!(fs.rm_recursive("/mnt"), Err(READ_ONLY));
+        assert_eq!(snapshot(&fs, "/mnt"), before);
+        // outside the overlay nothing changed
+        assert_eq!(fs.mkdir("/out/new", None), Ok(()));
+    }
+
+    #[test]
+    fn grep_l_prints_each_matching_file_once() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p src/deep\ncat > src/a <<END\ntodo one\ntodo two\nEND\n\
+             write src/b nothing\nwrite src/deep/c \"a todo\"\nmkdir todo\n",
+        );
+        assert_eq!(grep(&fs, "grep -l todo /src"), ["/src/a", "/src/deep/c"]);
+        // -l implies -r, and the path defaults to cwd
+        assert_eq!(grep(&fs, "grep -l todo"), ["/src/a", "/src/deep/c"]);
+        assert_eq!(grep(&fs, "grep -l missing /src"), Vec::<String>::new());
+        // a file with several matching lines is listed once
+        assert_eq!(grep(&fs, "grep -l todo src/a"), ["/src/a"]);
+    }
+
+    // the (total, path) lines of a du command line
+    fn du(fs: &FileSystem, line: &str) -> Vec<(usize, String)> {
+        match parse_command(line) {
+            Ok(Command::Du(start, options)) => fs.du(&start, &options).unwrap(),
+            _ => panic!("not a du command: {}", line),
+        }
+    }
+
+    #[test]
+    fn du_exclude_prunes_matching_subtrees() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p p/src/.cache/deep\nmkdir p/webcache\nwrite p/src/main 12345\n\
+             write p/src/.cache/blob 123456789\nwrite p/src/.cache/deep/more 1234\n\
+             write p/webcache/page 123\nwrite p/cached.txt 1\n",
+        );
+        let entry = |total: usize, path: &str| (total, path.to_string());
+        assert_eq!(
+            du(&fs, "du p"),
+            [
+                entry(5, "/p/src/.cache/deep"),
+                entry(15, "/p/src/.cache"),
+                entry(21, "/p/src"),
+                entry(4, "/p/webcache"),
+                entry(6 + 10 + 5 + 4 + 2, "/p"),
+            ]
+        );
+        // an excluded directory's files count for nothing, however deep
+        assert_eq!(
+            du(&fs, "du --exclude *cache* p"),
+            [entry(6, "/p/src"), entry(6, "/p")]
+        );
+        // only names are matched, so a pattern naming the path matches nothing
+        assert_eq!(du(&fs, "du --exclude p/webcache p"), du(&fs, "du p"));
+        // patterns add up
+        assert_eq!(
+            du(&fs, "du --exclude .cache --exclude *.txt p"),
+            [entry(6, "/p/src"), entry(4, "/p/webcache"), entry(10, "/p")]
+        );
+    }
+
+    #[test]
+    fn du_counts_a_file_reached_by_a_symlink_once() {
+        // there are no hard links, so a second name for a file can only be a
+        // symlink, and du counts the link itself rather than its target
+        let mut fs = FileSystem::new();
+        fs.mkdir("/d", None).unwrap();
+        fs.write("/d/f", &"x".repeat(100)).unwrap();
+        script(&mut fs, "ln -s f /d/link\nln -s /d /d/up\n");
+        let link_sizes = "f".len() + "/d".len();
+        assert_eq!(du(&fs, "du /d"), [(100 + link_sizes, "/d".to_string())]);
+    }
+
+    #[test]
+    fn find_path_matches_the_whole_path() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p app/logs/old\nmkdir -p app/src\ntouch app/logs/a.txt\n\
+             touch app/logs/old/b.txt\ntouch app/src/logs.txt\ntouch app/logs/c.log\n",
+        );
+        // -name alone can't tell logs/ files from a file named logs.txt
+        assert_eq!(
+            find(&fs, "find app -name *.txt"),
+            [
+                "/app/logs/a.txt",
+                "/app/logs/old/b.txt",
+                "/app/src/logs.txt"
+            ]
+        );
+        // * crosses slashes, as in find(1)
+        assert_eq!(
+            find(&fs, "find app -path */logs/*.txt"),
+            ["/app/logs/a.txt", "/app/logs/old/b.txt"]
+        );
+        assert_eq!(
+            find(&fs, "find app -path app/logs/?.txt"),
+            ["/app/logs/a.txt"]
+        );
+        // the pattern sees the start as it was typed, not the printed path
+        assert_eq!(
+            find(&fs, "find /app -path app/logs/a.txt"),
+            Vec::<String>::new()
+        );
+        assert_eq!(find(&fs, "find /app -path /app/logs"), ["/app/logs"]);
+        // and combines with the other predicates
+        assert_eq!(
+            find(&fs, "find app -path *logs* -type d"),
+            ["/app/logs", "/app/logs/old"]
+        );
+    }
+
+    #[test]
+    fn pipe_chains_the_text_utilities() {
+        let mut fs = FileSystem::new();
+        let log = "ok start\nerror one\nok step\nerror two\nerror three\nok end";
+        fs.write("log", log).unwrap();
+        let grep = |pattern: &str| PipeOp::Grep(pattern.to_string());
+        assert_eq!(
+            fs.pipe("log", &[grep("error"), PipeOp::Head(2)]),
+            Ok("error one\nerror two\n".to_string())
+        );
+        // each op reads the one before, so the order matters
+        assert_eq!(
+            fs.pipe("log", &[PipeOp::Head(2), grep("error")]),
+            Ok("error one\n".to_string())
+        );
+        assert_eq!(
+            fs.pipe("log", &[grep("error"), PipeOp::Tail(1), PipeOp::Wc]),
+            Ok("1 2 12\n".to_string())
+        );
+        // a last line without a newline is kept as it is
+        assert_eq!(fs.pipe("log", &[PipeOp::Tail(1)]), Ok("ok end".to_string()));
+        assert_eq!(fs.pipe("log", &[PipeOp::Head(0)]), Ok(String::new()));
+        assert_eq!(fs.pipe("log", &[PipeOp::Tail(10)]), Ok(log.to_string()));
+        assert_eq!(fs.pipe("log", &[]), Ok(log.to_string()));
+        assert_eq!(
+            fs.pipe("missing", &[PipeOp::Wc]),
+            Err("No such file or directory")
+        );
+    }
+
+    #[test]
+    fn recall_line_substitutes_bang_bang() {
+        let mut last_line = None;
+        // nothing to recall before the first command
+        assert_eq!(
+            recall_line("!!\n".to_string(), &mut last_line),
+            Err("!!: event not found")
+        );
+        assert_eq!(
+            recall_line("pwd\n".to_string(), &mut last_line).unwrap(),
+            "pwd\n"
+        );
+        assert_eq!(
+            recall_line(" !! \n".to_string(), &mut last_line).unwrap(),
+            "pwd\n"
+        );
+        // !! and blank lines leave the last line alone
+        assert_eq!(recall_line("\n".to_string(), &mut last_line).unwrap(), "\n");
+        assert_eq!(
+            recall_line("!!\n".to_string(), &mut last_line).unwrap(),
+            "pwd\n"
+        );
+        assert_eq!(last_line.as_deref(), Some("pwd\n"));
+        recall_line("ls\n".to_string(), &mut last_line).unwrap();
+        assert_eq!(
+            recall_line("!!\n".to_string(), &mut last_line).unwrap(),
+            "ls\n"
+        );
+    }
+
+    #[test]
+    fn blocks_round_content_up_to_whole_blocks() {
+        let mut fs = FileSystem::new();
+        fs.mkdir("/d", None).unwrap();
+        for (name, size) in [
+            ("empty", 0),
+            ("one", 1),
+            ("full", 512),
+            ("over", 513),
+            ("big", 1500),
+        ] {
+            fs.write(&format!("/d/{}", name), &"x".repeat(size))
+                .unwrap();
+        }
+        let blocks =
+            |fs: &FileSystem, name: &str| fs.stat_format(&format!("/d/{}", name), "%b").unwrap();
+        assert_eq!(blocks(&fs, "empty"), "0");
+        assert_eq!(blocks(&fs, "one"), "1");
+        assert_eq!(blocks(&fs, "full"), "1");
+        assert_eq!(blocks(&fs, "over"), "2");
+        assert_eq!(blocks(&fs, "big"), "3");
+        // du --blocks adds up the rounded sizes, not the bytes
+        assert_eq!(du(&fs, "du --blocks /d"), [(7, "/d".to_string())]);
+        assert_eq!(du(&fs, "du /d"), [(2526, "/d".to_string())]);
+        // the block size is configurable
+        script(&mut fs, "set block-size 1024\n");
+        assert_eq!(blocks(&fs, "over"), "1");
+        assert_eq!(blocks(&fs, "big"), "2");
+        assert_eq!(du(&fs, "du --blocks /d"), [(5, "/d".to_string())]);
+    }
+
+    #[test]
+    fn transfer_copies_a_subtree_between_instances() {
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        for size in [0, 10, 200] {
+            // a random tree moved under /r, so there is a subtree to transfer
+            let mut src = random_tree(&mut rng, size);
+            let names = src.ls(None).unwrap();
+            src.mkdir("/r", None).unwrap();
+            for (name, _) in names {
+                src.mv(
+                    &format!("/{}", name),
+                    &format!("/r/{}", name),
+                    CopyOptions::default(),
+                )
+                .unwrap();
+            }
+            let src_before = snapshot(&src, "/");
+            let mut dst = FileSystem::new();
+            script(&mut dst, "mkdir /into\ntouch /into/kept\n");
+            let old_counter = dst.counter;
+            let total = src.walk(0, None).count() - 1;
+
+            // into an existing directory, then as a new name
+            assert_eq!(src.transfer("/r", &mut dst, "/into"), Ok(total));
+            assert!(
+                tree_eq(&src, "/r", &dst, "/into/r"),
+                "tree of {} nodes",
+                size
+            );
+            assert_eq!(src.transfer("r", &mut dst, "/new"), Ok(total));
+            assert!(tree_eq(&src, "/r", &dst, "/new"));
+            assert!(dst.find(0, &["into", "kept"]).is_ok());
+            // the copies got fresh ids in dst, and the source is untouched
+            let copied = dst.walk(dst.find(0, &["into", "r"]).unwrap(), None);
+            assert!(copied.map(|(id, _)| id).all(|id| id > old_counter));
+            assert_eq!(dst.counter, old_counter + 2 * total);
+            assert_eq!(snapshot(&src, "/"), src_before);
+        }
+    }
+
+    #[test]
+    fn transfer_and_import_refuse_the_root() {
+        let backup = TempFile::new("import-root.fs");
+        let mut src = FileSystem::new();
+        script(&mut src, "mkdir /d\ntouch /d/f\n");
+        src.save(Some(backup.0.clone()), None, false).unwrap();
+        let mut dst = FileSystem::new();
+        script(&mut dst, "mkdir /x\n");
+        assert_eq!(
+            src.transfer("/", &mut dst, "/x"),
+            Err("cannot copy root directory")
+        );
+        assert_eq!(
+            dst.import(&backup.0, "/", "/x"),
+            Err("cannot copy root directory")
+        );
+        assert_eq!(
+            dst.import(&backup.0, "/d/..", "/y"),
+            Err("cannot copy root directory")
+        );
+        assert_eq!(dst.lspaths("/", false).unwrap(), ["/", "/x"]);
+        // a path below the root is fine
+        assert_eq!(dst.import(&backup.0, "/d", "/x"), Ok(2));
+        assert_eq!(dst.lspaths("/x", false).unwrap(), ["/x", "/x/d", "/x/d/f"]);
+    }
+
+    #[test]
+    fn reload_counts_what_it_loaded() {
+        let backup = TempFile::new("summary.fs");
+        let mut fs = FileSystem::new();
+        fs.gentree("/g", 3, 2).unwrap();
+        script(&mut fs, "ln -s /g /a\nln -s /g/d0 /g/d1/b\nmkdir /empty\n");
+        fs.save(Some(backup.0.clone()), None, false).unwrap();
+        let mut reloaded = FileSystem::new();
+        let counts = reloaded.reload(Some(backup.0.clone())).unwrap();
+        // the root counts among the directories
+        assert_eq!(counts.directories, 1 + 1 + 3 + 1);
+        assert_eq!(counts.files, 9);
+        assert_eq!(counts.symlinks, 2);
+        assert_eq!(counts.version, BACKUP_VERSION);
+        assert_eq!(
+            counts.directories + counts.files + counts.symlinks,
+            reloaded.nodes.len()
+        );
+        // a subtree backup counts only what went in
+        fs.save(Some(backup.0.clone()), Some("/g/d1".to_string()), false)
+            .unwrap();
+        let counts = reloaded.reload(Some(backup.0.clone())).unwrap();
+        let counted = (counts.directories, counts.files, counts.symlinks);
+        assert_eq!(counted, (1, 3, 1));
+    }
+
+    #[test]
+    fn cd_into_a_symlink_loop_fails_promptly() {
+        const LOOP: &str = "Too many levels of symbolic links";
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir d\nln -s /b /a\nln -s /a /b\nln -s self /d/self\n",
+        );
+        let start = Instant::now();
+        for physical in [false, true] {
+            for path in ["/a", "b", "/a/x", "d/self", "/d/self/self"] {
+                assert_eq!(
+                    fs.cd(Some(path.to_string()), physical),
+                    Err(LOOP),
+                    "{}",
+                    path
+                );
+                assert_eq!(fs.cwd, 0);
+            }
+        }
+        // -P resolves the loop before climbing out of it; -L drops it as text
+        for path in ["/d/self/..", "a/../d"] {
+            assert_eq!(fs.cd(Some(path.to_string()), true), Err(LOOP), "{}", path);
+            assert_eq!(fs.cd(Some(path.to_string()), false), Ok(()), "{}", path);
+            fs.cd(Some("/".to_string()), false).unwrap();
+        }
+        // mkdir -p, and so mkcd, reports the loop instead of a clash
+        let nodes = fs.nodes.len();
+        assert_eq!(fs.mkdir_p("/a/x", None), Err(LOOP));
+        assert_eq!(fs.cd_create("d/self/x"), Err(LOOP));
+        assert_eq!((fs.nodes.len(), fs.cwd), (nodes, 0));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // a long chain that ends somewhere is not a loop
+        let mut target = "/d".to_string();
+        for i in 0..10 {
+            let link = format!("/l{}", i);
+            fs.symlink(&target, &link).unwrap();
+            target = link;
+        }
+        assert_eq!(fs.cd(Some(target), false), Ok(()));
+        assert_eq!(fs.cwd, fs.find(0, &["d"]).unwrap());
+    }
+
+    #[test]
+    fn reload_reads_v1_and_v2_and_rejects_other_magic() {
+        let nodes = "3 3\n0 /\n1 d\n3 f\nD 0 0 1\nD 1 0 3\nF 3 1\n";
+        let mut fs = FileSystem::new();
+        let v1 = backup_with("v1.fs", nodes);
+        let counts = fs.reload(Some(v1.0.clone())).unwrap();
+        assert_eq!(counts.version, "v1");
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/d", "/d/f"]);
+        assert_eq!(fs.counter, 3);
+        let v2 = backup_with("v2.fs", &format!("FSBACKUP v2\n{}", nodes));
+        let counts = fs.reload(Some(v2.0.clone())).unwrap();
+        assert_eq!(counts.version, "v2");
+        assert_eq!(fs.lspaths("/", false).unwrap(), ["/", "/d", "/d/f"]);
+        // save always writes the current version
+        fs.save(Some(v1.0.clone()), None, false).unwrap();
+        let saved = std::fs::read_to_string(&v1.0).unwrap();
+        assert_eq!(saved.lines().next(), Some("FSBACKUP v2"));
+
+        let before = snapshot(&fs, "/");
+        for (magic, err) in [
+            (
+                "FSBACKUP v3\n",
+                "Error parsing the backup: unsupported format version",
+            ),
+            (
+                "FSBACKUP \n",
+                "Error parsing the backup: unsupported format version",
+            ),
+            (
+                "FSBACKUPv2\n",
+                "Error parsing the backup: not a backup file",
+            ),
+            ("PNG\n", "Error parsing the backup: not a backup file"),
+            ("\n", "Error parsing the backup: not a backup file"),
+        ] {
+            let bogus = backup_with("bogus.fs", &format!("{}{}", magic, nodes));
+            assert_eq!(
+                fs.reload(Some(bogus.0.clone())).map(drop),
+                Err(err),
+                "{:?}",
+                magic
+            );
+        }
+        let empty = backup_with("empty.fs", "");
+        let err = "Error parsing the backup: not a backup file";
+        assert_eq!(fs.reload(Some(empty.0.clone())).map(drop), Err(err));
+        // a rejected file leaves the tree as it was
+        assert_eq!(snapshot(&fs, "/"), before);
+    }
+
+    #[test]
+    fn mv_refuses_to_mix_directories_and_files() {
+        let force = CopyOptions {
+            overwrite: Overwrite::Force,
+            ..CopyOptions::default()
+        };
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p a/x\ntouch x\nmkdir y\nmkdir b\ntouch b/y\n",
+        );
+        let before = snapshot(&fs, "/");
+        // file onto directory
+        assert_eq!(fs.mv("x", "a", CopyOptions::default()), Err("File exists"));
+        assert_eq!(
+            fs.mv("x", "a", force),
+            Err("cannot overwrite directory with non-directory")
+        );
+        // directory onto file
+        assert_eq!(fs.mv("y", "b", CopyOptions::default()), Err("File exists"));
+        assert_eq!(
+            fs.mv("y", "b", force),
+            Err("cannot overwrite non-directory with directory")
+        );
+        assert_eq!(
+            fs.mv("y", "b/y", force),
+            Err("cannot overwrite non-directory with directory")
+        );
+        assert_eq!(snapshot(&fs, "/"), before);
+        // naming the directory itself moves the file into it, as in coreutils
+        assert_eq!(fs.mv("x", "a/x", force), Ok(()));
+        assert!(fs.find(0, &["a", "x", "x"]).is_ok());
+
+        // onto a non-empty directory the two are merged, and a mismatch
+        // inside is refused for that entry alone
+        script(
+            &mut fs,
+            "mkdir -p s/c\nmkdir -p t/s\ntouch t/s/c\ntouch s/other\n",
+        );
+        assert_eq!(
+            fs.mv("s", "t", force),
+            Err("some entries were left in place")
+        );
+        assert!(fs.find(0, &["t", "s", "other"]).is_ok());
+        assert!(fs.nodes[&fs.find(0, &["t", "s", "c"]).unwrap()].is_file_node());
+        assert!(fs.nodes[&fs.find(0, &["s", "c"]).unwrap()].is_dir_node());
+    }
+
+    #[test]
+    fn ln_sf_replaces_the_existing_entry() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "write old o\nwrite new n\nln -s old link\nmkdir empty\nmkdir full\ntouch full/f\n",
+        );
+        let target = |fs: &FileSystem, path: &str| {
+            let node = &fs.nodes[&fs.find(0, &[path]).unwrap()];
+            match &node.node_type {
+                NodeType::SYMLINK { target } => target.clone(),
+                _ => panic!("{} is not a symlink", path),
+            }
+        };
+        assert_eq!(fs.symlink("new", "link"), Err("File already exists"));
+        assert_eq!(fs.symlink_force("new", "link"), Ok(()));
+        assert_eq!(target(&fs, "link"), "new");
+        assert_eq!(fs.cat("link").unwrap(), "n\n");
+        // a bad target fails before the old entry is removed
+        assert_eq!(fs.symlink_force("", "link"), Err("empty symlink target"));
+        assert_eq!(target(&fs, "link"), "new");
+        // a file or an empty directory is replaced too
+        script(&mut fs, "ln -sf new old\nln -sf new empty\n");
+        assert_eq!(target(&fs, "old"), "new");
+        assert_eq!(target(&fs, "empty"), "new");
+        // but not a directory with something in it
+        assert_eq!(fs.symlink_force("new", "full"), Err("Directory not empty"));
+        assert!(fs.find(0, &["full", "f"]).is_ok());
+    }
+
+    #[test]
+    fn locate_finds_every_node_with_the_name() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p a/b\nmkdir c\ntouch a/notes\ntouch a/b/notes\ntouch c/notes\nmkdir notes\n",
+        );
+        assert_eq!(
+            fs.locate("notes"),
+            ["/a/b/notes", "/a/notes", "/c/notes", "/notes"]
+        );
+        assert_eq!(fs.locate("not"), Vec::<String>::new());
+        script(&mut fs, "mv a/notes a/renamed\nrm c/notes\nmv a/b c\n");
+        assert_eq!(fs.locate("notes"), ["/c/b/notes", "/notes"]);
+        assert_eq!(fs.locate("renamed"), ["/a/renamed"]);
+    }
+
+    #[test]
+    fn locate_index_follows_every_change() {
+        // what locate should print, by scanning every node
+        fn scan(fs: &FileSystem, name: &str) -> Vec<String> {
+            let mut paths = fs
+                .walk(0, None)
+                .filter(|(id, _)| *id != 0 && fs.nodes[id].name == name)
+                .map(|(id, _)| fs.path_of(id))
+                .collect::<Vec<String>>();
+            paths.sort();
+            paths
+        }
+        // a path of one to three of the names
+        fn path(rng: &mut Rng) -> String {
+            let parts = (0..1 + rng.below(3)).map(|_| ["a", "b", "c"][rng.below(3)]);
+            format!("/{}", parts.collect::<Vec<&str>>().join("/"))
+        }
+        let backup = TempFile::new("locate.fs");
+        let mut rng = Rng(0x4f1b_bcdc_bfa5_3e0b);
+        let mut fs = FileSystem::new();
+        for _ in 0..1500 {
+            let line = match rng.below(10) {
+                0 | 1 => format!("mkdir {}", path(&mut rng)),
+                2 => format!("touch {}", path(&mut rng)),
+                3 => format!("rm -r {}", path(&mut rng)),
+                4 => format!("mv {} {}", path(&mut rng), path(&mut rng)),
+                5 => format!("cp -r {} {}", path(&mut rng), path(&mut rng)),
+                6 => format!("ln -sf {} {}", path(&mut rng), path(&mut rng)),
+                7 => format!("flatten {}", path(&mut rng)),
+                8 => format!("save {}\nreload {}", backup.0, backup.0),
+                _ => "undo".to_string(),
+            };
+            script(&mut fs, &format!("{}\n", line));
+            for name in ["a", "b", "c"] {
+                assert_eq!(fs.locate(name), scan(&fs, name), "after {}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn symbolic_modes_apply_to_the_current_mode() {
+        let apply = |spec: &str, mode: u32| parse_mode_spec(spec).unwrap().apply(mode);
+        for (spec, from, to) in [
+            ("u+x", 0o644, 0o744),
+            ("g-w", 0o664, 0o644),
+            ("o=r", 0o757, 0o754),
+            ("u+x,g-w,o=r", 0o666, 0o744),
+            ("a+x", 0o644, 0o755),
+            ("+x", 0o600, 0o711),
+            ("ug=rw", 0o111, 0o661),
+            ("go=", 0o777, 0o700),
+            ("a-rwx", 0o777, 0o000),
+            ("u=rwx,go=rx", 0o000, 0o755),
+            // clauses apply in order, so a later one can undo an earlier
+            ("u+w,u-w", 0o444, 0o444),
+            // bits above rwx are left alone
+            ("a=r", 0o4755, 0o4444),
+            // octal still replaces the whole mode
+            ("640", 0o777, 0o640),
+        ] {
+            assert_eq!(apply(spec, from), to, "{} on {:o}", spec, from);
+        }
+        for bad in ["", "u", "x+u", "u+z", "u+x,", "k=r", "u+x-w", "+x,,g-w"] {
+            assert!(parse_mode_spec(bad).is_err(), "{:?}", bad);
+        }
+        // and through chmod itself
+        let mut fs = FileSystem::new();
+        script(&mut fs, "touch f\nchmod 640 f\nchmod u+x,g-r,o=w f\n");
+        assert_eq!(fs.nodes[&fs.find(0, &["f"]).unwrap()].mode, 0o702);
+    }
+
+    #[test]
+    fn umask_clears_bits_from_new_nodes() {
+        let mode = |fs: &FileSystem, path: &str| fs.nodes[&fs.find(0, &[path]).unwrap()].mode;
+        let mut fs = FileSystem::new();
+        script(&mut fs, "touch f0\nmkdir d0\n");
+        assert_eq!((mode(&fs, "f0"), mode(&fs, "d0")), (0o644, 0o755));
+        script(&mut fs, "umask 077\ntouch f1\nmkdir d1\nwrite w1 x\n");
+        assert_eq!((mode(&fs, "f1"), mode(&fs, "d1")), (0o600, 0o700));
+        assert_eq!(mode(&fs, "w1"), 0o600);
+        script(&mut fs, "umask 0\ntouch f2\nmkdir -p d2/sub\n");
+        assert_eq!((mode(&fs, "f2"), mode(&fs, "d2")), (0o666, 0o777));
+        // an explicit mkdir -m isn't masked, and existing nodes keep theirs
+        script(&mut fs, "umask 777\nmkdir -m 750 d3\n");
+        assert_eq!(mode(&fs, "d3"), 0o750);
+        assert_eq!(mode(&fs, "f0"), 0o644);
+        assert!(matches!(
+            parse_command("umask 8"),
+            Err("umask: invalid mask")
+        ));
+        assert!(matches!(
+            parse_command("umask 1777"),
+            Err("umask: invalid mask")
+        ));
+    }
+
+    #[test]
+    fn human_size_matches_coreutils_rounding() {
+        for (bytes, shown) in [
+            (0, "0"),
+            (1, "1"),
+            (1023, "1023"),
+            (1024, "1.0K"),
+            (1025, "1.1K"),
+            (1536, "1.5K"),
+            (10 * 1024 - 1, "10K"),
+            (10 * 1024, "10K"),
+            (10 * 1024 + 1, "11K"),
+            (1024 * 1024 - 1, "1.0M"),
+            (1048576, "1.0M"),
+            (1048577, "1.1M"),
+            (5 * 1024 * 1024 * 1024, "5.0G"),
+            (usize::MAX, "16E"),
+        ] {
+            assert_eq!(human_size(bytes), shown, "{}", bytes);
+        }
+    }
+
+    #[test]
+    fn archive_restores_only_the_matched_files() {
+        let archive = TempFile::new("archive");
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir /var\nmkdir /var/log\nmkdir /var/tmp\nmkdir /var/empty\n\
+             write /var/log/app.log started\nwrite /var/log/app.txt notes\n\
+             write /var/tmp/old.log stale\nwrite /top.log outside\n",
+        );
+        let (start, query) = find_query("find /var -name *.log");
+        assert_eq!(fs.archive(&start, &query, &archive.0), Ok(2));
+
+        // the archive is rooted at /var and keeps only the dirs leading to matches
+        let mut restored = FileSystem::new();
+        restored.reload(Some(archive.0.clone())).unwrap();
+        assert_eq!(
+            find(&restored, "find /"),
+            ["/", "/log", "/log/app.log", "/tmp", "/tmp/old.log"]
+        );
+        assert_eq!(restored.cat("/log/app.log"), Ok("started\n"));
+        assert_eq!(restored.cat("/tmp/old.log"), Ok("stale\n"));
+
+        assert_eq!(
+            fs.archive("/var/log/app.log", &query, &archive.0),
+            Err("Not a directory")
+        );
+        let (_, query) = find_query("find /var -name *.none");
+        assert_eq!(fs.archive("/var", &query, &archive.0), Ok(0));
+    }
+
+    #[test]
+    fn diff_lines_marks_only_the_changed_lines() {
+        let old = "one\ntwo\nthree\nfour\nfive\n";
+        let new = "one\n2\nthree\nfour\nfive\nsix\n";
+        assert_eq!(
+            diff_lines(old, new),
+            [" one", "-two", "+2", " three", " four", " five", "+six"]
+        );
+        // a dropped line in the middle, and the edge cases
+        assert_eq!(diff_lines("a\nb\nc\n", "a\nc\n"), [" a", "-b", " c"]);
+        assert_eq!(diff_lines("", "x\n"), ["+x"]);
+        assert_eq!(diff_lines("x\n", ""), ["-x"]);
+        assert_eq!(diff_lines("same\n", "same"), [" same"]);
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    #[test]
+    fn reloading_a_v1_backup_and_saving_upgrades_it() {
+        let v1 = backup_with(
+            "legacy.fs",
+            "4 4\n0 /\n1 d\n3 f\n4 g\nD 0 0 1,4\nD 1 0 3\nF 3 1\nF 4 0\n",
+        );
+        let mut fs = FileSystem::new();
+        assert_eq!(fs.reload(Some(v1.0.clone())).unwrap().version, "v1");
+        let before = snapshot(&fs, "/");
+
+        // save writes the current format without being asked to
+        let upgraded = TempFile::new("upgraded.fs");
+        fs.save(Some(upgraded.0.clone()), None, false).unwrap();
+        let lines = backup_lines(&upgraded);
+        assert_eq!(lines[0], "FSBACKUP v2");
+        assert_eq!(lines[1], "4 4");
+
+        let mut again = FileSystem::new();
+        let counts = again.reload(Some(upgraded.0.clone())).unwrap();
+        assert_eq!(counts.version, "v2");
+        assert_eq!((counts.directories, counts.files), (2, 2));
+        assert_eq!(snapshot(&again, "/"), before);
+        assert_eq!(
+            again.lspaths("/", false).unwrap(),
+            ["/", "/d", "/d/f", "/g"]
+        );
+    }
+
+    #[test]
+    fn relpath_climbs_to_the_common_ancestor() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir /a\nmkdir /a/b\nmkdir /a/b/c\nmkdir /x\nwrite /x/f y\n",
+        );
+        let id = |path: &[&str]| fs.find(0, path).unwrap();
+        let (a, b, c, x, f) = (
+            id(&["a"]),
+            id(&["a", "b"]),
+            id(&["a", "b", "c"]),
+            id(&["x"]),
+            id(&["x", "f"]),
+        );
+        for (from, to, expected) in [
+            (0, 0, "."),
+            (b, b, "."),
+            (0, c, "a/b/c"),
+            (a, c, "b/c"),
+            (c, a, "../.."),
+            (c, 0, "../../.."),
+            (b, x, "../../x"),
+            (c, f, "../../../x/f"),
+            (x, b, "../a/b"),
+        ] {
+            assert_eq!(fs.relpath(from, to), expected, "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn find_relative_prints_paths_from_cwd() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir /a\nmkdir /a/b\nwrite /a/b/f x\nmkdir /z\ncd /a/b\n",
+        );
+        assert_eq!(find(&fs, "find /a --relative"), ["..", ".", "f"]);
+        assert_eq!(find(&fs, "find / -name z --relative"), ["../../z"]);
+        assert_eq!(find(&fs, "find . -type f --relative"), ["f"]);
+        // without the flag the paths stay absolute
+        assert_eq!(find(&fs, "find . -type f"), ["/a/b/f"]);
+        assert_eq!(fs.lspaths("/a", true).unwrap(), ["..", ".", "f"]);
+    }
+
+    #[test]
+    fn mutex_shares_a_file_system_across_threads() {
+        // FileSystem takes no lock of its own; a Mutex around it keeps each
+        // call whole, so a save never sees a half-done change
+        let backup = TempFile::new("sync.fs");
+        let shared = Arc::new(Mutex::new(FileSystem::new()));
+        let writers = (0..8)
+            .map(|t| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    shared
+                        .lock()
+                        .unwrap()
+                        .mkdir(&format!("/t{}", t), None)
+                        .unwrap();
+                    for i in 0..50 {
+                        let path = format!("/t{}/f{}", t, i);
+                        let mut fs = shared.lock().unwrap();
+                        fs.creat(&path).unwrap();
+                        fs.append(&path, "x").unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let saver = {
+            let shared = Arc::clone(&shared);
+            let path = backup.0.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    shared
+                        .lock()
+                        .unwrap()
+                        .save(Some(path.clone()), None, true)
+                        .unwrap();
+                }
+            })
+        };
+        for handle in writers {
+            handle.join().unwrap();
+        }
+        saver.join().unwrap();
+
+        // every node got its own id, and every save was a whole snapshot
+        let fs = shared.lock().unwrap();
+        assert_eq!(fs.nodes.len(), 1 + 8 + 8 * 50);
+        assert_eq!(fs.counter, 8 + 8 * 50);
+        assert_eq!(fs.cat("/t3/f7").unwrap(), "x");
+        let mut reloaded = FileSystem::new();
+        reloaded.reload(Some(backup.0.clone())).unwrap();
+        fs.save(Some(backup.0.clone()), None, true).unwrap();
+        reloaded.reload(Some(backup.0.clone())).unwrap();
+        assert_eq!(reloaded.lspaths("/", false), fs.lspaths("/", false));
+    }
+
+    #[test]
+    fn tree_du_annotates_cumulative_sizes() {
+        let mut fs = FileSystem::new();
+        // write adds a newline: a is 4 bytes, b 2, c 6
+        script(
+            &mut fs,
+            "mkdir -p d/sub/deep\nwrite d/a abc\nwrite d/sub/b x\nwrite d/sub/deep/c hello\n",
+        );
+        let du = TreeOptions {
+            du: true,
+            ..TreeOptions::default()
+        };
+        assert_eq!(
+            fs.tree("d", du).unwrap(),
+            [
+                "[12] d",
+                "├── [4] a",
+                "└── [8] sub",
+                "    ├── [2] b",
+                "    └── [6] deep",
+                "        └── [6] c",
+                "",
+                "2 directories, 3 files",
+            ]
+        );
+        // -L hides the deeper lines but their bytes still count
+        let shallow = TreeOptions {
+            level: Some(1),
+            du: true,
+        };
+        assert_eq!(
+            fs.tree("d", shallow).unwrap(),
+            [
+                "[12] d",
+                "├── [4] a",
+                "└── [8] sub",
+                "",
+                "1 directory, 1 file"
+            ]
+        );
+    }
+
+    #[test]
+    fn creat_sets_initial_content() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "creat f \"initial  text\"\ncreat g two words\ncreat empty\n",
+        );
+        assert_eq!(fs.cat("f"), Ok("initial  text\n"));
+        assert_eq!(fs.cat("g"), Ok("two words\n"));
+        assert_eq!(fs.cat("empty"), Ok(""));
+        // a clash leaves the existing content alone
+        script(&mut fs, "creat f other\n");
+        assert_eq!(fs.cat("f"), Ok("initial  text\n"));
+        fs.creat_with_content("/h", "raw".to_string()).unwrap();
+        assert_eq!(fs.cat("/h"), Ok("raw"));
+        assert_eq!(
+            fs.creat_with_content("/nope/h", String::new()),
+            Err("No such file or directory")
+        );
+    }
+
+    #[test]
+    fn find_size_bands_are_strict_on_both_ends() {
+        let mut fs = FileSystem::new();
+        for (name, size) in [
+            ("k1", 1024),
+            ("k1p", 1025),
+            ("k2m", 2047),
+            ("k2", 2048),
+            ("k3", 3072),
+        ] {
+            fs.creat_with_content(&format!("/{}", name), "x".repeat(size))
+                .unwrap();
+        }
+        assert_eq!(
+            find(&fs, "find / -type f -size +1K -size -2K"),
+            ["/k1p", "/k2m"]
+        );
+        // exact sizes and bytes combine the same way
+        assert_eq!(
+            find(&fs, "find / -type f -size +1023c -size -2049c").len(),
+            4
+        );
+        assert_eq!(find(&fs, "find / -size 2k"), ["/k2"]);
+        assert_eq!(
+            find(&fs, "find / -size 2k -size +2048c"),
+            Vec::<String>::new()
+        );
+        // one band in M, against a file in it
+        fs.creat_with_content("/m", "x".repeat(3 << 20)).unwrap();
+        assert_eq!(find(&fs, "find / -size +1M -size -10M"), ["/m"]);
+        assert_eq!(find(&fs, "find / -size +3M"), Vec::<String>::new());
+
+        for (arg, err) in [
+            ("+1X", "invalid -size unit"),
+            ("+K", "invalid argument to -size"),
+        ] {
+            assert!(matches!(
+                parse_command(&format!("find / -size {}", arg)),
+                Err(e) if e == err
+            ));
+        }
+        assert!(matches!(
+            parse_command("find / -size"),
+            Err("missing argument to -size")
+        ));
+    }
+
+    #[test]
+    fn flatten_moves_children_up_and_removes_the_dir() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p /p/d/sub\nwrite /p/d/f one\nln -s f /p/d/link\nwrite /p/d/sub/g two\n",
+        );
+        let sub = fs.find(0, &["p", "d", "sub"]).unwrap();
+        fs.flatten("/p/d").unwrap();
+        assert_eq!(
+            fs.lspaths("/p", false).unwrap(),
+            ["/p", "/p/f", "/p/link", "/p/sub", "/p/sub/g"]
+        );
+        // moved, not copied
+        assert_eq!(fs.find(0, &["p", "sub"]), Ok(sub));
+        assert_eq!(fs.cat("/p/link"), Ok("one\n"));
+
+        // a clash with the parent, even with the directory's own name, moves nothing
+        script(
+            &mut fs,
+            "mkdir /p/e\nwrite /p/e/f other\nmkdir -p /q/q\nwrite /q/q/q x\n",
+        );
+        let before = snapshot(&fs, "/");
+        assert_eq!(fs.flatten("/p/e"), Err("File exists"));
+        assert_eq!(fs.flatten("/q/q"), Err("File exists"));
+        assert_eq!(snapshot(&fs, "/"), before);
+
+        assert_eq!(fs.flatten("/p/f"), Err("Not a directory"));
+        assert_eq!(fs.flatten("/"), Err("cannot flatten root directory"));
+        script(&mut fs, "cd /p/sub\n");
+        assert_eq!(fs.flatten("/p/sub"), Err("directory in use"));
+    }
+
+    #[test]
+    fn chmod_advances_ctime_but_not_the_birth_time() {
+        let mut fs = FileSystem::new();
+        set_clock(&mut fs, 100);
+        script(&mut fs, "write f x\n");
+        let born = fs.nodes[&fs.find(0, &["f"]).unwrap()].created;
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(born, at(100));
+
+        set_clock(&mut fs, 200);
+        script(&mut fs, "chmod 600 f\n");
+        let id = fs.find(0, &["f"]).unwrap();
+        assert_eq!(fs.nodes[&id].ctime, at(200));
+        assert_eq!(fs.nodes[&id].created, born);
+        assert_eq!(fs.stat_format("f", "%W").unwrap(), "100");
+
+        // nor does anything else that touches the node later
+        set_clock(&mut fs, 300);
+        script(
+            &mut fs,
+            "find / -name f -chown 7\nwrite f y\ntouch f\nmv f g\n",
+        );
+        let node = &fs.nodes[&id];
+        assert_eq!((node.ctime, node.mtime), (at(300), at(300)));
+        assert_eq!(node.created, born);
+        assert_eq!(fs.stat_format("g", "%W %U").unwrap(), "100 7");
+    }
+
+    #[test]
+    fn cp_update_skips_a_destination_that_is_not_older() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "set clock 100\nwrite old stale\nwrite same first\n\
+             set clock 200\nwrite new fresh\nwrite dst kept\nwrite same second\n",
+        );
+        // an older source, and one as old as the destination, are left out
+        script(&mut fs, "cp -u old dst\ncp --update same dst\n");
+        assert_eq!(fs.cat("dst"), Ok("kept\n"));
+        script(
+            &mut fs,
+            "set clock 300\nwrite newer latest\ncp -u newer dst\ncp -u old missing\n",
+        );
+        assert_eq!(fs.cat("dst"), Ok("latest\n"));
+        assert_eq!(fs.cat("missing"), Ok("stale\n"));
+
+        // a recursive -u merge decides entry by entry
+        script(
+            &mut fs,
+            "mkdir src\nmkdir -p out/src\n\
+             set clock 400\nwrite out/src/a out-a\nwrite src/b src-b\n\
+             set clock 500\nwrite src/a src-a-newer\nwrite out/src/b out-b-newer\n\
+             cp -r -u src out\n",
+        );
+        assert_eq!(fs.cat("out/src/a"), Ok("src-a-newer\n"));
+        assert_eq!(fs.cat("out/src/b"), Ok("out-b-newer\n"));
+        assert!(matches!(parse_command("mv -u a b"), Err("invalid option")));
+    }
+
+    #[test]
+    fn undo_puts_a_moved_node_back() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir /a\nmkdir /b\nwrite /a/f one\nmkdir /a/d\nwrite /a/d/g two\n",
+        );
+        let (a, f, d) = (
+            fs.find(0, &["a"]).unwrap(),
+            fs.find(0, &["a", "f"]).unwrap(),
+            fs.find(0, &["a", "d"]).unwrap(),
+        );
+        let before = snapshot(&fs, "/");
+        script(&mut fs, "mv /a/f /b/renamed\nmv /a/d /b\n");
+        assert!(matches!(fs.undo_history.back(), Some(UndoRecord::Moved { id, .. }) if *id == d));
+
+        // newest first, each back under its old parent and name with its id
+        assert_eq!(fs.undo(), Ok("/a/d".to_string()));
+        assert_eq!(fs.undo(), Ok("/a/f".to_string()));
+        assert_eq!(fs.find(0, &["a", "f"]), Ok(f));
+        assert_eq!(fs.nodes[&f].parent, a);
+        assert_eq!(
+            fs.find(0, &["a", "d", "g"]).map(|id| fs.nodes[&id].parent),
+            Ok(d)
+        );
+        assert_eq!(snapshot(&fs, "/"), before);
+        assert_eq!(fs.undo(), Err("nothing to undo"));
+
+        // a record whose old spot has been taken since is dropped
+        script(&mut fs, "mv /a/f /b/f\nwrite /a/f new\n");
+        assert_eq!(fs.undo(), Err("File exists"));
+        assert_eq!(fs.cat("/b/f"), Ok("one\n"));
+        // replacing a file can't be undone, and forgets older moves
+        script(&mut fs, "mv /b/f /b/x\nmv -f /a/f /b/x\n");
+        assert_eq!(fs.undo(), Err("nothing to undo"));
+    }
+
+    #[test]
+    fn undo_after_flatten_has_nothing_to_take_back() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p /p/d/sub\nwrite /p/d/f one\nwrite /g two\nmv /g /p/g\nflatten /p/d\n",
+        );
+        let after = snapshot(&fs, "/");
+        // neither flatten's own moves nor the mv before it can be undone
+        assert_eq!(fs.undo(), Err("nothing to undo"));
+        assert_eq!(snapshot(&fs, "/"), after);
+        assert_eq!(
+            fs.lspaths("/p", false).unwrap(),
+            ["/p", "/p/f", "/p/g", "/p/sub"]
+        );
+    }
+
+    #[test]
+    fn find_chmod_and_chown_change_only_the_matches() {
+        let mut fs = FileSystem::new();
+        script(
+            &mut fs,
+            "mkdir -p /bin/sub\nwrite /bin/a.sh x\nwrite /bin/sub/b.sh y\n\
+             write /bin/notes.txt z\nmkdir /bin/dir.sh\n\
+             find /bin -type f -name *.sh -chmod 755\n",
+        );
+        let mode = |fs: &FileSystem, path: &[&str]| fs.nodes[&fs.find(0, path).unwrap()].mode;
+        assert_eq!(mode(&fs, &["bin", "a.sh"]), 0o755);
+        assert_eq!(mode(&fs, &["bin", "sub", "b.sh"]), 0o755);
+        assert_eq!(mode(&fs, &["bin", "notes.txt"]), FILE_BASE_MODE & !fs.umask);
+        assert_eq!(mode(&fs, &["bin", "dir.sh"]), DIR_BASE_MODE & !fs.umask);
+
+        // symbolic modes apply to each match's own mode, and chown goes along
+        script(&mut fs, "find /bin -name *.sh -chmod go-rx -chown 42\n");
+        assert_eq!(mode(&fs, &["bin", "a.sh"]), 0o700);
+        assert_eq!(
+            mode(&fs, &["bin", "dir.sh"]),
+            (DIR_BASE_MODE & !fs.umask) & 0o700
+        );
+        let uid = |path: &[&str]| fs.nodes[&fs.find(0, path).unwrap()].uid;
+        assert_eq!(uid(&["bin", "sub", "b.sh"]), 42);
+        assert_eq!(uid(&["bin", "dir.sh"]), 42);
+        assert_eq!(uid(&["bin", "notes.txt"]), 0);
+
+        // a match that can't be changed is handed back, and the others still change
+        let sub = fs.find(0, &["bin", "sub"]).unwrap();
+        fs.read_only.push(sub);
+        let (start, query) = find_query("find /bin -name *.sh -chown 7");
+        assert_eq!(
+            fs.find_apply(&start, &query),
+            Ok(vec![("/bin/sub/b.sh".to_string(), "Read-only file system")])
+        );
+        let uid = |path: &[&str]| fs.nodes[&fs.find(0, path).unwrap()].uid;
+        assert_eq!(uid(&["bin", "a.sh"]), 7);
+        assert_eq!(uid(&["bin", "sub", "b.sh"]), 42);
+        assert!(matches!(
+            parse_command("find /bin -delete -chmod 755"),
+            Err("-delete cannot be combined with -chmod or -chown")
+        ));
     }
 }
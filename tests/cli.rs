@@ -0,0 +1,310 @@
+// drives the shell binary through its stdin and checks what it prints
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// runs lines through a fresh shell and returns what it printed, without
+// prompts; it runs in the temp directory so nothing lands in the checkout
+fn shell(lines: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_filesystem"))
+        .current_dir(std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let script = format!("set prompt \"\"\n{}", lines);
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // the prompt in effect before set prompt
+    stdout.strip_prefix("$ ").unwrap().to_string()
+}
+
+#[test]
+fn stat_marks_the_root_directory() {
+    let out = shell("mkdir a\nstat /\nstat /a\n");
+    let lines = out.lines().collect::<Vec<&str>>();
+    assert!(lines.contains(&"Parent: 0 (root directory)"));
+    assert!(lines.contains(&"Parent: 0"));
+}
+
+#[test]
+fn stat_printf_has_no_trailing_newline() {
+    assert_eq!(shell("write f hello\nstat --printf %s f\n"), "6");
+    assert_eq!(shell("write f hello\nstat -c %s f\n"), "6\n");
+    // --printf takes its newlines as escapes, like a shell's printf
+    assert_eq!(
+        shell("write f hello\nstat --printf '%n\\t%s\\n' f\n"),
+        "f\t6\n"
+    );
+    assert_eq!(shell("write f hello\nstat -c '%n %%' f\n"), "f %\n");
+}
+
+#[test]
+fn ls_classify_marks_symlinks() {
+    let out = shell("mkdir dir\ntouch file\nln -s dir link\nln -s missing broken\nls -F\nls\n");
+    assert_eq!(
+        out,
+        "broken@\ndir/\nfile\nlink@\n\
+         broken\ndir\nfile\nlink\n"
+    );
+}
+
+#[test]
+fn verbose_reports_only_when_enabled() {
+    let quiet = "mkdir a\nmkdir a/b\ntouch a/f\ncp a/f a/g\nmv a/g a/h\nrm a/h\nrm -r a\n";
+    assert_eq!(shell(quiet), "");
+    let loud = shell(&format!(
+        "set verbose on\n{}set verbose off\nmkdir c\n",
+        quiet
+    ));
+    assert_eq!(
+        loud,
+        "mkdir: created directory '/a'\n\
+         mkdir: created directory '/a/b'\n\
+         '/a/f' -> '/a/g'\n\
+         renamed '/a/g' -> '/a/h'\n\
+         removed '/a/h'\n\
+         removed '/a/f'\n\
+         removed directory '/a/b'\n\
+         removed directory '/a'\n"
+    );
+}
+
+#[test]
+fn cp_progress_reports_every_thousand_nodes() {
+    // src and 1100 files below it
+    let mut script = "mkdir src\n".to_string();
+    for i in 0..1100 {
+        script.push_str(&format!("touch src/{}\n", i));
+    }
+    let output = shell(&format!(
+        "{}cp -r --progress src dst\ncp -r src quiet\n",
+        script
+    ));
+    assert_eq!(output, "cp: copied 1000 nodes\ncp: copied 1101 nodes\n");
+}
+
+#[test]
+fn echo_without_redirection_prints() {
+    assert_eq!(shell("echo \"a  b\" c\n"), "a  b c\n");
+}
+
+#[test]
+fn echo_writes_and_appends_through_redirection() {
+    let out = shell(
+        "echo hello > f\necho more >> f\necho \"a  b\" > g\necho y >> new\n\
+         cat f\ncat g\ncat new\n",
+    );
+    assert_eq!(out, "hello\nmore\na  b\ny\n");
+    // > replaces what was there
+    assert_eq!(shell("echo old > f\necho again > f\ncat f\n"), "again\n");
+    assert_eq!(shell("echo hi >\n"), "missing operand\n");
+}
+
+#[test]
+fn newer_compares_modification_times() {
+    let output = shell(
+        "set clock 100\ntouch a\nset clock 200\ntouch b\n\
+         newer b a\nnewer a b\nnewer a a\nnewer a missing\nnewer missing a\n",
+    );
+    assert_eq!(
+        output,
+        "b is newer than a\n\
+         a is not newer than b\n\
+         a is not newer than a\n\
+         newer: cannot stat missing: No such file or directory\n\
+         newer: cannot stat missing: No such file or directory\n"
+    );
+}
+
+#[test]
+fn ls_groups_directories_first() {
+    let setup = "touch b\nmkdir d\ntouch a\nmkdir c\nln -s d e\n";
+    assert_eq!(
+        shell(&format!("{}ls --group-directories-first\n", setup)),
+        "c\nd\na\nb\ne\n"
+    );
+    assert_eq!(
+        shell(&format!("{}ls -F --group-directories-first\n", setup)),
+        "c/\nd/\na\nb\ne@\n"
+    );
+    assert_eq!(shell(&format!("{}ls\n", setup)), "a\nb\nc\nd\ne\n");
+}
+
+#[test]
+fn df_inodes_reports_the_node_limit() {
+    let output = shell("mkdir a\ndf -i\nset max-nodes 3\ntouch b\ntouch c\ndf -i\n");
+    assert_eq!(
+        output,
+        "    Inodes      IUsed      IFree\n\
+         \x20        -          2          -\n\
+         touch: cannot touch c: No space left on device (inodes)\n\
+         \x20   Inodes      IUsed      IFree\n\
+         \x20        3          3          0\n"
+    );
+}
+
+#[test]
+fn stat_path_follows_a_moved_node() {
+    let out = shell(
+        "mkdir a\nmkdir a/b\ntouch a/b/f\nmkdir c\ncd a\nstat b/f\n\
+         mv b/f /c/g\nmv /c /a/b/c\nstat b/c/g\n",
+    );
+    let paths = out
+        .lines()
+        .filter(|line| line.starts_with("  Path: "))
+        .collect::<Vec<&str>>();
+    assert_eq!(paths, ["  Path: /a/b/f", "  Path: /a/b/c/g"]);
+}
+
+#[test]
+fn tac_reverses_lines() {
+    let output = shell(
+        "cat > log <<EOF\none\ntwo\n\nfour\nEOF\ntac log\n\
+         mkdir d\ntac d\ntac missing\ntouch empty\ntac empty\n",
+    );
+    assert_eq!(
+        output,
+        "four\n\ntwo\none\n\
+         tac: d: Is a directory\n\
+         tac: missing: No such file or directory\n"
+    );
+}
+
+#[test]
+fn cp_names_both_sides_of_a_self_copy() {
+    assert_eq!(
+        shell("write a hi\ncp a ./a\ncat a\n"),
+        "cp: 'a' and './a' are the same file\nhi\n"
+    );
+}
+
+#[test]
+fn wc_totals_multiple_files() {
+    let files = "write a \"one two\"\ncat > b <<END\nx\ny z\nEND\n";
+    assert_eq!(
+        shell(&format!("{}wc a b\n", files)),
+        " 1  2  8 a\n 2  3  6 b\n 3  5 14 total\n"
+    );
+    // a missing operand is reported in place and left out of the total
+    assert_eq!(
+        shell(&format!("{}wc a nope b\n", files)),
+        " 1  2  8 a\nwc: nope: No such file or directory\n 2  3  6 b\n 3  5 14 total\n"
+    );
+    // one file gets no total
+    assert_eq!(shell(&format!("{}wc a\n", files)), "1 2 8 a\n");
+}
+
+#[test]
+fn prompt_is_rendered_before_each_command() {
+    // shell's own `set prompt` runs at count 1
+    assert_eq!(
+        shell("set prompt \"{cwd} {count}> \"\nmkdir a\ncd a\n"),
+        "/ 3> / 4> /a 5> "
+    );
+}
+
+#[test]
+fn ls_reverse_flips_every_sort() {
+    let setup = "set clock 300\nwrite b 1234567\nset clock 100\nwrite c 12\nmkdir a\n\
+                 set clock 200\nwrite d 1234\n";
+    let ls = |args: &str| shell(&format!("{}ls {}\n", setup, args));
+    assert_eq!(ls(""), "a\nb\nc\nd\n");
+    assert_eq!(ls("-r"), "d\nc\nb\na\n");
+    assert_eq!(ls("-t"), "b\nd\na\nc\n");
+    assert_eq!(ls("-tr"), "c\na\nd\nb\n");
+    assert_eq!(ls("-r -S"), "a\nc\nd\nb\n");
+    // directories stay first when grouped, each group reversed
+    assert_eq!(ls("-r --group-directories-first"), "a\nd\nc\nb\n");
+}
+
+#[test]
+fn stat_shows_allocated_blocks() {
+    // ten bytes, with the newline, take two 8-byte blocks
+    let output = shell("set block-size 8\nwrite f 123456789\nstat f\n");
+    assert!(output.contains("\n  Size: 10\nBlocks: 2\n"), "{}", output);
+}
+
+#[test]
+fn reload_prints_a_summary() {
+    let backup = std::env::temp_dir().join(format!("fs-cli-{}-summary.fs", std::process::id()));
+    let out = shell(&format!(
+        "mkdir d\ntouch d/f\nln -s d l\nsave {0}\npurge -f\nreload {0}\n",
+        backup.display()
+    ));
+    std::fs::remove_file(&backup).unwrap();
+    assert_eq!(
+        out,
+        "reloaded 4 nodes (v2): 2 directories, 1 files, 1 symlinks\n"
+    );
+}
+
+#[test]
+fn cat_sep_goes_between_the_contents() {
+    let files = "write a one\nwrite b two\nwrite c three\n";
+    assert_eq!(
+        shell(&format!("{}cat --sep \"-- \" a b c\n", files)),
+        "one\n-- two\n-- three\n"
+    );
+    // the default is plain concatenation, and one file gets no separator
+    assert_eq!(shell(&format!("{}cat a b\n", files)), "one\ntwo\n");
+    assert_eq!(shell(&format!("{}cat --sep X a\n", files)), "one\n");
+    // a missing file is reported in turn and leaves no empty slot behind
+    assert_eq!(
+        shell(&format!("{}cat --sep=, a nope c\n", files)),
+        "one\ncat: nope: No such file or directory\n,three\n"
+    );
+}
+
+#[test]
+fn umask_prints_the_current_mask() {
+    assert_eq!(shell("umask\numask 27\numask\n"), "0022\n0027\n");
+}
+
+#[test]
+fn du_h_prints_human_sizes() {
+    // write adds a newline, so this file is exactly 2K
+    let out = shell(&format!(
+        "mkdir d\nwrite d/f {}\ndu -h d\n",
+        "x".repeat(2047)
+    ));
+    assert_eq!(out, "2.0K\t/d\n");
+    // ls -sh shows the allocated bytes: 10 blocks of 512
+    let out = shell(&format!("write f {}\nls -sh /\n", "x".repeat(5000)));
+    assert_eq!(out, "5.0K f\n");
+}
+
+#[test]
+fn diff_prints_a_unified_style_diff() {
+    let files = "write a one\necho two >> a\necho three >> a\n\
+                 write b one\necho 2 >> b\necho three >> b\nmkdir d\n";
+    assert_eq!(
+        shell(&format!("{}diff a b\n", files)),
+        "--- a\n+++ b\n one\n-two\n+2\n three\n"
+    );
+    // identical files print nothing, and both operands must be files
+    assert_eq!(shell(&format!("{}diff a a\n", files)), "");
+    assert_eq!(
+        shell(&format!("{}diff a d\ndiff nope a\n", files)),
+        "diff: d: Is a directory\ndiff: nope: No such file or directory\n"
+    );
+}
+
+#[test]
+fn info_reports_the_counter_and_node_count() {
+    let out = shell("info\nmkdir a\nmkdir a/b\nwrite a/f x\nrmdir a/b\ncd a\ninfo\n");
+    let (fresh, after) = out.split_at(out.find("counter: 3").unwrap());
+    assert_eq!(
+        fresh,
+        "counter: 0\ncwd: 0 /\nnodes: 1\nnames: 0 names, 0 ids\n\
+         path-cache: 0/256\ncd-history: 0\nread-only: 0\n"
+    );
+    // counter is the last id handed out, and rmdir does not take a/b's back
+    assert!(after.starts_with("counter: 3\ncwd: 1 /a\nnodes: 3\nnames: 2 names, 2 ids\n"));
+    assert!(after.ends_with("cd-history: 1\nread-only: 0\n"));
+}